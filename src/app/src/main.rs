@@ -39,8 +39,11 @@ use std::io::{self,Read,Write,Seek,SeekFrom};
 use std::ptr;
 use std::ffi::CString;
 use std::slice;
+use std::mem;
 use std::u32;
-use std::time::{Instant};
+use std::f64;
+use std::collections::HashSet;
+use std::time::{Instant,SystemTime,UNIX_EPOCH};
 
 mod vcf;
 
@@ -133,9 +136,8 @@ pub extern "C" fn ocall_append_file(ptr: *const u8, len: usize, chisq_file: bool
         }
     };
 
-    let buff_str = unsafe {
-        std::str::from_utf8( slice::from_raw_parts(ptr, len) )
-    }.unwrap_or("ERROR: invalid utf8 string");
+    // Encrypted by the enclave, see `shared::results`.
+    let buff = unsafe { slice::from_raw_parts(ptr, len) };
 
     let file = fs::OpenOptions::new()
                             .append(true)
@@ -143,55 +145,128 @@ pub extern "C" fn ocall_append_file(ptr: *const u8, len: usize, chisq_file: bool
 
     match file {
         Ok(mut file) => {
-            file.write_all(buff_str.as_bytes()).unwrap();
+            file.write_all(buff).unwrap();
         },
         Err(_) => panic!("ERROR: appending to file {}", filename),
     }
 }
 
-fn create_chisq_file() {
+#[no_mangle]
+pub extern "C" fn ocall_append_audit(ptr: *const u8, len: usize) {
+    let filename = app_params().audit_file_name.as_str();
+
+    // Signed by the enclave, see `shared::audit`.
+    let buff = unsafe { slice::from_raw_parts(ptr, len) };
+
     let file = fs::OpenOptions::new()
                             .create(true)
-                            .truncate(true)
-                            .write(true)
-                            .open(app_params().chisq_file_name.as_str());
+                            .append(true)
+                            .open(filename);
+
     match file {
         Ok(mut file) => {
-            writeln!(&mut file, "#Top most significant SNPs(sorted)").unwrap();
-            writeln!(&mut file, "#CHROM\tPOS\tID\tREF\tALT\tp-value").unwrap();
+            file.write_all(buff).unwrap();
         },
-        Err(_) => panic!("ERROR: creating chisq output file"),
+        Err(_) => panic!("ERROR: appending to file {}", filename),
     }
 }
 
-fn create_af_file() {
+// Last record of the audit log, none if the log is empty or missing.
+fn read_last_audit(filename: &str) -> io::Result<Vec<u8>> {
+    let mut file = match fs::File::open( filename ) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let size = try!( file.metadata() ).len();
+    let record_size = shared::audit::AUDIT_SIZE as u64;
+    if size % record_size != 0 {
+        return Err(io::Error::new( io::ErrorKind::InvalidData, "truncated audit log" ));
+    }
+    let mut record = Vec::new();
+    if size != 0 {
+        try!( file.seek( SeekFrom::Start( size - record_size ) ) );
+        record.resize( shared::audit::AUDIT_SIZE, 0 );
+        try!( file.read_exact( &mut record ) );
+    }
+    Ok(record)
+}
+
+// The enclave writes the results, header lines included.
+fn create_output_file(filename: &str) {
     let file = fs::OpenOptions::new()
                             .create(true)
                             .truncate(true)
                             .write(true)
-                            .open(app_params().af_file_name.as_str());
-    match file {
-        Ok(mut file) => {
-            writeln!(&mut file, "#Allele frequecies of SNPs from two groups").unwrap();
-            writeln!(&mut file, "#CHROM\tPOS\tID\tREF\tALT\talleleFreq").unwrap();
-        },
-        Err(_) => panic!("ERROR: creating allele frequecies output file"),
+                            .open(filename);
+    if let Err(why) = file {
+        panic!("ERROR: creating output file {}: {}", filename, why);
+    }
+}
+
+
+// Public key of the researcher, in hex as `ce --result_keygen` writes it.
+fn read_recipient( path: &str ) -> Result<[u8;shared::provision::PUBLIC_KEY_SIZE], String> {
+    let mut text = String::new();
+    try!( fs::File::open( path ).and_then(|mut f| f.read_to_string( &mut text ) ).map_err(|e| e.to_string() ) );
+    let text = text.trim();
+    let mut key = [0u8;shared::provision::PUBLIC_KEY_SIZE];
+    if text.len() != 2*key.len() || !text.is_ascii() {
+        return Err("invalid public key".to_string());
+    }
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = try!( u8::from_str_radix( &text[2*i..2*i+2], 16 ).map_err(|_| "invalid public key".to_string() ) );
     }
+    Ok(key)
 }
 
 
+// Larger than the sealed keypair of the enclave.
+const SEALED_SIZE_MAX: usize = 1024;
+
 extern "C" {
+    fn encl_keygen(eid:        sgx_types::sgx_enclave_id_t,
+                   retval:     *mut u32,
+                   sealed:     *mut u8,
+                   sealed_len: u32,
+                   target:     *const u8,
+                   target_len: usize,
+                   report:     *mut u8,
+                   report_len: usize,
+                   key:        *mut u8,
+                   key_len:    usize) -> sgx_types::sgx_status_t;
+
+    fn encl_load_key(eid:    sgx_types::sgx_enclave_id_t,
+                     sealed: *const u8,
+                     len:    u32) -> sgx_types::sgx_status_t;
+
     fn encl_init(eid:            sgx_types::sgx_enclave_id_t,
-                 nb_control:     u32,
-                 nb_case:        u32,
+                 nb_files:       u32,
                  keys_per_block: u32,
                  nb_keys:        u32,
                  snp_cnt:        usize,
-                 output_allele_freq: bool) -> sgx_types::sgx_status_t;
+                 output_allele_freq: bool,
+                 aggregation:    u8,
+                 epsilon:        f64,
+                 delta:          f64,
+                 af_epsilon:     f64,
+                 min_cases:      u32,
+                 min_controls:   u32,
+                 min_mac:        u32,
+                 max_k:          u32,
+                 study:          *const u8,
+                 study_len:      usize,
+                 timestamp:      u64,
+                 reset_time:     bool,
+                 audit:          *const u8,
+                 audit_len:      usize,
+                 recipient:      *const u8,
+                 recipient_len:  usize) -> sgx_types::sgx_status_t;
     
     fn encl_register(eid:     sgx_types::sgx_enclave_id_t,
                      fid:     u32,
-                     kind:    shared::Kind ) -> sgx_types::sgx_status_t;
+                     hdr:     *const u8,
+                     len:     usize ) -> sgx_types::sgx_status_t;
 
     fn encl_begin(eid:    sgx_types::sgx_enclave_id_t,
                   retval: *mut u32,
@@ -205,13 +280,141 @@ extern "C" {
                 len:    usize) -> sgx_types::sgx_status_t;
 
     fn encl_end(eid: sgx_types::sgx_enclave_id_t, cont: *mut bool) -> sgx_types::sgx_status_t;
+
+    fn encl_sign(eid:       sgx_types::sgx_enclave_id_t,
+                 statement: *mut u8,
+                 len:       usize) -> sgx_types::sgx_status_t;
+}
+
+
+
+// Generate the keypair of the enclave. The public key goes to the data owners
+// with the MRENCLAVE to expect, its quote to the attestation service, see
+// `shared::attestation`. Without a SPID there is no quote, only a test report
+// for `test/attest.sh` to sign.
+fn keygen( eid: sgx_types::sgx_enclave_id_t, spid: Option<&[u8;16]>, linkable: bool,
+           sealed_path: &str, key_path: &str ) {
+    // The report of the enclave is for the quoting enclave, if any.
+    let mut target = sgx_types::sgx_target_info_t::default();
+    let mut gid: sgx_types::sgx_epid_group_id_t = [0u8;4];
+    if spid.is_some() {
+        let res = unsafe { sgx_types::sgx_init_quote( &mut target, &mut gid ) };
+        if res != SGX_SUCCESS {
+            panic!("ERROR: sgx_init_quote returned {:?}", res);
+        }
+    }
+
+    let mut sealed = vec![0u8; SEALED_SIZE_MAX];
+    let mut report = sgx_types::sgx_report_t::default();
+    let mut key = shared::provision::EnclaveKey::zeroed();
+    let mut len = 0;
+    let res = unsafe { encl_keygen( eid, &mut len, sealed.as_mut_ptr(), sealed.len() as u32,
+                                    shared::as_u8_slice( &target ).as_ptr(), mem::size_of::<sgx_types::sgx_target_info_t>(),
+                                    shared::as_u8_slice_mut( &mut report ).as_mut_ptr(), mem::size_of::<sgx_types::sgx_report_t>(),
+                                    shared::as_u8_slice_mut( &mut key ).as_mut_ptr(),
+                                    mem::size_of::<shared::provision::EnclaveKey>() ) };
+    if res != SGX_SUCCESS {
+        panic!("ERROR: encl_keygen returned {:?}", res);
+    }
+    sealed.truncate( len as usize );
+
+    let spid = match spid {
+        Some(spid) => spid,
+        None       => return test_report( &report, key, &sealed, sealed_path, key_path ),
+    };
+
+    // Without signature revocation list, the attestation service tells if the
+    // platform is revoked.
+    let mut quote_len = 0u32;
+    let res = unsafe { sgx_types::sgx_calc_quote_size( ptr::null(), 0, &mut quote_len ) };
+    if res != SGX_SUCCESS {
+        panic!("ERROR: sgx_calc_quote_size returned {:?}", res);
+    }
+    let mut quote = vec![0u8; quote_len as usize];
+    let sign_type = if linkable {
+        sgx_types::sgx_quote_sign_type_t::SGX_LINKABLE_SIGNATURE
+    } else {
+        sgx_types::sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE
+    };
+    let spid = sgx_types::sgx_spid_t { id: *spid };
+    let res = unsafe { sgx_types::sgx_get_quote( &report, sign_type, &spid, ptr::null(), ptr::null(), 0,
+                                                 ptr::null_mut(), quote.as_mut_ptr() as *mut sgx_types::sgx_quote_t,
+                                                 quote_len ) };
+    if res != SGX_SUCCESS {
+        panic!("ERROR: sgx_get_quote returned {:?}", res);
+    }
+    if let Err(why) = key.set_quote( &quote ) {
+        panic!("ERROR: quote of {} bytes: {}", quote_len, why);
+    }
+
+    // The request body of the attestation service.
+    let mut encoded = vec![0u8; shared::attestation::base64_len( quote.len() )];
+    shared::attestation::base64_encode( &quote, &mut encoded );
+    let request = format!("{{\"isvEnclaveQuote\":\"{}\"}}\n", String::from_utf8_lossy( &encoded ) );
+    let request_path = format!("{}.json", key_path);
+
+    for &(path, data) in [(sealed_path, &sealed[..]), (key_path, key.as_slice()),
+                          (&request_path[..], request.as_bytes())].iter() {
+        if let Err(why) = fs::File::create( path ).and_then(|mut f| f.write_all( data ) ) {
+            panic!("{}: {}", path, why);
+        }
+    }
+    let mr_enclave: Vec<String> = key.quote_body().mr_enclave().iter().map(|b| format!("{:02x}", b) ).collect();
+    println!("Enclave key written to {}, sealed in {}", key_path, sealed_path );
+    println!("Attestation request written to {}", request_path );
+    println!("MRENCLAVE: {}", mr_enclave.concat() );
 }
 
 
+// Test attestation of `keygen`: the quote body is a zero quote header and the
+// report body of the enclave, in a report of status `QUOTE_SIMULATION` that
+// only `ce --test_attestation` trusts once `test/attest.sh` has signed it.
+fn test_report( report: &sgx_types::sgx_report_t, mut key: shared::provision::EnclaveKey, sealed: &[u8],
+                sealed_path: &str, key_path: &str ) {
+    let mut body = vec![0u8; shared::attestation::QUOTE_BODY_SIZE];
+    let report_body = shared::as_u8_slice( &report.body );
+    body[shared::attestation::QUOTE_BODY_SIZE - report_body.len()..].copy_from_slice( report_body );
+    if let Err(why) = key.set_quote( &body ) {
+        panic!("ERROR: test quote: {}", why);
+    }
+
+    let mut encoded = vec![0u8; shared::attestation::base64_len( body.len() )];
+    shared::attestation::base64_encode( &body, &mut encoded );
+    let report = format!("{{\"id\":\"test\",\"isvEnclaveQuoteStatus\":\"{}\",\"isvEnclaveQuoteBody\":\"{}\"}}\n",
+                         shared::attestation::QUOTE_SIMULATION, String::from_utf8_lossy( &encoded ) );
+    let report_path = format!("{}.report.json", key_path);
+
+    for &(path, data) in [(sealed_path, sealed), (key_path, key.as_slice()),
+                          (&report_path[..], report.as_bytes())].iter() {
+        if let Err(why) = fs::File::create( path ).and_then(|mut f| f.write_all( data ) ) {
+            panic!("{}: {}", path, why);
+        }
+    }
+    let mr_enclave: Vec<String> = key.quote_body().mr_enclave().iter().map(|b| format!("{:02x}", b) ).collect();
+    println!("Enclave key written to {}, sealed in {}", key_path, sealed_path );
+    println!("Test attestation report written to {}, to sign with test/attest.sh", report_path );
+    println!("MRENCLAVE: {}", mr_enclave.concat() );
+}
+
+
+// Load the keypair sealed by `keygen`, the enclave unwraps the data keys of
+// the files with it.
+fn load_key( eid: sgx_types::sgx_enclave_id_t, sealed_path: &str ) {
+    let mut sealed = Vec::new();
+    if let Err(why) = fs::File::open( sealed_path ).and_then(|mut f| f.read_to_end( &mut sealed ) ) {
+        panic!("{}: {}", sealed_path, why);
+    }
+    let res = unsafe { encl_load_key( eid, sealed.as_ptr(), sealed.len() as u32 ) };
+    if res != SGX_SUCCESS {
+        panic!("ERROR: encl_load_key returned {:?}", res);
+    }
+}
+
 
 fn register_file( eid: sgx_types::sgx_enclave_id_t, vcf: &mut vcf::Vcf) {
     // Register the file.
-    let res = unsafe { encl_register( eid, vcf.fid, vcf.kind ) };
+    let hdr = vcf.header.as_slice();
+    let res = unsafe { encl_register( eid, vcf.fid, hdr.as_ptr(), hdr.len() ) };
     if res != SGX_SUCCESS {
          panic!("ERROR: encl_register returned {:?}", res);
     }
@@ -238,23 +441,20 @@ fn read_file( eid: sgx_types::sgx_enclave_id_t, vcf: &mut vcf::Vcf ) -> io::Resu
     assert_eq!( vcf.ec_path.is_file(), true );
 
     let mut blk_nb = vcf.blk_nb;
+    if blk_nb as usize > vcf.offsets.len() {
+        return Ok(());
+    }
     
     // Open file and go to the last pos.
     let mut file = try!( fs::File::open(&vcf.ec_path) );
-    let keys_per_block = app_params().keys_per_block;
     
-    let pos = shared::block_size( keys_per_block ) as u64 * (blk_nb-1) as u64 ;
+    let pos = vcf.offsets[(blk_nb-1) as usize];
     assert_eq!( try!( file.seek( SeekFrom::Start(pos) ) ), pos );    
 
-    let mut buffer = {
-        let size = shared::block_size( keys_per_block ) as usize;
-        let mut b = Vec::with_capacity( size );
-        unsafe { b.set_len( size ) };
-        b.into_boxed_slice()
-    };
+    let mut buffer = Vec::with_capacity( shared::block_size( app_params().keys_per_block ) );
     
     loop {
-        let readed = try!( file.read( &mut buffer ) );
+        let readed = try!( vcf::read_block( &mut file, &mut buffer ) );
         if readed == 0 { 
             break
         }
@@ -283,14 +483,44 @@ fn end_loop( eid: sgx_types::sgx_enclave_id_t ) -> bool {
     cont
 }
 
+// Write the results signed by the enclave, see `ce --verify`.
+fn write_signed( eid: sgx_types::sgx_enclave_id_t, path: &str ) {
+    let mut statement = shared::signature::SignedResults::zeroed();
+    let res = unsafe { encl_sign( eid, shared::as_u8_slice_mut( &mut statement ).as_mut_ptr(),
+                                  mem::size_of::<shared::signature::SignedResults>() ) };
+    if res != SGX_SUCCESS {
+        panic!("ERROR: encl_sign returned {:?}", res );
+    }
+    if let Err(why) = fs::File::create( path ).and_then(|mut f| f.write_all( statement.as_slice() ) ) {
+        panic!("{}: {}", path, why);
+    }
+}
+
 #[derive(Debug)]
 struct AppParams {
-    control: String,
-    case: String,
+    control: Option<String>,
+    case: Option<String>,
+    sheet: Option<String>,
+    data: String,
+    keygen: bool,
+    spid: Option<[u8;16]>,
+    linkable: bool,
+    sealed: String,
+    enclave_key: String,
+    recipient: Option<String>,
     snp_cnt: usize,
     chisq_file_name: String,
     af_file_name: String,
+    signed_file_name: String,
+    audit_file_name: String,
+    reset_time: bool,
     output_allele_freq: bool,
+    aggregation: shared::Aggregation,
+    epsilon: f64,
+    delta: f64,
+    af_epsilon: f64,
+    policy: shared::policy::Policy,
+    study: [u8;shared::policy::STUDY_SIZE],
     keys_per_block: u32,
     keys_per_iter: u32,
 }
@@ -301,6 +531,35 @@ fn app_params() -> &'static mut AppParams {
     unsafe { APP_PARAMS.as_mut().unwrap() }
 }
 
+// A privacy parameter given is positive, below `max`.
+fn parse_budget( s: &str, name: &str, max: f64 ) -> f64 {
+    match s.parse::<f64>() {
+        Ok(v) if v > 0.0 && v < max => v,
+        _ => panic!("--{}: invalid value {}", name, s),
+    }
+}
+
+fn parse_spid( s: &str ) -> Result<[u8;16], String> {
+    let mut spid = [0u8;16];
+    if s.len() != 2*spid.len() || !s.is_ascii() {
+        return Err("invalid SPID".to_string());
+    }
+    for (i, b) in spid.iter_mut().enumerate() {
+        *b = try!( u8::from_str_radix( &s[2*i..2*i+2], 16 ).map_err(|_| "invalid SPID".to_string() ) );
+    }
+    Ok(spid)
+}
+
+// Seconds since 1970-01-01, the time of the audit record. The usage policies
+// are checked at its day, once the enclave has checked it against the trusted
+// time, see `shared::audit`.
+fn now() -> u64 {
+    match SystemTime::now().duration_since( UNIX_EPOCH ) {
+        Ok(dur)  => dur.as_secs(),
+        Err(why) => panic!("system time: {}", why),
+    }
+}
+
 fn parse_cmd_args() -> AppParams {
     // Read command line arguments.
     let matches =
@@ -311,15 +570,94 @@ fn parse_cmd_args() -> AppParams {
              .short("C")
              .long("control")
              .value_name("DIR")
-             .required(true)
+             .required_unless_one(&["sheet", "keygen"])
              .takes_value(true))
         .arg(Arg::with_name("case")
              .help("Case .vcf directory")
              .short("c")
              .long("case")
              .value_name("DIR")
-             .required(true)
+             .required_unless_one(&["sheet", "keygen"])
              .takes_value(true)) 
+        .arg(Arg::with_name("sheet")
+             .help("Sample sheet giving the phenotype of each sample")
+             .short("s")
+             .long("sheet")
+             .value_name("FILE")
+             .required(false)
+             .conflicts_with_all(&["case", "control"])
+             .takes_value(true))
+        .arg(Arg::with_name("data")
+             .help("Directory of the .ce files of the sample sheet")
+             .short("d")
+             .long("data")
+             .value_name("DIR")
+             .required(false)
+             .default_value("out")
+             .takes_value(true))
+        .arg(Arg::with_name("keygen")
+             .help("Generate the keypair of the enclave and exit")
+             .short("g")
+             .long("keygen")
+             .required(false)
+             .conflicts_with_all(&["case", "control", "sheet"])
+             .takes_value(false))
+        .arg(Arg::with_name("spid")
+             .help("Service provider ID registered with the attestation service, in hex, to quote the enclave key")
+             .long("spid")
+             .value_name("HEX")
+             .required(false)
+             .requires("keygen")
+             .takes_value(true))
+        .arg(Arg::with_name("test_attestation")
+             .help("Write a test attestation report in place of the quote, for ce --test_attestation, see test/attest.sh")
+             .long("test_attestation")
+             .required(false)
+             .requires("keygen")
+             .conflicts_with("spid")
+             .takes_value(false))
+        .arg(Arg::with_name("linkable")
+             .help("Quote the enclave key with a linkable signature, as the SPID is registered")
+             .long("linkable")
+             .required(false)
+             .requires("spid")
+             .takes_value(false))
+        .arg(Arg::with_name("sealed")
+             .help("Keypair of the enclave, sealed to it")
+             .short("S")
+             .long("sealed")
+             .value_name("FILE")
+             .required(false)
+             .default_value("enclave.sealed")
+             .takes_value(true))
+        .arg(Arg::with_name("enclave")
+             .help("Public key of the enclave for ce, written by --keygen")
+             .short("e")
+             .long("enclave")
+             .value_name("FILE")
+             .required(false)
+             .default_value("enclave.pub")
+             .takes_value(true))
+        .arg(Arg::with_name("audit")
+             .help("Audit log the enclave appends a record of the analysis to, see ce --audit")
+             .long("audit")
+             .value_name("FILE")
+             .required(false)
+             .default_value("enclave.audit")
+             .takes_value(true))
+        .arg(Arg::with_name("reset_time")
+             .help("Accept a new time source of the platform since the last record of the audit log, which is flagged")
+             .long("reset_time")
+             .required(false)
+             .conflicts_with("keygen")
+             .takes_value(false))
+        .arg(Arg::with_name("recipient")
+             .help("Public key of the researcher the results are encrypted to, see ce --result_keygen")
+             .short("r")
+             .long("recipient")
+             .value_name("FILE")
+             .required_unless("keygen")
+             .takes_value(true))
         .arg(Arg::with_name("snp_count")
              .help("Count of top SNP alleles to compute")
              .short("k")
@@ -343,23 +681,129 @@ fn parse_cmd_args() -> AppParams {
              .value_name("BOOL")
              .required(false)
              .takes_value(false))        
+        .arg(Arg::with_name("aggregation")
+             .help("Counting of the keys in the enclave, oblivious to hide their memory accesses")
+             .short("A")
+             .long("aggregation")
+             .value_name("STR")
+             .required(false)
+             .possible_values(&["hashmap", "oblivious"])
+             .default_value("hashmap")
+             .takes_value(true))
+        .arg(Arg::with_name("epsilon")
+             .help("Release the top SNPs with differential privacy, within this epsilon")
+             .short("E")
+             .long("epsilon")
+             .value_name("FLOAT")
+             .required(false)
+             .requires("delta")
+             .takes_value(true))
+        .arg(Arg::with_name("delta")
+             .help("Delta of the differential privacy of the top SNPs, and of each allele frequency")
+             .long("delta")
+             .value_name("FLOAT")
+             .required(false)
+             .requires("epsilon")
+             .takes_value(true))
+        .arg(Arg::with_name("af_epsilon")
+             .help("Add noise to the allele frequecies, within this epsilon each, required by -a with --epsilon")
+             .long("af_epsilon")
+             .value_name("FLOAT")
+             .required(false)
+             .requires_all(&["epsilon", "output_allele_freq"])
+             .takes_value(true))
+        .arg(Arg::with_name("study")
+             .help("Study of the analysis, which the usage policy of the .ce files may require")
+             .short("t")
+             .long("study")
+             .value_name("ID")
+             .required(false)
+             .takes_value(true))
+        .arg(Arg::with_name("min_cases")
+             .help("Refuse the analysis with fewer cases")
+             .long("min_cases")
+             .value_name("INT")
+             .required(false)
+             .default_value("10")
+             .takes_value(true))
+        .arg(Arg::with_name("min_controls")
+             .help("Refuse the analysis with fewer controls")
+             .long("min_controls")
+             .value_name("INT")
+             .required(false)
+             .default_value("10")
+             .takes_value(true))
+        .arg(Arg::with_name("min_mac")
+             .help("Suppress the SNPs of a lower minor allele count from the results")
+             .long("min_mac")
+             .value_name("INT")
+             .required(false)
+             .default_value("5")
+             .takes_value(true))
+        .arg(Arg::with_name("max_k")
+             .help("Refuse the analysis with a higher count of top SNPs")
+             .long("max_k")
+             .value_name("INT")
+             .required(false)
+             .default_value("100")
+             .takes_value(true))
         .get_matches();
 
+    if matches.is_present("keygen") && !matches.is_present("spid") && !matches.is_present("test_attestation") {
+        panic!("--keygen requires --spid, or --test_attestation");
+    }
+
+    // With differential privacy, allele frequencies are only released noisy.
+    if matches.is_present("epsilon") && matches.is_present("output_allele_freq") && !matches.is_present("af_epsilon") {
+        panic!("--af_epsilon is required to output allele frequencies with --epsilon");
+    }
+
     let out_prefix = matches.value_of("output").unwrap().to_string();
     let mut chisq_file_name = out_prefix.clone();
-    chisq_file_name.push_str("Chisq.vcf");
+    chisq_file_name.push_str("Chisq.vcf.enc");
     let mut af_file_name = out_prefix.clone();
-    af_file_name.push_str("AF.vcf");
+    af_file_name.push_str("AF.vcf.enc");
+    let mut signed_file_name = out_prefix.clone();
+    signed_file_name.push_str("Results.sig");
 
     AppParams {
-        control: matches.value_of("control").unwrap().to_string(),
-        case: matches.value_of("case").unwrap().to_string(),
+        control: matches.value_of("control").map(|s| s.to_string() ),
+        case: matches.value_of("case").map(|s| s.to_string() ),
+        sheet: matches.value_of("sheet").map(|s| s.to_string() ),
+        data: matches.value_of("data").unwrap().to_string(),
+        keygen: matches.is_present("keygen"),
+        spid: matches.value_of("spid").map(|s| match parse_spid( s ) {
+            Ok(spid) => spid,
+            Err(why) => panic!("--spid {}: {}", s, why),
+        }),
+        linkable: matches.is_present("linkable"),
+        sealed: matches.value_of("sealed").unwrap().to_string(),
+        enclave_key: matches.value_of("enclave").unwrap().to_string(),
+        recipient: matches.value_of("recipient").map(|s| s.to_string() ),
         snp_cnt: matches.value_of("snp_count").unwrap().parse::<usize>().unwrap(),
         chisq_file_name: chisq_file_name,
         af_file_name: af_file_name,
+        signed_file_name: signed_file_name,
+        audit_file_name: matches.value_of("audit").unwrap().to_string(),
+        reset_time: matches.is_present("reset_time"),
         output_allele_freq: matches.is_present("output_allele_freq"), 
-        keys_per_block: shared::KEYS_PER_BLOCK_DEFAULT,  // keys_per_block,
-        keys_per_iter: shared::KEYS_PER_BLOCK_DEFAULT * shared::ITER_FACTOR_DEFAULT + 1,
+        aggregation: matches.value_of("aggregation").unwrap().parse().unwrap(),
+        epsilon: matches.value_of("epsilon").map_or( 0.0, |s| parse_budget( s, "epsilon", f64::INFINITY ) ),
+        delta: matches.value_of("delta").map_or( 0.0, |s| parse_budget( s, "delta", 1.0 ) ),
+        af_epsilon: matches.value_of("af_epsilon").map_or( 0.0, |s| parse_budget( s, "af_epsilon", f64::INFINITY ) ),
+        policy: shared::policy::Policy {
+            min_cases: matches.value_of("min_cases").unwrap().parse::<u32>().unwrap(),
+            min_controls: matches.value_of("min_controls").unwrap().parse::<u32>().unwrap(),
+            min_mac: matches.value_of("min_mac").unwrap().parse::<u32>().unwrap(),
+            max_k: matches.value_of("max_k").unwrap().parse::<u32>().unwrap(),
+        },
+        study: matches.value_of("study").map_or( [0u8;shared::policy::STUDY_SIZE], |s| match shared::policy::study_id( s ) {
+            Ok(id)   => id,
+            Err(why) => panic!("--study {}: {}", s, why),
+        }),
+        // Set from the file headers.
+        keys_per_block: 0,
+        keys_per_iter: 0,
     }
 }
 
@@ -408,6 +852,46 @@ fn run(eid: sgx_types::sgx_enclave_id_t, mut controls: Vec<vcf::Vcf>, mut cases:
 }
 
 
+// Found .ce files of a directory.
+fn find_files( dir: &str, kind: shared::Kind ) -> Vec<(path::PathBuf, shared::Kind, Option<String>)> {
+    WalkDir::new( dir )
+        .min_depth( 1 )
+        .follow_links( true )
+        .into_iter()
+        .filter_entry(|e| e.path().extension().unwrap() == "ce")
+        .map(|e| (e.unwrap().path().to_path_buf(), kind, None) )
+        .collect()
+}
+
+
+// Samples of the sheet and their .ce file in `data`, excluded samples are
+// left out.
+fn read_sheet( sheet: &path::Path, data: &str ) -> Result<Vec<(path::PathBuf, shared::Kind, Option<String>)>, String> {
+    let mut text = String::new();
+    try!( fs::File::open( sheet ).and_then(|mut f| f.read_to_string( &mut text ) ).map_err(|e| e.to_string() ) );
+
+    let mut inputs: Vec<(path::PathBuf, shared::Kind, Option<String>)> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut excluded = 0;
+    for entry in try!( shared::sheet::entries( &text ).map_err(|e| e.to_string() ) ) {
+        let entry = try!( entry.map_err(|e| e.to_string() ) );
+        if !seen.insert( entry.sample ) {
+            return Err(format!("duplicate sample {}", entry.sample));
+        }
+        match entry.phenotype.kind() {
+            Some(kind) => {
+                let mut ec_path = path::PathBuf::from( data );
+                ec_path.push( format!("{}.ce", entry.sample) );
+                inputs.push( (ec_path, kind, Some(entry.sample.to_string())) );
+            },
+            None => excluded += 1,
+        }
+    }
+    println!("{}: {} samples, {} excluded", sheet.display(), inputs.len(), excluded );
+    Ok(inputs)
+}
+
+
 fn main() {
     let start = Instant::now();
     println!("-=< Analysing >=-");
@@ -417,57 +901,133 @@ fn main() {
     }
     let params = app_params();
 
-    let mut fid: u32 = 0;
+    if params.keygen {
+        let enclave_id = create_enclave( "enclave.token", "enclave.signed.so" );
+        // Without a SPID, --test_attestation was given.
+        keygen( enclave_id, params.spid.as_ref(), params.linkable, &params.sealed, &params.enclave_key );
+        unsafe { sgx_types::sgx_destroy_enclave( enclave_id ) };
+        return
+    }
 
-    // Found control files.
-    let mut controls: Vec<_> = WalkDir::new( &params.control )
-        .min_depth( 1 )
-        .follow_links( true )
-        .into_iter()
-        .filter_entry(|e| e.path().extension().unwrap() == "ce")
-        .map( |e| {
-            let vcf = vcf::Vcf::new( e.unwrap().path().to_path_buf(),
-                            shared::Kind::Control, fid );
-            fid+=1;
-            vcf
-        }).collect();
-
-    // Found case files.
-    let mut cases: Vec<_> = WalkDir::new( &params.case )
-        .min_depth(1)
-        .follow_links( true )
-        .into_iter()
-        .filter_entry(|e| e.path().extension().unwrap() == "ce")
-        .map( |e| {
-            let vcf = vcf::Vcf::new( e.unwrap().path().to_path_buf(),
-                            shared::Kind::Case, fid );
-            fid+=1;
-            vcf
-        }).collect();
+    // Files to analyse with their phenotype.
+    let inputs = match params.sheet {
+        Some(ref sheet) => match read_sheet( path::Path::new( sheet ), &params.data ) {
+            Ok(inputs) => inputs,
+            Err(why)   => panic!("{}: {}", sheet, why),
+        },
+        // This is safe because args control and case are required without a sheet.
+        None => {
+            let mut inputs = find_files( params.control.as_ref().unwrap(), shared::Kind::Control );
+            inputs.append( &mut find_files( params.case.as_ref().unwrap(), shared::Kind::Case ) );
+            inputs
+        },
+    };
+
+    // The usage policy of each file is checked again by the enclave.
+    let timestamp = now();
+    let analysis = shared::policy::Analysis { output_allele_freq: params.output_allele_freq,
+                                              snp_cnt:            params.snp_cnt as u64,
+                                              study:              params.study,
+                                              date:               (timestamp / 86400) as u32 };
+
+    let mut controls = Vec::new();
+    let mut cases = Vec::new();
+    let mut relabelled = Vec::new();
+    for (ec_path, kind, sample) in inputs {
+        // Numbered once the excluded files are left out.
+        let vcf = vcf::Vcf::new( ec_path, 0 );
+        if let Some(sample) = sample {
+            if vcf.header.sample() != sample {
+                panic!("{}: holds sample {}, {} expected", vcf.ec_path.display(), vcf.header.sample(), sample );
+            }
+        }
+        // The enclave only trusts the phenotype sealed in the file: the sheet
+        // may leave a sample out, relabelling it takes `ce -r`.
+        if vcf.kind != Some(kind) {
+            relabelled.push( format!("{}: sealed phenotype {:?}, {:?} expected", vcf.ec_path.display(), vcf.header.phenotype(), kind ) );
+            continue;
+        }
+        if let Err(why) = vcf.header.usage().allows( &analysis ) {
+            println!("{}: excluded, {}", vcf.ec_path.display(), why );
+            continue;
+        }
+        match kind {
+            shared::Kind::Control => controls.push( vcf ),
+            shared::Kind::Case    => cases.push( vcf ),
+        }
+    }
+    if !relabelled.is_empty() {
+        panic!("{}\n{} files to relabel with ce -r, or to exclude from the sheet", relabelled.join("\n"), relabelled.len() );
+    }
+    // Files are registered in this order.
+    for (fid, vcf) in controls.iter_mut().chain( cases.iter_mut() ).enumerate() {
+        vcf.fid = fid as u32;
+    }
     
+    // The block size is read from the files.
+    params.keys_per_block = controls.iter().chain( cases.iter() )
+        .map(|vcf| vcf.header.keys_per_block() )
+        .max().unwrap_or( shared::KEYS_PER_BLOCK_DEFAULT );
+    params.keys_per_iter = params.keys_per_block * shared::ITER_FACTOR_DEFAULT + 1;
+
+    // This is safe because the recipient is required without keygen.
+    let recipient_path = params.recipient.as_ref().unwrap();
+    let recipient = match read_recipient( recipient_path ) {
+        Ok(recipient) => recipient,
+        Err(why)      => panic!("{}: {}", recipient_path, why),
+    };
+
+    // The enclave chains its record after the last one.
+    let audit = match read_last_audit( &params.audit_file_name ) {
+        Ok(audit) => audit,
+        Err(why)  => panic!("{}: {}", params.audit_file_name, why),
+    };
+
     // create chisquare and allele frequecies files
-    create_chisq_file();
+    create_output_file( &params.chisq_file_name );
     if params.output_allele_freq {
-        create_af_file();
+        create_output_file( &params.af_file_name );
     }
 
     // Create the enclave.
     let enclave_id = create_enclave( "enclave.token", "enclave.signed.so" );
+    load_key( enclave_id, &params.sealed );
 
-    // Init the enclave with the key, the number of files, and the size of the buffer.
+    // Init the enclave with the number of files, and the size of the buffer.
     let res = unsafe { encl_init( enclave_id,
-                                  controls.len() as u32,
-                                  cases.len() as u32,
+                                  (controls.len() + cases.len()) as u32,
                                   params.keys_per_block,
                                   params.keys_per_iter,
                                   params.snp_cnt,
-                                  params.output_allele_freq ) };
+                                  params.output_allele_freq,
+                                  params.aggregation as u8,
+                                  params.epsilon,
+                                  params.delta,
+                                  params.af_epsilon,
+                                  params.policy.min_cases,
+                                  params.policy.min_controls,
+                                  params.policy.min_mac,
+                                  params.policy.max_k,
+                                  params.study.as_ptr(),
+                                  params.study.len(),
+                                  timestamp,
+                                  params.reset_time,
+                                  audit.as_ptr(),
+                                  audit.len(),
+                                  recipient.as_ptr(),
+                                  recipient.len() ) };
     if res != SGX_SUCCESS {
         panic!("ERROR: encl_init returned {:?}", res );
     }
 
-    println!("{}: Found {} .ce files", &params.control, controls.len() );
-    println!("{}: Found {} .ce files", &params.case, cases.len() );
+    println!("Control: Found {} .ce files", controls.len() );
+    println!("Case: Found {} .ce files", cases.len() );
+    println!("Aggregation: {}", params.aggregation );
+    println!("Policy: {}", params.policy );
+    println!("Study: {}, date {}", shared::policy::Study( &params.study ), shared::policy::Date( analysis.date ) );
+    if params.epsilon > 0.0 {
+        println!("Differential privacy: epsilon {}, delta {}", params.epsilon, params.delta );
+    }
         
     // Register all files
     for vcf in controls.iter_mut().chain( cases.iter_mut() ) {
@@ -475,15 +1035,18 @@ fn main() {
     }
 
     run( enclave_id, controls, cases );
+    write_signed( enclave_id, &params.signed_file_name );
     
     // Destroy the enclave
     unsafe { sgx_types::sgx_destroy_enclave( enclave_id ) };
 
     if params.output_allele_freq {
-        println!("See files {} and {} for results", params.chisq_file_name, params.af_file_name );
+        println!("See files {} and {} for results, to decrypt with ce --decrypt", params.chisq_file_name, params.af_file_name );
     } else {
-        println!("See file {} for result", params.chisq_file_name );
+        println!("See file {} for result, to decrypt with ce --decrypt", params.chisq_file_name );
     }
+    println!("Signed by the enclave in {}, to check with ce --verify", params.signed_file_name );
+    println!("Audit record appended to {}, to check with ce --audit", params.audit_file_name );
 
     let dur = start.elapsed();
     let secs = dur.as_secs();
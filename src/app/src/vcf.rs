@@ -21,24 +21,103 @@
 
 use std::path;
 use std::fs;
+use std::io::{self,Read,Seek,SeekFrom};
+use std::mem;
 
 use shared;
 
 #[derive(Debug)]
 pub struct Vcf {
     pub ec_path:  path::PathBuf,
-    pub kind:     shared::Kind,
+    // Sealed in the header, None for an excluded sample.
+    pub kind:     Option<shared::Kind>,
     pub size:     u64,
     pub blk_nb:   u32,
     pub fid:      u32,
     pub eof:      bool,
+    pub header:   shared::FileHeader,
+    // Offset of each block in the file, blocks have variable sizes.
+    pub offsets:  Vec<u64>,
 }
 
 impl Vcf {
-    pub fn new( ec_path: path::PathBuf, kind: shared::Kind, fid: u32 ) -> Vcf {
-        let file = fs::File::open(&ec_path).unwrap();
+    // The phenotype is the one sealed in the file header.
+    pub fn new( ec_path: path::PathBuf, fid: u32 ) -> Vcf {
+        let mut file = fs::File::open(&ec_path).unwrap();
         let size = file.metadata().unwrap().len();
-        Vcf { ec_path: ec_path, kind: kind, size: size, blk_nb: 0, fid: fid , eof: false }
+        let header = match read_file_header( &mut file ) {
+            Ok(header) => header,
+            Err(why) => panic!("{}: {}", ec_path.display(), why),
+        };
+        let offsets = match index_blocks( &mut file, size ) {
+            Ok(offsets) => offsets,
+            Err(why) => panic!("{}: {}", ec_path.display(), why),
+        };
+        let kind = header.phenotype().kind();
+        Vcf { ec_path: ec_path, kind: kind, size: size, blk_nb: 0, fid: fid , eof: false,
+              header: header, offsets: offsets }
     }
 }
 
+
+// Read and check the .ce file header.
+fn read_file_header( file: &mut fs::File ) -> io::Result<shared::FileHeader> {
+    let mut header = shared::FileHeader::zeroed();
+    try!( file.read_exact( shared::as_u8_slice_mut( &mut header ) ) );
+    match header.check() {
+        Ok(_) => Ok(header),
+        Err(why) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", why))),
+    }
+}
+
+
+// Read a block header, `None` at the end of the file.
+pub fn read_header<R: Read>( file: &mut R ) -> io::Result<Option<shared::Header>> {
+    let mut header = shared::Header::new( 0, 0, [0;3] );
+    {
+        let buf = shared::as_u8_slice_mut( &mut header );
+        let readed = try!( file.read( buf ) );
+        if readed == 0 {
+            return Ok(None);
+        }
+        try!( file.read_exact( &mut buf[readed..] ) );
+    }
+    Ok(Some(header))
+}
+
+
+// Read a whole block (header and payload) in `buffer`, returns its size or 0
+// at the end of the file.
+pub fn read_block<R: Read>( file: &mut R, buffer: &mut Vec<u8> ) -> io::Result<usize> {
+    let header = match try!( read_header( file ) ) {
+        Some(header) => header,
+        None => return Ok(0),
+    };
+    let blk_size = match header.blk_size() {
+        Ok(blk_size) => blk_size as usize,
+        Err(why)     => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", why))),
+    };
+    let hdr_size = mem::size_of::<shared::Header>();
+    buffer.resize( blk_size, 0 );
+    buffer[..hdr_size].copy_from_slice( header.as_slice() );
+    try!( file.read_exact( &mut buffer[hdr_size..blk_size] ) );
+    Ok(blk_size)
+}
+
+
+fn index_blocks( file: &mut fs::File, size: u64 ) -> io::Result<Vec<u64>> {
+    let mut offsets = Vec::new();
+    let mut pos = mem::size_of::<shared::FileHeader>() as u64;
+    while pos < size {
+        offsets.push( pos );
+        let header = match try!( read_header( file ) ) {
+            Some(header) => header,
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block")),
+        };
+        pos = try!( file.seek( SeekFrom::Current( header.size() as i64 ) ) );
+    }
+    if pos != size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block"));
+    }
+    Ok(offsets)
+}
@@ -19,20 +19,46 @@
 */
 
 
+/* The EC_KEY interface is deprecated since OpenSSL 3.0 but is the one shared
+   by all versions. */
+#define OPENSSL_SUPPRESS_DEPRECATED
+
 #include <stdint.h>
+#include <string.h>
 #include <openssl/bio.h>
 #include <openssl/evp.h>
+#include <openssl/ec.h>
+#include <openssl/ecdh.h>
+#include <openssl/ecdsa.h>
+#include <openssl/obj_mac.h>
+#include <openssl/pem.h>
+#include <openssl/x509.h>
+#include <openssl/x509_vfy.h>
+#include <openssl/err.h>
+
+
+/* The AEADs of shared::cipher::Cipher, by value. */
+static const EVP_CIPHER* aead_cipher(int cipher)
+{
+  switch (cipher) {
+  case 0:  return EVP_aes_128_gcm();
+  case 1:  return EVP_aes_256_gcm();
+  case 2:  return EVP_chacha20_poly1305();
+  default: return NULL;
+  }
+}
 
 
-void aes_gcm_encrypt(uint8_t* key, const uint8_t* pt_buff, uint64_t pt_size, uint8_t* ct_buff, uint8_t* iv, uint8_t* mac)
+void aead_encrypt(int cipher, uint8_t* key, const uint8_t* pt_buff, uint64_t pt_size, uint8_t* ct_buff, uint8_t* iv,
+                  const uint8_t* aad, uint64_t aad_size, uint8_t* mac)
 {
   unsigned int outlen;
   EVP_CIPHER_CTX *ctx = EVP_CIPHER_CTX_new();
   /* Set cipher type and mode */
-  EVP_EncryptInit_ex(ctx, EVP_aes_128_gcm(), NULL, NULL, NULL);
+  EVP_EncryptInit_ex(ctx, aead_cipher(cipher), NULL, NULL, NULL);
 
   /* Set IV length if default 96 bits is not appropriate */
-  EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_GCM_SET_IVLEN, 12, NULL);
+  EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_AEAD_SET_IVLEN, 12, NULL);
 
   // printf("AES GCM Encrypt:\n");
   // printf("Plaintext %d:\n", 64);
@@ -41,11 +67,13 @@ void aes_gcm_encrypt(uint8_t* key, const uint8_t* pt_buff, uint64_t pt_size, uin
   /* Initialise key and IV */
   EVP_EncryptInit_ex(ctx, NULL, NULL, key, iv);
 
-  // /* Zero or more calls tol specify any AAD */
-  // EVP_EncryptUpdate(ctx, NULL, &outlen, gcm_iv, sizeof(gcm_iv));
+  /* Zero or more calls tol specify any AAD */
+  if (aad_size > 0)
+    EVP_EncryptUpdate(ctx, NULL, &outlen, aad, aad_size);
 
-  /* Encrypt plaintext */
-  EVP_EncryptUpdate(ctx, ct_buff, &outlen, pt_buff, pt_size);
+  /* Encrypt plaintext, none for a GMAC */
+  if (pt_size > 0)
+    EVP_EncryptUpdate(ctx, ct_buff, &outlen, pt_buff, pt_size);
 
   // /* Output encrypted block */
   // printf("CiphertextA %d:\n", outlen);
@@ -59,7 +87,7 @@ void aes_gcm_encrypt(uint8_t* key, const uint8_t* pt_buff, uint64_t pt_size, uin
   // BIO_dump_fp(stdout, ct_buff, 64);
 
   /* Get tag */
-  EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_GCM_GET_TAG, 16, mac);
+  EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_AEAD_GET_TAG, 16, mac);
 
   // /* Output tag */
   // printf("Tag %d:\n", 16);
@@ -71,3 +99,222 @@ void aes_gcm_encrypt(uint8_t* key, const uint8_t* pt_buff, uint64_t pt_size, uin
 
   EVP_CIPHER_CTX_free(ctx);
 }
+
+
+/* Returns 1 if the tag matches, the plaintext must be dropped otherwise. */
+int aead_decrypt(int cipher, uint8_t* key, const uint8_t* ct_buff, uint64_t ct_size, uint8_t* pt_buff, uint8_t* iv,
+                 const uint8_t* aad, uint64_t aad_size, uint8_t* mac)
+{
+  int outlen, ret;
+  EVP_CIPHER_CTX *ctx = EVP_CIPHER_CTX_new();
+  EVP_DecryptInit_ex(ctx, aead_cipher(cipher), NULL, NULL, NULL);
+  EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_AEAD_SET_IVLEN, 12, NULL);
+  EVP_DecryptInit_ex(ctx, NULL, NULL, key, iv);
+
+  if (aad_size > 0)
+    EVP_DecryptUpdate(ctx, NULL, &outlen, aad, aad_size);
+
+  if (ct_size > 0)
+    EVP_DecryptUpdate(ctx, pt_buff, &outlen, ct_buff, ct_size);
+
+  /* The tag is checked when finalising. */
+  EVP_CIPHER_CTX_ctrl(ctx, EVP_CTRL_AEAD_SET_TAG, 16, mac);
+  ret = EVP_DecryptFinal_ex(ctx, pt_buff, &outlen);
+
+  EVP_CIPHER_CTX_free(ctx);
+  return ret > 0;
+}
+
+
+/* SHA-256 of the input files, recorded in the .ce file header. */
+
+EVP_MD_CTX* sha256_new(void)
+{
+  EVP_MD_CTX *ctx = EVP_MD_CTX_create();
+  EVP_DigestInit_ex(ctx, EVP_sha256(), NULL);
+  return ctx;
+}
+
+void sha256_update(EVP_MD_CTX* ctx, const uint8_t* buff, uint64_t size)
+{
+  EVP_DigestUpdate(ctx, buff, size);
+}
+
+void sha256_final(EVP_MD_CTX* ctx, uint8_t* digest)
+{
+  unsigned int outlen;
+  EVP_DigestFinal_ex(ctx, digest, &outlen);
+}
+
+void sha256_free(EVP_MD_CTX* ctx)
+{
+  EVP_MD_CTX_destroy(ctx);
+}
+
+
+/* Ephemeral ECDH on P-256 with the public key of the enclave, see
+   shared::provision. Public keys are x || y and the secret is x, all big
+   endian. Returns 0 on success. */
+
+static void bn_write(const BIGNUM* bn, uint8_t* to)
+{
+  memset(to, 0, 32);
+  BN_bn2bin(bn, to + 32 - BN_num_bytes(bn));
+}
+
+int ecdh_p256(const uint8_t* peer, uint8_t* eph_pub, uint8_t* secret)
+{
+  int ret = -1;
+  EC_KEY* eph = EC_KEY_new_by_curve_name(NID_X9_62_prime256v1);
+  const EC_GROUP* group = EC_KEY_get0_group(eph);
+  EC_POINT* point = EC_POINT_new(group);
+  BIGNUM* x = BN_bin2bn(peer, 32, NULL);
+  BIGNUM* y = BN_bin2bn(peer + 32, 32, NULL);
+  BIGNUM* ex = BN_new();
+  BIGNUM* ey = BN_new();
+
+  if (EC_POINT_set_affine_coordinates_GFp(group, point, x, y, NULL) == 1
+      && EC_POINT_is_on_curve(group, point, NULL) == 1
+      && EC_KEY_generate_key(eph) == 1
+      && ECDH_compute_key(secret, 32, point, eph, NULL) == 32
+      && EC_POINT_get_affine_coordinates_GFp(group, EC_KEY_get0_public_key(eph), ex, ey, NULL) == 1) {
+    bn_write(ex, eph_pub);
+    bn_write(ey, eph_pub + 32);
+    ret = 0;
+  }
+
+  BN_free(ey);
+  BN_free(ex);
+  BN_free(y);
+  BN_free(x);
+  EC_POINT_free(point);
+  EC_KEY_free(eph);
+  return ret;
+}
+
+
+/* Keypair of a researcher, the enclave encrypts the results to it (see
+   shared::results). Returns 0 on success. */
+int p256_keygen(uint8_t* priv, uint8_t* pub)
+{
+  int ret = -1;
+  EC_KEY* key = EC_KEY_new_by_curve_name(NID_X9_62_prime256v1);
+  BIGNUM* x = BN_new();
+  BIGNUM* y = BN_new();
+
+  if (EC_KEY_generate_key(key) == 1
+      && EC_POINT_get_affine_coordinates_GFp(EC_KEY_get0_group(key), EC_KEY_get0_public_key(key), x, y, NULL) == 1) {
+    bn_write(EC_KEY_get0_private_key(key), priv);
+    bn_write(x, pub);
+    bn_write(y, pub + 32);
+    ret = 0;
+  }
+
+  BN_free(y);
+  BN_free(x);
+  EC_KEY_free(key);
+  return ret;
+}
+
+/* ECDH of a private key with the ephemeral public key of the enclave.
+   Returns 0 on success. */
+int ecdh_p256_priv(const uint8_t* priv, const uint8_t* peer, uint8_t* secret)
+{
+  int ret = -1;
+  EC_KEY* key = EC_KEY_new_by_curve_name(NID_X9_62_prime256v1);
+  const EC_GROUP* group = EC_KEY_get0_group(key);
+  EC_POINT* point = EC_POINT_new(group);
+  BIGNUM* d = BN_bin2bn(priv, 32, NULL);
+  BIGNUM* x = BN_bin2bn(peer, 32, NULL);
+  BIGNUM* y = BN_bin2bn(peer + 32, 32, NULL);
+
+  if (EC_KEY_set_private_key(key, d) == 1
+      && EC_POINT_set_affine_coordinates_GFp(group, point, x, y, NULL) == 1
+      && EC_POINT_is_on_curve(group, point, NULL) == 1
+      && ECDH_compute_key(secret, 32, point, key, NULL) == 32) {
+    ret = 0;
+  }
+
+  BN_free(y);
+  BN_free(x);
+  BN_clear_free(d);
+  EC_POINT_free(point);
+  EC_KEY_free(key);
+  return ret;
+}
+
+/* Check an ECDSA signature (r || s) of the SHA-256 `hash` with the signing key
+   of the enclave, see shared::signature. Returns 1 if it is valid. */
+int ecdsa_p256_verify(const uint8_t* pub, const uint8_t* hash, const uint8_t* sig)
+{
+  int ret = 0;
+  EC_KEY* key = EC_KEY_new_by_curve_name(NID_X9_62_prime256v1);
+  const EC_GROUP* group = EC_KEY_get0_group(key);
+  EC_POINT* point = EC_POINT_new(group);
+  BIGNUM* x = BN_bin2bn(pub, 32, NULL);
+  BIGNUM* y = BN_bin2bn(pub + 32, 32, NULL);
+  ECDSA_SIG* s = ECDSA_SIG_new();
+
+  if (EC_POINT_set_affine_coordinates_GFp(group, point, x, y, NULL) == 1
+      && EC_KEY_set_public_key(key, point) == 1
+      && EC_KEY_check_key(key) == 1
+      && ECDSA_SIG_set0(s, BN_bin2bn(sig, 32, NULL), BN_bin2bn(sig + 32, 32, NULL)) == 1) {
+    ret = ECDSA_do_verify(hash, 32, s, key) == 1;
+  }
+
+  ECDSA_SIG_free(s);
+  BN_free(y);
+  BN_free(x);
+  EC_POINT_free(point);
+  EC_KEY_free(key);
+  return ret;
+}
+
+
+/* Attestation verification report of the IAS, see src/attestation.rs.
+   Returns 1 if `body` is signed in RSA-SHA256 by the first certificate of the
+   PEM `chain` and the chain leads to the PEM certificate `root`. */
+int ias_verify(const uint8_t* body, uint64_t body_size, const uint8_t* sig, uint64_t sig_size,
+               const uint8_t* chain, uint64_t chain_size, const uint8_t* root, uint64_t root_size)
+{
+  int ret = 0;
+  X509* ca = NULL;
+  X509* signer = NULL;
+  X509* cert;
+  STACK_OF(X509)* untrusted = sk_X509_new_null();
+  X509_STORE* store = X509_STORE_new();
+  X509_STORE_CTX* ctx = X509_STORE_CTX_new();
+  EVP_MD_CTX* md = EVP_MD_CTX_create();
+  BIO* bio;
+
+  bio = BIO_new_mem_buf(root, (int)root_size);
+  ca = PEM_read_bio_X509(bio, NULL, NULL, NULL);
+  BIO_free(bio);
+  bio = BIO_new_mem_buf(chain, (int)chain_size);
+  while ((cert = PEM_read_bio_X509(bio, NULL, NULL, NULL)) != NULL) {
+    if (signer == NULL)
+      signer = cert;
+    else
+      sk_X509_push(untrusted, cert);
+  }
+  BIO_free(bio);
+  /* The end of the chain is reported as an error. */
+  ERR_clear_error();
+
+  if (ca != NULL && signer != NULL
+      && X509_STORE_add_cert(store, ca) == 1
+      && X509_STORE_CTX_init(ctx, store, signer, untrusted) == 1
+      && X509_verify_cert(ctx) == 1
+      && EVP_DigestVerifyInit(md, NULL, EVP_sha256(), NULL, X509_get0_pubkey(signer)) == 1
+      && EVP_DigestVerifyUpdate(md, body, body_size) == 1) {
+    ret = EVP_DigestVerifyFinal(md, sig, sig_size) == 1;
+  }
+
+  EVP_MD_CTX_destroy(md);
+  X509_STORE_CTX_free(ctx);
+  X509_STORE_free(store);
+  sk_X509_pop_free(untrusted, X509_free);
+  X509_free(signer);
+  X509_free(ca);
+  return ret;
+}
@@ -0,0 +1,129 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Attestation verification reports of the IAS, see `shared::attestation`.
+//
+// The report is read from the HTTP response of the IAS as `curl -i` saves
+// it: the report is the body, the `X-IASReport-Signature` header its RSA
+// signature in base64 and the `X-IASReport-Signing-Certificate` header the
+// certificate chain of the signing key, URL encoded PEM. The chain must lead
+// to the root certificate given by the data owner.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::str;
+
+use shared;
+
+use compress::Error;
+
+extern "C" {
+    fn ias_verify(body: *const u8, body_size: u64, sig: *const u8, sig_size: u64,
+                  chain: *const u8, chain_size: u64, root: *const u8, root_size: u64) -> i32;
+}
+
+
+// The RSA signature of the IAS is 2048 bits long.
+const SIGNATURE_SIZE_MAX: usize = 512;
+
+
+// The report of `path`, once its signature is checked.
+pub fn read_report( path: &Path, root_path: &Path ) -> Result<String,Error> {
+    let mut response = Vec::new();
+    try!( try!( fs::File::open( path ) ).read_to_end( &mut response ) );
+    let mut root = Vec::new();
+    try!( try!( fs::File::open( root_path ) ).read_to_end( &mut root ) );
+
+    let (headers, body) = try!( split_response( &response ) );
+    let signature = try!( header( headers, "X-IASReport-Signature" ) );
+    let mut sig = [0u8;SIGNATURE_SIZE_MAX];
+    let sig_len = try!( shared::attestation::base64_decode( signature, &mut sig ) );
+    let chain = try!( url_decode( try!( header( headers, "X-IASReport-Signing-Certificate" ) ) ) );
+
+    let valid = unsafe { ias_verify( body.as_ptr(), body.len() as u64, sig.as_ptr(), sig_len as u64,
+                                     chain.as_ptr(), chain.len() as u64, root.as_ptr(), root.len() as u64 ) };
+    if valid != 1 {
+        return Err(Error::AttestationSignature);
+    }
+    match str::from_utf8( body ) {
+        Ok(body) => Ok(body.to_string()),
+        Err(_)   => Err(Error::Shared( shared::Error::InvalidAttestation )),
+    }
+}
+
+
+// Headers and body of the last response, `curl -i` also saves the interim
+// ones (`100 Continue`). Only a `200` is a report.
+fn split_response( response: &[u8] ) -> Result<(&str,&[u8]),Error> {
+    let invalid = Error::Shared( shared::Error::InvalidAttestation );
+    let mut rest = response;
+    loop {
+        let end = match rest.windows(4).position(|w| w == b"\r\n\r\n" ) {
+            Some(end) => end,
+            None      => return Err(invalid),
+        };
+        let headers = match str::from_utf8( &rest[..end] ) {
+            Ok(headers) => headers,
+            Err(_)      => return Err(invalid),
+        };
+        rest = &rest[end+4..];
+        let status = headers.lines().next().and_then(|l| l.split_whitespace().nth(1) );
+        match status {
+            Some("100") => continue,
+            Some("200") => return Ok( (headers, rest) ),
+            _           => return Err(invalid),
+        }
+    }
+}
+
+fn header<'a>( headers: &'a str, name: &str ) -> Result<&'a str,Error> {
+    for line in headers.lines().skip(1) {
+        let mut parts = line.splitn( 2, ':' );
+        if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+            if key.trim().eq_ignore_ascii_case( name ) {
+                return Ok( value.trim() );
+            }
+        }
+    }
+    Err(Error::Shared( shared::Error::InvalidAttestation ))
+}
+
+fn url_decode( s: &str ) -> Result<Vec<u8>,Error> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity( bytes.len() );
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get( i+1..i+3 ).and_then(|h| str::from_utf8( h ).ok() );
+            match hex.and_then(|h| u8::from_str_radix( h, 16 ).ok() ) {
+                Some(b) => out.push( b ),
+                None    => return Err(Error::Shared( shared::Error::InvalidAttestation )),
+            }
+            i += 3;
+        } else {
+            out.push( bytes[i] );
+            i += 1;
+        }
+    }
+    Ok(out)
+}
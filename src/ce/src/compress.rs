@@ -21,124 +21,768 @@
 
 use std::io;
 use std::fs;
+use std::cmp;
 use std::fmt;
 use std::num;
 use std::io::BufRead;
-use std::io::Write;
+use std::io::{Read,Seek,Write};
+use std::str;
 use std::str::FromStr;
 use std::path;
+use std::mem;
 
 use rand;
+use rand::Rng;
 
 use shared;
+use shared::cipher::Cipher;
+
+use digest;
+use sort;
+use input;
+use keys;
 
 extern "C" {
-    fn aes_gcm_encrypt(key:  *const u8,
-                       from: *const u8,
-                       size: u64,
-                       to:   *mut u8,
-                       iv:   *mut u8,
-                       mac:  *mut u8);
+    fn aead_encrypt(cipher: i32,
+                    key:  *const u8,
+                    from: *const u8,
+                    size: u64,
+                    to:   *mut u8,
+                    iv:   *mut u8,
+                    aad:  *const u8,
+                    aad_size: u64,
+                    mac:  *mut u8);
+
+    fn aead_decrypt(cipher: i32,
+                    key:  *const u8,
+                    from: *const u8,
+                    size: u64,
+                    to:   *mut u8,
+                    iv:   *mut u8,
+                    aad:  *const u8,
+                    aad_size: u64,
+                    mac:  *mut u8) -> i32;
+}
+
+
+// AEAD encryption of `buf` in place, an empty buffer gives a MAC of `aad`.
+// The first `cipher.key_size()` bytes of `key` are used.
+pub fn encrypt( cipher: Cipher, key: &[u8], buf: &mut [u8], iv: &[u8], aad: &[u8], mac: &mut [u8;16] ) {
+    assert!( iv.len() == 12 && key.len() >= cipher.key_size() );
+    unsafe {
+        aead_encrypt( cipher as i32,
+                      key.as_ptr(),
+                      buf.as_ptr(),
+                      buf.len() as u64,
+                      buf.as_mut_ptr(),
+                      iv.as_ptr() as *mut u8,
+                      aad.as_ptr(),
+                      aad.len() as u64,
+                      mac.as_mut_ptr() );
+    }
 }
 
 
 fn write_block<T: Write, R: rand::Rng>( writer:  &mut io::BufWriter<T>,
+                                        cipher:  Cipher,
+                                        key:     &[u8],
                                         content: &[shared::Key],
-                                        buffer:  &mut [u8],
+                                        side:    &[u8],
+                                        aad:     &[u8],
+                                        buffer:  &mut Vec<u8>,
                                         rng:     &mut R ) -> io::Result<usize> {
     // convert vector to &[u8].
     let slice = shared::as_u8_slice( content );
-    
+    let size  = slice.len() + side.len();
+    let hdr_size = mem::size_of::<shared::Header>();
+
+    // The keys and the side table are encrypted in place.
+    buffer.clear();
+    buffer.resize( hdr_size, 0 );
+    buffer.extend_from_slice( slice );
+    buffer.extend_from_slice( side );
+
     // Encrypt the block.
-    let blk_size = {
-        // let aad = [0u8;0];
-        let (mut hdr,buf) = buffer.split_at_mut( 32 );
-        let size  = slice.len();
+    {
+        let (hdr,buf) = buffer.split_at_mut( hdr_size );
     
         // Initialize block header.
         let mut iv = [0u32;3];
         for i in iv.iter_mut() {
             *i = rng.gen::<u32>();
         }
-        let header = shared::Header::new( size as u32, iv );
-
+        let mut header = shared::Header::new( size as u32, content.len() as u32, iv );
+        let mut mac = [0u8;16];
+        encrypt( cipher, key, buf, header.iv(), aad, &mut mac );
+        *header.mac_mut() = mac;
         hdr.copy_from_slice( header.as_slice() );
-
-         unsafe {
-             aes_gcm_encrypt( shared::AES_KEY.as_ptr(),
-                              slice.as_ptr(),
-                              slice.len() as u64,
-                              buf.as_mut_ptr(),
-                              hdr[4..16].as_mut_ptr(),
-                              hdr[16..].as_mut_ptr() );
-        } 
-        header.blk_size() as usize
-    };
+    }
     
     // write the block.
-    writer.write( &buffer[..blk_size] )
+    try!( writer.write_all( &buffer[..] ) );
+    Ok( buffer.len() )
+}
+
+
+// MAC of the file header, see `shared::FileHeader`.
+fn header_mac( header: &shared::FileHeader, key: &[u8] ) -> [u8;16] {
+    let mut mac = [0u8;16];
+    encrypt( header.cipher(), key, &mut [], header.iv(), header.authenticated(), &mut mac );
+    mac
+}
+
+fn seal_header<R: rand::Rng>( header: &mut shared::FileHeader, key: &[u8], rng: &mut R ) {
+    rng.fill_bytes( header.iv_mut() );
+    let mac = header_mac( header, key );
+    *header.mac_mut() = mac;
+}
+
+
+// AEAD decryption of `buf` in place, returns false if the MAC does not
+// match.
+pub fn decrypt( cipher: Cipher, key: &[u8], buf: &mut [u8], iv: &[u8], aad: &[u8], mac: &[u8;16] ) -> bool {
+    assert!( iv.len() == 12 && key.len() >= cipher.key_size() );
+    unsafe {
+        aead_decrypt( cipher as i32,
+                      key.as_ptr(),
+                      buf.as_ptr(),
+                      buf.len() as u64,
+                      buf.as_mut_ptr(),
+                      iv.as_ptr() as *mut u8,
+                      aad.as_ptr(),
+                      aad.len() as u64,
+                      mac.as_ptr() as *mut u8 ) == 1
+    }
+}
+
+
+// Append a side table record for the key at `idx` in the current block.
+fn push_side( side: &mut Vec<u8>, tag: shared::side::Tag, idx: usize, parts: &[&str] ) -> Result<(),Error> {
+    let len = parts.iter().fold( 0, |l, p| l + p.len() );
+    if len > shared::side::RECORD_DATA_MAX {
+        return Err(Error::TooLong);
+    }
+    side.extend_from_slice( &shared::side::record_header( tag, idx as u16, len as u16 ) );
+    for p in parts {
+        side.extend_from_slice( p.as_bytes() );
+    }
+    Ok(())
+}
+
+
+// Contig dictionary of an input: its ##contig lines, or the reference build
+// when it has none.
+pub struct Contigs {
+    declared: Vec<String>,
+    build:    shared::contig::Build,
+}
+
+impl Contigs {
+    pub fn new( build: shared::contig::Build ) -> Contigs {
+        Contigs { declared: Vec::new(), build }
+    }
+
+    // Read a `##contig=<ID=name,...>` header line.
+    fn declare( &mut self, line: &str ) -> Result<(),Error> {
+        let fields = &line["##contig=<".len()..line.len()-1];
+        match fields.split(',').find(|f| f.starts_with("ID=") ) {
+            Some(id) => {
+                self.declared.push( id[3..].to_string() );
+                Ok(())
+            },
+            None => Err(Error::InvalidFormat),
+        }
+    }
+
+    pub fn index( &self, name: &str ) -> Result<u32,Error> {
+        let known = if self.declared.is_empty() {
+            self.build.contains( name )
+        } else {
+            self.declared.iter().any(|d| d == name )
+        };
+        if known {
+            Ok( shared::contig::index( name ) )
+        } else {
+            Err( Error::Shared( shared::Error::UnknownContig ) )
+        }
+    }
+}
+
+
+// What to do with several records of the same variant.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Duplicates {
+    Reject,
+    // Keep the first record.
+    Collapse,
+}
+
+impl FromStr for Duplicates {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject"   => Ok(Duplicates::Reject),
+            "collapse" => Ok(Duplicates::Collapse),
+            _          => Err(Error::InvalidFormat),
+        }
+    }
+}
+
+
+// Padding of the .ce files with dummy keys, so that their size and the key
+// counts of their blocks do not tell the number of variants of the sample.
+// Every block of a padded file is full, its side table filled up to
+// `PADDED_SIDE_PER_KEY` bytes per key, and padding blocks follow the last one.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Padding {
+    None,
+    // Up to this number of blocks, a sample needing more is rejected.
+    Blocks(u32),
+    // Up to the next power of two blocks.
+    Pow2,
+}
+
+impl FromStr for Padding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Padding::None),
+            "pow2" => Ok(Padding::Pow2),
+            _      => match try!( s.parse::<u32>() ) {
+                0 => Err(Error::InvalidPadding),
+                n => Ok(Padding::Blocks(n)),
+            },
+        }
+    }
+}
+
+const PADDED_SIDE_PER_KEY: usize = 8;
+
+// Longest padding record.
+const PAD_RECORD_MAX: usize = shared::side::RECORD_DATA_MAX;
+
+// Fill a side table up to `size` with padding records. `size` must be the
+// size of the table or at least a record header more.
+fn pad_side( side: &mut Vec<u8>, size: usize ) {
+    let hdr_size = shared::side::RECORD_HEADER_SIZE;
+    while side.len() < size {
+        let mut len = cmp::min( size - side.len() - hdr_size, PAD_RECORD_MAX );
+        // Leave room for the header of the next record.
+        let rest = size - side.len() - hdr_size - len;
+        if rest > 0 && rest < hdr_size {
+            len -= hdr_size;
+        }
+        side.extend_from_slice( &shared::side::record_header( shared::side::Tag::Padding, 0, len as u16 ) );
+        let end = side.len() + len;
+        side.resize( end, 0 );
+    }
+}
+
+
+// Writes the header and the blocks of a .ce file, sites must be pushed in
+// increasing order. The records of a site are buffered and written in key
+// order once the next site comes.
+pub struct BlockWriter {
+    writer:     io::BufWriter<fs::File>,
+    header:     shared::FileHeader,
+    // Derived from the data key, the file ID and the cipher of the header.
+    key:        [u8;shared::cipher::KEY_SIZE_MAX],
+    duplicates: Duplicates,
+    padding:    Padding,
+    content:    Vec<shared::Key>,
+    side:       Vec<u8>,
+    // Contigs named in the side table of the current block.
+    named:      Vec<u32>,
+    buffer:     Vec<u8>,
+    // Blocks and keys written so far.
+    nb_blocks:  u32,
+    nb_keys:    u64,
+    // Current site, its records and the text of their fields.
+    current:    Option<(u32,u32)>,
+    site:       Vec<(shared::Key,[usize;5])>,
+    site_text:  String,
+    prev:       Option<shared::Key>,
+    rng:        rand::ThreadRng,
+}
+
+impl BlockWriter {
+    fn new( to:         fs::File,
+            mut header: shared::FileHeader,
+            data_key:   &keys::DataKey,
+            duplicates: Duplicates,
+            padding:    Padding ) -> Result<BlockWriter,Error> {
+        let keys_per_blk = header.keys_per_block();
+        let mut rng = rand::thread_rng();
+        let mut file_id = [0u8;16];
+        rng.fill_bytes( &mut file_id );
+        header.set_file_id( file_id );
+        header.set_key_id( *data_key.id() );
+        header.set_wrapped_key( *data_key.wrapped() );
+        let key = data_key.file_key( &file_id, header.cipher() );
+        let mut writer = io::BufWriter::new( to );
+        // The file header is written again with the checksum at the end.
+        try!( writer.write_all( header.as_slice() ) );
+        Ok( BlockWriter { writer,
+                          header,
+                          key,
+                          duplicates,
+                          padding,
+                          content:    Vec::with_capacity( keys_per_blk as usize ),
+                          side:       Vec::new(),
+                          named:      Vec::new(),
+                          buffer:     Vec::with_capacity( shared::block_size( keys_per_blk ) ),
+                          nb_blocks:  0,
+                          nb_keys:    0,
+                          current:    None,
+                          site:       Vec::new(),
+                          site_text:  String::new(),
+                          prev:       None,
+                          rng } )
+    }
+
+    // Drop the blocks written so far.
+    fn reset(&mut self) -> Result<(),Error> {
+        try!( self.writer.flush() );
+        let hdr_size = mem::size_of::<shared::FileHeader>() as u64;
+        let to = self.writer.get_mut();
+        try!( to.set_len( hdr_size ) );
+        try!( to.seek( io::SeekFrom::Start( hdr_size ) ) );
+        self.content.clear();
+        self.side.clear();
+        self.named.clear();
+        self.nb_blocks = 0;
+        self.nb_keys = 0;
+        self.current = None;
+        self.site.clear();
+        self.site_text.clear();
+        self.prev = None;
+        Ok(())
+    }
+
+    // Add a record, returns false if its site is lower than the previous one.
+    pub fn push( &mut self, key: shared::Key, fields: &Fields ) -> Result<bool,Error> {
+        let site = (key.contig(), key.pos());
+        if let Some(current) = self.current {
+            match site.cmp( &current ) {
+                cmp::Ordering::Less    => return Ok(false),
+                cmp::Ordering::Equal   => (),
+                cmp::Ordering::Greater => try!( self.flush_site() ),
+            }
+        }
+        self.current = Some(site);
+
+        let mut bounds = [self.site_text.len();5];
+        for (i, f) in [fields.chrom, fields.id, fields.refe, fields.alt].iter().enumerate() {
+            self.site_text.push_str( f );
+            bounds[i+1] = self.site_text.len();
+        }
+        self.site.push( (key, bounds) );
+        Ok(true)
+    }
+
+    fn flush_site(&mut self) -> Result<(),Error> {
+        // The sort is stable so collapsed duplicates keep the first record.
+        self.site.sort_by_key(|s| s.0 );
+        let mut site = mem::take( &mut self.site );
+        let text = mem::take( &mut self.site_text );
+        for &(key, b) in site.iter() {
+            if self.prev == Some(key) {
+                match self.duplicates {
+                    Duplicates::Reject   => return Err(Error::Duplicate),
+                    Duplicates::Collapse => continue,
+                }
+            }
+            self.prev = Some(key);
+            try!( self.write_key( key, &Fields { chrom: &text[b[0]..b[1]],
+                                                 id:    &text[b[1]..b[2]],
+                                                 refe:  &text[b[2]..b[3]],
+                                                 alt:   &text[b[3]..b[4]] } ) );
+        }
+        site.clear();
+        self.site = site;
+        self.site_text = text;
+        self.site_text.clear();
+        Ok(())
+    }
+
+    // Side table size of the blocks of a padded file.
+    fn padded_side_size(&self) -> usize {
+        self.header.keys_per_block() as usize * PADDED_SIDE_PER_KEY
+    }
+
+    fn write_key( &mut self, key: shared::Key, fields: &Fields ) -> Result<(),Error> {
+        let idx = self.content.len();
+        let (side_len, nb_named) = (self.side.len(), self.named.len());
+        if !self.named.contains( &key.contig() ) {
+            try!( push_side( &mut self.side, shared::side::Tag::Contig, idx, &[fields.chrom] ) );
+            self.named.push( key.contig() );
+        }
+        if key.is_named_id() {
+            try!( push_side( &mut self.side, shared::side::Tag::Id, idx, &[fields.id] ) );
+        }
+        if key.is_named_allele() {
+            try!( push_side( &mut self.side, shared::side::Tag::Allele, idx, &[fields.refe, "\t", fields.alt] ) );
+        }
+        // A padded block ends early when its side table is full, room is left
+        // for a padding record.
+        if self.padding != Padding::None
+            && self.side.len() + shared::side::RECORD_HEADER_SIZE > self.padded_side_size() {
+            if idx == 0 {
+                return Err(Error::TooLong);
+            }
+            self.side.truncate( side_len );
+            self.named.truncate( nb_named );
+            try!( self.flush_block() );
+            return self.write_key( key, fields );
+        }
+        self.content.push( key );
+        if self.content.len() == self.content.capacity() {
+            try!( self.flush_block() );
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> Result<(),Error> {
+        if !self.content.is_empty() {
+            if self.padding != Padding::None {
+                for n in self.content.len()..self.header.keys_per_block() as usize {
+                    self.content.push( shared::Key::padding( n as u32 ) );
+                }
+                let size = self.padded_side_size();
+                pad_side( &mut self.side, size );
+            }
+            self.nb_blocks += 1;
+            self.nb_keys += self.content.len() as u64;
+            let aad = shared::block_aad( self.header.file_id(), self.nb_blocks, self.content.len() as u32 );
+            try!( write_block( &mut self.writer, self.header.cipher(), &self.key, &self.content, &self.side, &aad, &mut self.buffer, &mut self.rng ) );
+            self.content.clear();
+            self.side.clear();
+            self.named.clear();
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, checksum: [u8;32]) -> Result<(),Error> {
+        try!( self.flush_site() );
+        try!( self.flush_block() );
+        let nb_blocks = match self.padding {
+            Padding::None      => self.nb_blocks,
+            Padding::Blocks(n) => n,
+            Padding::Pow2      => cmp::max( self.nb_blocks, 1 ).next_power_of_two(),
+        };
+        if self.nb_blocks > nb_blocks {
+            return Err(Error::TooManyBlocks);
+        }
+        while self.nb_blocks < nb_blocks {
+            self.content.push( shared::Key::padding( 0 ) );
+            try!( self.flush_block() );
+        }
+        // A block without keys ends the file.
+        let footer = shared::footer( self.nb_keys, self.nb_blocks );
+        let aad = shared::block_aad( self.header.file_id(), self.nb_blocks + 1, 0 );
+        try!( write_block( &mut self.writer, self.header.cipher(), &self.key, &[], &footer, &aad, &mut self.buffer, &mut self.rng ) );
+        self.header.set_checksum( checksum );
+        seal_header( &mut self.header, &self.key, &mut self.rng );
+        try!( self.writer.flush() );
+        let to = self.writer.get_mut();
+        try!( to.seek( io::SeekFrom::Start(0) ) );
+        try!( to.write_all( self.header.as_slice() ) );
+        Ok(())
+    }
+}
+
+
+// Read a header line, returns true for data lines.
+pub fn read_header_line( line: &str, contigs: &mut Contigs ) -> Result<bool,Error> {
+    if line.starts_with("##contig=<") && line.ends_with('>') {
+        try!( contigs.declare( line ) );
+        Ok(false)
+    } else {
+        Ok( !line.starts_with('#') && !line.is_empty() )
+    }
+}
+
+
+// Where the genotypes of the data lines are.
+pub enum Layout {
+    // The competition's one-sample files, `heterozygous` or `homozygous` in
+    // the INFO column.
+    Competition,
+    // FORMAT/GT, with the sample column of each target.
+    Genotype(Vec<usize>),
+}
+
+// Find the layout from the `#CHROM` line, samples come after FORMAT.
+fn read_layout( line: &str, targets: &[Target] ) -> Result<Layout,Error> {
+    let cols: Vec<&str> = line.split('\t').collect();
+    if cols.len() < 10 || cols[8] != "FORMAT" {
+        return if targets.len() == 1 { Ok(Layout::Competition) } else { Err(Error::UnknownSample) };
+    }
+    let names = &cols[9..];
+    let mut columns = Vec::with_capacity( targets.len() );
+    for t in targets {
+        match names.iter().position(|n| *n == t.sample ) {
+            Some(c) => columns.push( c ),
+            // The sample of a one-sample file is named after the file.
+            None if names.len() == 1 && targets.len() == 1 => columns.push( 0 ),
+            None => return Err(Error::UnknownSample),
+        }
+    }
+    Ok( Layout::Genotype(columns) )
+}
+
+// Encode a data line for each target, returns false if it is not in site
+// order.
+pub fn encode_line( line: &str, contigs: &Contigs, layout: &Layout, writers: &mut [BlockWriter] ) -> Result<bool,Error> {
+    match *layout {
+        Layout::Competition => {
+            let (key, fields) = try!( parse_line( line, contigs ) );
+            writers[0].push( key, &fields )
+        },
+        Layout::Genotype(ref columns) => encode_genotypes( line, contigs, columns, writers ),
+    }
 }
 
 
+// A .ce file to write from a sample of the input.
+pub struct Target {
+    pub sample:    String,
+    pub phenotype: shared::sheet::Phenotype,
+    pub out_path:  path::PathBuf,
+}
 
 
+pub fn compress( inp_path:     &path::PathBuf,
+                 targets:      &[Target],
+                 data_key:     &keys::DataKey,
+                 keys_per_blk: u32,
+                 build:        shared::contig::Build,
+                 cipher:       Cipher,
+                 duplicates:   Duplicates,
+                 padding:      Padding,
+                 usage:        &shared::policy::Usage ) -> Result<(),Error> {
+    let mut reader = try!( input::open(inp_path) );
 
-pub fn compress( inp_path: &path::PathBuf, out_path: &path::PathBuf, keys_per_blk: u32 ) -> Result<(),Error> {
-    let from   = try!( fs::File::open(&inp_path) );
-    let to     = try!( fs::OpenOptions::new()
+    let mut writers = Vec::with_capacity( targets.len() );
+    for t in targets {
+        let to = try!( fs::OpenOptions::new()
                        .create(true)
                        .truncate(true)
                        .write(true)
-                       .open(&out_path) );
-    
-    // let mut reader = io::BufReader::with_capacity( 8*1024, from );
-    let mut reader = io::BufReader::new( from );
-    let mut writer = io::BufWriter::new( to );
-
-    let mut content = Vec::with_capacity( keys_per_blk as usize);
-    
-    let mut buffer = {
-        let size = shared::block_size( keys_per_blk ) as usize;
-        let mut b = Vec::with_capacity( size );
-        unsafe { b.set_len( size ) };
-        b.into_boxed_slice()
-    };
-                 
-    // Initialize random number generator.
-    let mut rng = rand::thread_rng();
+                       .open(&t.out_path) );
+        let header = try!( shared::FileHeader::new( keys_per_blk, build, &t.sample, t.phenotype, cipher, usage ) );
+        writers.push( try!( BlockWriter::new( to, header, data_key, duplicates, padding ) ) );
+    }
+    let mut checksum = digest::Sha256::new();
+    let mut contigs = Contigs::new( build );
+    let mut layout = None;
  
     let mut line = String::with_capacity( 64 );
     
-    loop {
+    // Most inputs are sorted and encoded as they are read.
+    let sorted = loop {
         let len = try!( reader.read_line( &mut line ) );
+        checksum.update( line.as_bytes() );
         if len == 0 {
-            if content.len() > 0 {
-                try!( write_block( &mut writer, &content, &mut buffer, &mut rng ) );
-            }
-            break
+            break true
         } else {
             let line = line.trim();
-            if !line.starts_with('#') && !line.is_empty() {
-                let data = try!( parse_line( line ) );
-                content.push( data );
-                if content.len() == content.capacity() {
-                    try!( write_block( &mut writer, &content, &mut buffer, &mut rng ) );
-                    content.clear();
-               }
+            if line.starts_with("#CHROM") {
+                layout = Some( try!( read_layout( line, targets ) ) );
+            } else if try!( read_header_line( line, &mut contigs ) ) {
+                // Files without a `#CHROM` line are in the competition layout.
+                if layout.is_none() {
+                    layout = Some( try!( read_layout( "", targets ) ) );
+                }
+                if !try!( encode_line( line, &contigs, layout.as_ref().unwrap(), &mut writers ) ) {
+                    break false
+                }
             }
         }
         line.clear();
+    };
+
+    // Otherwise start again with an external merge sort.
+    if !sorted {
+        for w in writers.iter_mut() {
+            try!( w.reset() );
+        }
+        checksum = digest::Sha256::new();
+        contigs = Contigs::new( build );
+        // This is safe because a data line was read.
+        let layout = layout.unwrap();
+        try!( sort::sort( inp_path, &targets[0].out_path, &mut contigs, &mut checksum,
+                          &mut |l, contigs| encode_line( l, contigs, &layout, &mut writers ) ) );
+    }
+
+    let checksum = checksum.finish();
+    for w in writers {
+        try!( w.finish( checksum ) );
     }
-    
     Ok(())
 }
 
 
 
+// Change the phenotype sealed in the header of a .ce file.
+// The data key is the one kept by `ce -k`.
+//
+// The file gets a new file ID and its blocks are encrypted again under the
+// key derived from it, so the previous header cannot be put back in front of
+// the blocks. A copy of the whole previous file is another input, see
+// `results::verify`.
+pub fn relabel( path: &path::Path, data_key: &[u8;shared::kdf::DATA_KEY_SIZE], phenotype: shared::sheet::Phenotype ) -> Result<(),Error> {
+    let mut reader = io::BufReader::new( try!( fs::File::open( path ) ) );
+    let mut header = shared::FileHeader::zeroed();
+    try!( reader.read_exact( shared::as_u8_slice_mut( &mut header ) ) );
+    try!( header.check() );
+    if keys::key_id( data_key ) != *header.key_id() {
+        return Err(Error::UnknownKey);
+    }
+    let old = keys::file_key( data_key, header.file_id(), header.cipher() );
+    if header_mac( &header, &old ) != *header.mac() {
+        return Err(Error::InvalidMac);
+    }
+
+    let mut relabelled = header;
+    relabelled.set_phenotype( phenotype );
+    let mut file_id = [0u8;16];
+    rand::thread_rng().fill_bytes( &mut file_id );
+    relabelled.set_file_id( file_id );
+    let new = keys::file_key( data_key, &file_id, relabelled.cipher() );
+    replace( path, "ce.relabel", |to| rekey_blocks( &mut reader, to, &header, &old, relabelled, &new ) )
+}
+
+
+// Encrypt a .ce file again under a new data key, block by block. The blocks
+// keep their layout, numbers and file ID, with fresh IVs, and are encrypted
+// with `cipher`, the one of the file if None. The new file replaces the old
+// one once complete.
+pub fn rekey( path:     &path::Path,
+              old_key:  &[u8;shared::kdf::DATA_KEY_SIZE],
+              data_key: &keys::DataKey,
+              cipher:   Option<Cipher> ) -> Result<(),Error> {
+    let mut reader = io::BufReader::new( try!( fs::File::open( path ) ) );
+    let mut header = shared::FileHeader::zeroed();
+    try!( reader.read_exact( shared::as_u8_slice_mut( &mut header ) ) );
+    try!( header.check() );
+    if keys::key_id( old_key ) != *header.key_id() {
+        return Err(Error::UnknownKey);
+    }
+    let old = keys::file_key( old_key, header.file_id(), header.cipher() );
+    if header_mac( &header, &old ) != *header.mac() {
+        return Err(Error::InvalidMac);
+    }
+
+    let mut rekeyed = header;
+    rekeyed.set_key_id( *data_key.id() );
+    rekeyed.set_wrapped_key( *data_key.wrapped() );
+    rekeyed.set_cipher( cipher.unwrap_or( header.cipher() ) );
+    let new = data_key.file_key( rekeyed.file_id(), rekeyed.cipher() );
+    replace( path, "ce.rekey", |to| rekey_blocks( &mut reader, to, &header, &old, rekeyed, &new ) )
+}
+
+// Write a file next to `path`, which it replaces once complete.
+fn replace<F>( path: &path::Path, extension: &str, write: F ) -> Result<(),Error>
+    where F: FnOnce(fs::File) -> Result<(),Error> {
+    let tmp_path = path.with_extension( extension );
+    let to = try!( fs::File::create( &tmp_path ) );
+    let res = write( to );
+    match res {
+        Ok(_)  => try!( fs::rename( &tmp_path, path ) ),
+        Err(_) => { let _ = fs::remove_file( &tmp_path ); },
+    }
+    res
+}
+
+// A file ending before its footer is truncated.
+fn read_block_part<R: Read>( reader: &mut R, buf: &mut [u8] ) -> Result<(),Error> {
+    match reader.read_exact( buf ) {
+        Ok(_)                                                 => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Err(Error::InvalidBlock),
+        Err(err)                                              => Err(Error::Io(err)),
+    }
+}
+
+// Decrypt the blocks of the file of header `from` with its key `old`, and
+// write them encrypted for `header` with its key `new`.
+fn rekey_blocks<R: Read>( reader:     &mut R,
+                          to:         fs::File,
+                          from:       &shared::FileHeader,
+                          old:        &[u8],
+                          mut header: shared::FileHeader,
+                          new:        &[u8] ) -> Result<(),Error> {
+    let mut writer = io::BufWriter::new( to );
+    try!( writer.write_all( header.as_slice() ) );
+
+    let mut rng = rand::thread_rng();
+    let mut payload = Vec::new();
+    let mut blk_nb = 0;
+    loop {
+        let mut hdr = shared::Header::new( 0, 0, [0;3] );
+        try!( read_block_part( reader, shared::as_u8_slice_mut( &mut hdr ) ) );
+        blk_nb += 1;
+        let nb_keys = try!( hdr.nb_keys() );
+        payload.resize( hdr.size() as usize, 0 );
+        try!( read_block_part( reader, &mut payload[..] ) );
+        let aad = shared::block_aad( from.file_id(), blk_nb, nb_keys );
+        if !decrypt( from.cipher(), old, &mut payload[..], hdr.iv(), &aad, hdr.mac() ) {
+            return Err(Error::InvalidBlock);
+        }
+
+        let mut iv = [0u32;3];
+        for i in iv.iter_mut() {
+            *i = rng.gen::<u32>();
+        }
+        let mut new_hdr = shared::Header::new( hdr.size(), nb_keys, iv );
+        let aad = shared::block_aad( header.file_id(), blk_nb, nb_keys );
+        let mut mac = [0u8;16];
+        encrypt( header.cipher(), new, &mut payload[..], new_hdr.iv(), &aad, &mut mac );
+        *new_hdr.mac_mut() = mac;
+        try!( writer.write_all( new_hdr.as_slice() ) );
+        try!( writer.write_all( &payload[..] ) );
+
+        // The footer ends the file.
+        if nb_keys == 0 {
+            break
+        }
+    }
+    if try!( reader.read( &mut [0u8;1] ) ) != 0 {
+        return Err(Error::InvalidBlock);
+    }
+
+    seal_header( &mut header, new, &mut rng );
+    try!( writer.flush() );
+    let to = writer.get_mut();
+    try!( to.seek( io::SeekFrom::Start(0) ) );
+    try!( to.write_all( header.as_slice() ) );
+    Ok(())
+}
+
+
 #[derive(Debug)]
 pub enum Error {
     InvalidLine,
     InvalidFormat,
+    InvalidGenotype,
+    UnknownSample,
+    InvalidMac,
+    TooLong,
+    Duplicate,
+    UntrustedEnclave,
+    InvalidKey,
+    UnknownKey,
+    InvalidBlock,
+    InvalidPadding,
+    TooManyBlocks,
+    InvalidSignature,
+    AttestationSignature,
+    InputsMismatch,
+    ResultsMismatch,
+    // The number of the first record out of the chain.
+    AuditChain(u64),
     Shared(shared::Error),
     Int(num::ParseIntError),
     Io(io::Error),
@@ -151,7 +795,23 @@ impl fmt::Display for Error {
             Error::Shared(ref err) => write!(f, "{}", err),
             Error::Io(ref err)     => write!(f, "{}", err),
             Error::InvalidFormat   => write!(f, "Invalid VCF Format"),
-            Error::InvalidLine     => write!(f, "Invalid Line Format"),            
+            Error::InvalidLine     => write!(f, "Invalid Line Format"),
+            Error::InvalidGenotype => write!(f, "Invalid Genotype"),
+            Error::UnknownSample   => write!(f, "Unknown Sample"),
+            Error::InvalidMac      => write!(f, "Invalid File Header MAC"),
+            Error::TooLong         => write!(f, "Field Too Long"),
+            Error::Duplicate       => write!(f, "Duplicate Record"),
+            Error::UntrustedEnclave => write!(f, "Enclave Key Not Trusted"),
+            Error::InvalidKey      => write!(f, "Invalid Key"),
+            Error::UnknownKey      => write!(f, "Encrypted Under Another Key"),
+            Error::InvalidBlock    => write!(f, "Invalid or Truncated Block"),
+            Error::InvalidPadding  => write!(f, "Invalid Padding"),
+            Error::TooManyBlocks   => write!(f, "More Blocks Than the Padding"),
+            Error::InvalidSignature => write!(f, "Invalid Enclave Signature"),
+            Error::AttestationSignature => write!(f, "Attestation Report Not Signed by the Attestation Service"),
+            Error::InputsMismatch  => write!(f, "Inputs Differ From the Signed Ones"),
+            Error::ResultsMismatch => write!(f, "Results File Differs From the Signed One"),
+            Error::AuditChain(seq) => write!(f, "Audit Log Chain Broken at Record {}", seq),
         }
     }
 }
@@ -175,43 +835,68 @@ impl From<shared::Error> for Error {
 }
 
 
-pub fn parse_line(line: &str) -> Result<shared::Key,Error> {
+// Text of the fields of a data line that may go to the side table.
+pub struct Fields<'a> {
+    pub chrom: &'a str,
+    pub id:    &'a str,
+    pub refe:  &'a str,
+    pub alt:   &'a str,
+}
+
+
+// The first columns of a data line, common to both layouts.
+pub struct Site<'a> {
+    pub contig: u32,
+    pub pos:    u32,
+    pub chrom:  &'a str,
+    pub id:     &'a str,
+    pub refe:   &'a str,
+    pub alt:    &'a str,
+}
+
+
+// Read CHROM to ALT, returns the site and the remaining columns.
+pub fn parse_site<'a>(line: &'a str, contigs: &Contigs) -> Result<(Site<'a>,str::Split<'a,char>),Error> {
     // Retreive the necessary fields.
     let mut iter = line.split('\t');
     // Read CHROM
-    let chrom: u8 =
+    let chrom =
         match iter.next() {
-            Some(s) => try!( s.parse() ),
+            Some(s) => s,
             None    => return Err(Error::InvalidLine),
         };
+    let contig = try!( contigs.index( chrom ) );
     // Read POS
     let pos: u32 =
         match iter.next() {
             Some(s) => try!( s.parse() ),
             None    => return Err(Error::InvalidLine),
         };
-   // Read ID
-    let id: u64 =
+    // Read ID
+    let id =
         match iter.next() {
-            Some(".") => 0 ,
-            Some(s) => {
-                if s.len() > 2 && &s[..2] == "rs" { try!( s[2..].parse() ) }
-                else { return Err(Error::InvalidFormat) }
-            },
+            Some(s) => s,
             None    => return Err(Error::InvalidLine),
-    };
+        };
     // Read REF.
     let refe =
         match iter.next() {
-            Some(s) => try!( shared::Base::from_str(s) ),
+            Some(s) => s,
             None    => return Err(Error::InvalidLine),
         };
     // Read ALT.
     let alt =
         match iter.next() {
-            Some(s) => try!( shared::Base::from_str(s) ),
+            Some(s) => s,
             None    => return Err(Error::InvalidLine),
         };
+    Ok( (Site { contig, pos, chrom, id, refe, alt }, iter) )
+}
+
+
+pub fn parse_line<'a>(line: &'a str, contigs: &Contigs) -> Result<(shared::Key,Fields<'a>),Error> {
+    let (site, mut iter) = try!( parse_site( line, contigs ) );
+    let allele = try!( shared::Key::encode_allele(site.refe, site.alt) );
     // Skip QUAL and FILTER and read TYP
     let typ = 
         match iter.nth(2) {
@@ -219,5 +904,116 @@ pub fn parse_line(line: &str) -> Result<shared::Key,Error> {
             _       => return Err(Error::InvalidLine),
         };
 
-    Ok( shared::Key::new(chrom, pos, id, refe, alt, typ) )
+    let key = shared::Key::new(site.contig, site.pos, shared::Key::encode_id(site.id), allele, typ);
+    Ok( (key, Fields { chrom: site.chrom, id: site.id, refe: site.refe, alt: site.alt }) )
+}
+
+
+// Count the copies of each alternate allele in a GT field: `0/1`, `1|1`,
+// `./.`, or `1` for haploid calls.
+pub fn parse_gt( gt: &str, counts: &mut [u8] ) -> Result<(),Error> {
+    for c in counts.iter_mut() {
+        *c = 0;
+    }
+    for a in gt.split(&['/', '|'][..]) {
+        if a == "." {
+            continue
+        }
+        let a: usize = try!( a.parse() );
+        if a > counts.len() {
+            return Err(Error::InvalidGenotype);
+        }
+        if a > 0 {
+            counts[a-1] = counts[a-1].saturating_add( 1 );
+        }
+    }
+    Ok(())
+}
+
+
+// Multi-allelic sites give a record per alternate allele carried by the
+// sample, with one copy heterozygous and two homozygous.
+fn encode_genotypes( line: &str, contigs: &Contigs, columns: &[usize], writers: &mut [BlockWriter] ) -> Result<bool,Error> {
+    let (site, mut iter) = try!( parse_site( line, contigs ) );
+    // Skip QUAL, FILTER and INFO and read FORMAT.
+    let gt =
+        match iter.nth(3) {
+            Some(s) => match s.split(':').position(|f| f == "GT" ) {
+                Some(gt) => gt,
+                // Nothing to encode for a site without genotypes.
+                None     => return Ok(true),
+            },
+            None    => return Err(Error::InvalidLine),
+        };
+    let samples: Vec<&str> = iter.collect();
+
+    let alts: Vec<&str> = site.alt.split(',').collect();
+    let mut alleles = Vec::with_capacity( alts.len() );
+    for alt in alts.iter() {
+        // Spanning deletions and missing alleles are not variants of the site.
+        if *alt == "*" || *alt == "." {
+            alleles.push( None );
+        } else {
+            alleles.push( Some( try!( shared::Key::encode_allele( site.refe, alt ) ) ) );
+        }
+    }
+    let id = shared::Key::encode_id( site.id );
+
+    let mut counts = vec![0u8; alts.len()];
+    for (writer, &col) in writers.iter_mut().zip( columns.iter() ) {
+        // Trailing FORMAT fields may be dropped, GT included.
+        let sample_gt =
+            match samples.get( col ) {
+                Some(s) => s.split(':').nth( gt ).unwrap_or("."),
+                None    => return Err(Error::InvalidLine),
+            };
+        try!( parse_gt( sample_gt, &mut counts ) );
+        for (k, &n) in counts.iter().enumerate() {
+            let allele = match alleles[k] {
+                Some(a) if n > 0 => a,
+                _                => continue,
+            };
+            let typ = if n == 1 { shared::Typ::Heterozygous } else { shared::Typ::Homozygous };
+            let key = shared::Key::new( site.contig, site.pos, id, allele, typ );
+            let fields = Fields { chrom: site.chrom, id: site.id, refe: site.refe, alt: alts[k] };
+            if !try!( writer.push( key, &fields ) ) {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_gt,Error};
+
+    #[test]
+    fn gt() {
+        let mut counts = [9u8;3];
+        for &(gt, expected) in [("0/0", [0, 0, 0]), ("0/1", [1, 0, 0]), ("1|0", [1, 0, 0]), ("1/1", [2, 0, 0]),
+                                ("1/3", [1, 0, 1]), ("2|2", [0, 2, 0]), ("./.", [0, 0, 0]), ("./1", [1, 0, 0]),
+                                (".", [0, 0, 0]), ("1", [1, 0, 0]), ("0", [0, 0, 0]), ("1/2/3", [1, 1, 1])].iter() {
+            assert!( parse_gt( gt, &mut counts ).is_ok(), "{}", gt );
+            assert_eq!( counts, expected, "{}", gt );
+        }
+        // Copies saturate.
+        let mut counts = [0u8;1];
+        let gt: Vec<&str> = (0..300).map(|_| "1" ).collect();
+        assert!( parse_gt( &gt.join( "/" ), &mut counts ).is_ok() );
+        assert_eq!( counts, [255] );
+
+        let mut counts = [0u8;2];
+        match parse_gt( "0/3", &mut counts ) {
+            Err(Error::InvalidGenotype) => (),
+            res => panic!("0/3: {:?}", res),
+        }
+        for gt in ["", "0/", "a/1", "-1/0", "0 /1", "0:1"].iter() {
+            match parse_gt( gt, &mut counts ) {
+                Err(Error::Int(_)) => (),
+                res => panic!("{}: {:?}", gt, res),
+            }
+        }
+    }
 }
@@ -0,0 +1,86 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// SHA-256 through the openssl shim of aesgcm.c.
+
+use std::fs;
+use std::io::{self,Read};
+use std::path::Path;
+
+enum Ctx {}
+
+extern "C" {
+    fn sha256_new() -> *mut Ctx;
+    fn sha256_update(ctx: *mut Ctx, from: *const u8, size: u64);
+    fn sha256_final(ctx: *mut Ctx, digest: *mut u8);
+    fn sha256_free(ctx: *mut Ctx);
+}
+
+
+pub struct Sha256 {
+    ctx: *mut Ctx,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 { ctx: unsafe { sha256_new() } }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        unsafe { sha256_update( self.ctx, data.as_ptr(), data.len() as u64 ) };
+    }
+
+    pub fn finish(self) -> [u8;32] {
+        let mut digest = [0u8;32];
+        unsafe { sha256_final( self.ctx, digest.as_mut_ptr() ) };
+        digest
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { sha256_free( self.ctx ) };
+    }
+}
+
+
+pub fn sha256(data: &[u8]) -> [u8;32] {
+    let mut hash = Sha256::new();
+    hash.update( data );
+    hash.finish()
+}
+
+
+// SHA-256 of a whole file.
+pub fn sha256_file(path: &Path) -> io::Result<[u8;32]> {
+    let mut file = try!( fs::File::open( path ) );
+    let mut hash = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = try!( file.read( &mut buf ) );
+        if n == 0 {
+            break;
+        }
+        hash.update( &buf[..n] );
+    }
+    Ok( hash.finish() )
+}
@@ -0,0 +1,189 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Input files: plain, gzip or BGZF compressed VCF.
+//
+// The format is detected from the first bytes of the file. BGZF blocks are
+// independent deflate streams, they are inflated by batches in parallel.
+
+use std::io::{self,Read,BufRead};
+use std::fs;
+use std::path;
+
+use flate2::Crc;
+use flate2::read::{DeflateDecoder,MultiGzDecoder};
+
+use rayon::prelude::*;
+
+
+// Number of BGZF blocks inflated together, blocks hold up to 64KB.
+const BGZF_BATCH: usize = 64;
+
+const GZIP_MAGIC: [u8;3] = [0x1f, 0x8b, 0x08];
+const FEXTRA: u8 = 0x04;
+
+
+// Stem of a VCF file name: `name` for `name.vcf`, `name.vcf.gz` or
+// `name.vcf.bgz`, `None` for any other file.
+pub fn vcf_stem( path: &path::Path ) -> Option<String> {
+    let name = path.file_name().and_then(|n| n.to_str() )?;
+    for ext in [".vcf", ".vcf.gz", ".vcf.bgz"].iter() {
+        if name.len() > ext.len() && name.ends_with( ext ) {
+            return Some( name[..name.len()-ext.len()].to_string() );
+        }
+    }
+    None
+}
+
+
+pub fn open( path: &path::PathBuf ) -> io::Result<Box<dyn BufRead>> {
+    let mut file = io::BufReader::new( try!( fs::File::open(path) ) );
+    let (gzip, bgzf) = {
+        let head = try!( file.fill_buf() );
+        let gzip = head.len() >= 4 && head[..3] == GZIP_MAGIC;
+        (gzip, gzip && head[3] & FEXTRA != 0 && is_bgzf( head ))
+    };
+
+    if bgzf {
+        Ok( Box::new( io::BufReader::new( Bgzf::new( file ) ) ) )
+    } else if gzip {
+        Ok( Box::new( io::BufReader::new( try!( MultiGzDecoder::new( file ) ) ) ) )
+    } else {
+        Ok( Box::new( file ) )
+    }
+}
+
+
+// The extra field of a BGZF header holds the `BC` subfield with the block size.
+fn is_bgzf( head: &[u8] ) -> bool {
+    head.len() >= 18 && head[12] == b'B' && head[13] == b'C' && head[14] == 2 && head[15] == 0
+}
+
+
+pub struct Bgzf<R> {
+    inner: R,
+    data:  Vec<u8>,
+    pos:   usize,
+    eof:   bool,
+}
+
+impl<R: Read> Bgzf<R> {
+    pub fn new( inner: R ) -> Bgzf<R> {
+        Bgzf { inner, data: Vec::new(), pos: 0, eof: false }
+    }
+
+    // Read one compressed block, `None` at the end of the file.
+    fn read_block( &mut self ) -> io::Result<Option<Vec<u8>>> {
+        let mut head = [0u8;12];
+        let readed = try!( self.inner.read( &mut head ) );
+        if readed == 0 {
+            return Ok(None);
+        }
+        try!( self.inner.read_exact( &mut head[readed..] ) );
+        if head[..3] != GZIP_MAGIC || head[3] & FEXTRA == 0 {
+            return Err(invalid("invalid BGZF block header"));
+        }
+
+        // Find the block size in the extra subfields.
+        let xlen = head[10] as usize | (head[11] as usize) << 8;
+        let mut extra = vec![0u8; xlen];
+        try!( self.inner.read_exact( &mut extra ) );
+        let mut bsize = None;
+        let mut i = 0;
+        while i + 4 <= xlen {
+            let slen = extra[i+2] as usize | (extra[i+3] as usize) << 8;
+            if extra[i] == b'B' && extra[i+1] == b'C' && slen == 2 && i + 6 <= xlen {
+                bsize = Some( extra[i+4] as usize | (extra[i+5] as usize) << 8 );
+            }
+            i += 4 + slen;
+        }
+        let bsize = match bsize {
+            Some(bsize) if bsize + 1 >= 12 + xlen + 8 => bsize,
+            _ => return Err(invalid("missing BGZF block size")),
+        };
+
+        // Compressed data followed by CRC32 and ISIZE.
+        let mut block = vec![0u8; bsize + 1 - 12 - xlen];
+        try!( self.inner.read_exact( &mut block ) );
+        Ok(Some(block))
+    }
+
+    fn fill( &mut self ) -> io::Result<()> {
+        let mut blocks = Vec::with_capacity( BGZF_BATCH );
+        while blocks.len() < BGZF_BATCH {
+            match try!( self.read_block() ) {
+                Some(block) => blocks.push( block ),
+                None => {
+                    self.eof = true;
+                    break
+                },
+            }
+        }
+
+        let inflated: Vec<io::Result<Vec<u8>>> = blocks.par_iter().map(|b| inflate( b ) ).collect();
+        self.data.clear();
+        self.pos = 0;
+        for data in inflated {
+            self.data.extend_from_slice( &try!( data ) );
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Bgzf<R> {
+    fn read( &mut self, buf: &mut [u8] ) -> io::Result<usize> {
+        // Empty blocks (as the EOF marker) give empty batches.
+        while self.pos == self.data.len() && !self.eof {
+            try!( self.fill() );
+        }
+        let len = ::std::cmp::min( buf.len(), self.data.len() - self.pos );
+        buf[..len].copy_from_slice( &self.data[self.pos..self.pos+len] );
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+
+fn inflate( block: &[u8] ) -> io::Result<Vec<u8>> {
+    let (cdata, trailer) = block.split_at( block.len() - 8 );
+    let crc   = le_u32( &trailer[..4] );
+    let isize = le_u32( &trailer[4..] ) as usize;
+
+    let mut data = Vec::with_capacity( isize );
+    try!( DeflateDecoder::new( cdata ).read_to_end( &mut data ) );
+
+    let mut check = Crc::new();
+    check.update( &data );
+    if data.len() != isize || check.sum() != crc {
+        return Err(invalid("corrupted BGZF block"));
+    }
+    Ok(data)
+}
+
+
+fn le_u32( b: &[u8] ) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+fn invalid( msg: &str ) -> io::Error {
+    io::Error::new( io::ErrorKind::InvalidData, msg )
+}
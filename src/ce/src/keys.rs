@@ -0,0 +1,171 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Data keys, wrapped to the enclave, see `shared::provision`.
+
+use std::fs;
+use std::io::{Read,Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use rand::{OsRng,Rng};
+
+use shared;
+use shared::provision;
+use shared::kdf;
+use shared::cipher::{self,Cipher};
+
+use compress::{self,Error};
+use digest;
+use attestation;
+
+extern "C" {
+    fn ecdh_p256(peer: *const u8, eph_pub: *mut u8, secret: *mut u8) -> i32;
+}
+
+
+// Read the public keys of an enclave. The attestation report of its quote,
+// signed by the IAS under `ias_root` (see `attestation`), must carry the
+// expected MRENCLAVE and the hashes of the keys. Debug enclaves, whose memory
+// the host can read, are refused unless `debug`.
+pub fn read_enclave( path:       &Path,
+                     mr_enclave: &[u8;32],
+                     report:     &Path,
+                     ias_root:   &Path,
+                     debug:      bool,
+                     test:       bool ) -> Result<provision::EnclaveKey,Error> {
+    let mut key = provision::EnclaveKey::zeroed();
+    try!( try!( fs::File::open( path ) ).read_exact( shared::as_u8_slice_mut( &mut key ) ) );
+    try!( key.check() );
+    let json = try!( attestation::read_report( report, ias_root ) );
+    let report = try!( shared::attestation::Report::parse( &json ) );
+    let mut hashes = [0u8;64];
+    hashes[..32].copy_from_slice( &digest::sha256( key.public() ) );
+    hashes[32..].copy_from_slice( &digest::sha256( key.signing() ) );
+    try!( key.verify( &report, mr_enclave, &hashes, debug, test ) );
+    Ok(key)
+}
+
+
+// Data key of a dataset, its ID and its wrapped form for the .ce file headers.
+pub struct DataKey {
+    key:     [u8;kdf::DATA_KEY_SIZE],
+    id:      [u8;kdf::KEY_ID_SIZE],
+    wrapped: provision::WrappedKey,
+}
+
+impl DataKey {
+    pub fn wrap( key: [u8;kdf::DATA_KEY_SIZE], enclave: &[u8;provision::PUBLIC_KEY_SIZE] ) -> Result<DataKey,Error> {
+        let mut public = [0u8;provision::PUBLIC_KEY_SIZE];
+        let mut secret = [0u8;provision::SECRET_SIZE];
+        if unsafe { ecdh_p256( enclave.as_ptr(), public.as_mut_ptr(), secret.as_mut_ptr() ) } != 0 {
+            return Err(Error::UntrustedEnclave);
+        }
+        let kek = digest::sha256( &provision::kek_input( &secret, &public, enclave ) );
+
+        let mut iv = [0u8;12];
+        try!( OsRng::new() ).fill_bytes( &mut iv );
+        let mut wrapped = key;
+        let mut mac = [0u8;16];
+        compress::encrypt( Cipher::Aes256Gcm, &kek, &mut wrapped, &iv, &[], &mut mac );
+        Ok( DataKey { key,
+                      id:      kdf::key_id( &digest::sha256, &key ),
+                      wrapped: provision::WrappedKey::new( &public, iv, wrapped, mac ) } )
+    }
+
+    pub fn id(&self) -> &[u8;kdf::KEY_ID_SIZE] {
+        &self.id
+    }
+
+    pub fn file_key(&self, file_id: &[u8;16], cipher: Cipher) -> [u8;cipher::KEY_SIZE_MAX] {
+        file_key( &self.key, file_id, cipher )
+    }
+
+    pub fn wrapped(&self) -> &provision::WrappedKey {
+        &self.wrapped
+    }
+}
+
+
+pub fn new_key() -> Result<[u8;kdf::DATA_KEY_SIZE],Error> {
+    let mut key = [0u8;kdf::DATA_KEY_SIZE];
+    try!( OsRng::new() ).fill_bytes( &mut key );
+    Ok(key)
+}
+
+
+// Key files hold the key in hex, they are only readable by their owner.
+// Several datasets may share one.
+pub fn read_key( path: &Path ) -> Result<[u8;kdf::DATA_KEY_SIZE],Error> {
+    let mut text = String::new();
+    try!( try!( fs::File::open( path ) ).read_to_string( &mut text ) );
+    let mut key = [0u8;kdf::DATA_KEY_SIZE];
+    try!( parse_hex( text.trim(), &mut key ) );
+    Ok(key)
+}
+
+pub fn key_id( key: &[u8;kdf::DATA_KEY_SIZE] ) -> [u8;kdf::KEY_ID_SIZE] {
+    kdf::key_id( &digest::sha256, key )
+}
+
+// Key of the .ce file with this ID and cipher.
+pub fn file_key( key: &[u8;kdf::DATA_KEY_SIZE], file_id: &[u8;16], cipher: Cipher ) -> [u8;cipher::KEY_SIZE_MAX] {
+    kdf::file_key( &digest::sha256, key, file_id, cipher )
+}
+
+// Write a new key, an existing file is left as it is.
+pub fn create_key( path: &Path ) -> Result<[u8;kdf::DATA_KEY_SIZE],Error> {
+    let key = try!( new_key() );
+    try!( write_key( path, &key ) );
+    Ok(key)
+}
+
+pub fn write_key( path: &Path, key: &[u8;kdf::DATA_KEY_SIZE] ) -> Result<(),Error> {
+    let mut file = try!( fs::OpenOptions::new()
+                         .write(true)
+                         .create_new(true)
+                         .mode(0o600)
+                         .open( path ) );
+    try!( writeln!( file, "{}", hex( key ) ) );
+    Ok(())
+}
+
+
+pub fn hex( bytes: &[u8] ) -> String {
+    let text: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b) ).collect();
+    text.concat()
+}
+
+
+pub fn parse_hex( s: &str, out: &mut [u8] ) -> Result<(),Error> {
+    if s.len() != 2*out.len() {
+        return Err(Error::InvalidKey);
+    }
+    let mut digits = s.chars().map(|c| c.to_digit( 16 ) );
+    for b in out.iter_mut() {
+        match (digits.next(), digits.next()) {
+            (Some(Some(h)), Some(Some(l))) => *b = (h << 4 | l) as u8,
+            _                              => return Err(Error::InvalidKey),
+        }
+    }
+    Ok(())
+}
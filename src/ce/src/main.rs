@@ -20,28 +20,44 @@
 
 
 extern crate clap;
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches};
 
 
 extern crate walkdir;
-use walkdir::{WalkDir, WalkDirIterator};
+use walkdir::WalkDir;
 
 extern crate rayon;
 use rayon::prelude::*;
 
 extern crate openssl_sys;
 
+extern crate flate2;
+
 extern crate rand;
 
 extern crate shared;
+use shared::sheet::Phenotype;
+use shared::cipher::Cipher;
 
-use std::path::PathBuf;
-use std::io::Write;
+use std::path::{Path,PathBuf};
+use std::io::{Read,Write};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Instant};
 use std::fs;
+use std::cmp;
+use std::collections::HashSet;
 
 mod compress;
+mod digest;
+mod sort;
+mod input;
+mod keys;
+mod attestation;
+mod results;
+
+// Output files open at once when splitting a multi-sample VCF, the input is
+// read once per batch.
+const SAMPLES_PER_PASS: usize = 256;
 
 fn main() {
     let start = Instant::now();
@@ -56,14 +72,166 @@ fn main() {
              .short("C")
              .long("control")
              .value_name("DIR")
-             .required(true)
+             .required_unless_one(&["sheet", "keygen", "rekey", "result_keygen", "decrypt", "verify", "audit"])
              .takes_value(true))
         .arg(Arg::with_name("case")
              .help("Case .vcf directory")
              .short("c")
              .long("case")
              .value_name("DIR")
-             .required(true)
+             .required_unless_one(&["sheet", "keygen", "rekey", "result_keygen", "decrypt", "verify", "audit"])
+             .takes_value(true))
+        .arg(Arg::with_name("sheet")
+             .help("Sample sheet, .ce files are written to the output directory")
+             .short("s")
+             .long("sheet")
+             .value_name("FILE")
+             .required(false)
+             .conflicts_with_all(&["case", "control"])
+             .takes_value(true))
+        .arg(Arg::with_name("relabel")
+             .help("Only change the phenotypes of the .ce files of the sample sheet")
+             .short("r")
+             .long("relabel")
+             .required(false)
+             .requires_all(&["sheet", "key"])
+             .takes_value(false))
+        .arg(Arg::with_name("enclave")
+             .help("Public key of the enclave, see app --keygen")
+             .short("e")
+             .long("enclave")
+             .value_name("FILE")
+             .required(false)
+             .requires_all(&["mrenclave", "attestation", "ias_root"])
+             .conflicts_with_all(&["relabel", "keygen", "result_keygen", "decrypt"])
+             .takes_value(true))
+        .arg(Arg::with_name("mrenclave")
+             .help("Expected MRENCLAVE of the enclave, in hex")
+             .short("m")
+             .long("mrenclave")
+             .value_name("HEX")
+             .required(false)
+             .requires("enclave")
+             .takes_value(true))
+        .arg(Arg::with_name("attestation")
+             .help("Attestation report of the enclave key, the response of the attestation service as curl -i saves it")
+             .short("q")
+             .long("attestation")
+             .value_name("FILE")
+             .required(false)
+             .requires("enclave")
+             .takes_value(true))
+        .arg(Arg::with_name("ias_root")
+             .help("Root certificate of the attestation service, in PEM, or the test root of --test_attestation")
+             .long("ias_root")
+             .value_name("FILE")
+             .required(false)
+             .requires("enclave")
+             .takes_value(true))
+        .arg(Arg::with_name("debug_enclave")
+             .help("Trust the enclave key even if the enclave is a debug one, for tests only")
+             .long("debug_enclave")
+             .required(false)
+             .requires("enclave")
+             .takes_value(false))
+        .arg(Arg::with_name("test_attestation")
+             .help("Trust the test reports of app --keygen --test_attestation, as signed by test/attest.sh, for tests and SGX_MODE=SIM only")
+             .long("test_attestation")
+             .required(false)
+             .requires("enclave")
+             .takes_value(false))
+        .arg(Arg::with_name("key")
+             .help("Data key of the dataset, created if missing, needed to relabel")
+             .short("k")
+             .long("key")
+             .value_name("FILE")
+             .required(false)
+             .takes_value(true))
+        .arg(Arg::with_name("keygen")
+             .help("Only write a new data key to the key file")
+             .short("g")
+             .long("keygen")
+             .required(false)
+             .requires("key")
+             .conflicts_with_all(&["case", "control", "sheet"])
+             .takes_value(false))
+        .arg(Arg::with_name("rekey")
+             .help("Only encrypt the .ce files of a directory again, under a new data key")
+             .short("R")
+             .long("rekey")
+             .value_name("DIR")
+             .required(false)
+             .requires("key")
+             .conflicts_with_all(&["case", "control", "sheet", "keygen"])
+             .takes_value(true))
+        .arg(Arg::with_name("new_key")
+             .help("New data key of the files to rekey, created if missing")
+             .short("n")
+             .long("new_key")
+             .value_name("FILE")
+             .required(false)
+             .requires("rekey")
+             .takes_value(true))
+        .arg(Arg::with_name("result_keygen")
+             .help("Only write a new keypair to receive the results of app, its public key goes to FILE.pub")
+             .short("G")
+             .long("result_keygen")
+             .value_name("FILE")
+             .required(false)
+             .conflicts_with_all(&["case", "control", "sheet", "keygen", "rekey"])
+             .takes_value(true))
+        .arg(Arg::with_name("decrypt")
+             .help("Only decrypt results files of app with the keypair of --result_key")
+             .short("D")
+             .long("decrypt")
+             .value_name("FILE")
+             .multiple(true)
+             .required(false)
+             .requires("result_key")
+             .conflicts_with_all(&["case", "control", "sheet", "keygen", "rekey", "result_keygen"])
+             .takes_value(true))
+        .arg(Arg::with_name("result_key")
+             .help("Keypair of the researcher, see --result_keygen")
+             .short("K")
+             .long("result_key")
+             .value_name("FILE")
+             .required(false)
+             .requires("decrypt")
+             .takes_value(true))
+        .arg(Arg::with_name("verify")
+             .help("Only check the results signed by the enclave, see app, against the .ce files of --inputs")
+             .short("v")
+             .long("verify")
+             .value_name("FILE")
+             .required(false)
+             .requires("inputs")
+             .conflicts_with_all(&["case", "control", "sheet", "keygen", "rekey", "result_keygen", "decrypt"])
+             .takes_value(true))
+        .arg(Arg::with_name("audit")
+             .help("Only check the audit log of the enclave, see app --audit, and list its analyses")
+             .short("a")
+             .long("audit")
+             .value_name("FILE")
+             .required(false)
+             .conflicts_with_all(&["case", "control", "sheet", "keygen", "rekey", "result_keygen", "decrypt", "verify"])
+             .takes_value(true))
+        .arg(Arg::with_name("inputs")
+             .help("The .ce files the results must come from")
+             .short("i")
+             .long("inputs")
+             .value_name("FILE")
+             .multiple(true)
+             .required(false)
+             .requires("verify")
+             .takes_value(true))
+        .arg(Arg::with_name("results")
+             .help("Results files of app, also checked against the signed results")
+             .short("f")
+             .long("results")
+             .value_name("FILE")
+             .multiple(true)
+             .required(false)
+             .requires("verify")
              .takes_value(true))
         .arg(Arg::with_name("out_path")
              .help("Output directory")
@@ -73,13 +241,198 @@ fn main() {
              .required(false)
              .default_value("")
              .takes_value(true))          
+        .arg(Arg::with_name("build")
+             .help("Reference build of inputs without ##contig lines")
+             .short("b")
+             .long("build")
+             .value_name("STR")
+             .required(false)
+             .possible_values(&["GRCh37", "GRCh38"])
+             .default_value("GRCh37")
+             .takes_value(true))
+        .arg(Arg::with_name("duplicates")
+             .help("Records of an already seen site")
+             .short("d")
+             .long("duplicates")
+             .value_name("STR")
+             .required(false)
+             .possible_values(&["reject", "collapse"])
+             .default_value("reject")
+             .takes_value(true))
+        .arg(Arg::with_name("padding")
+             .help("Pad each .ce file with dummy keys to this number of blocks, or to the next power of two")
+             .short("p")
+             .long("padding")
+             .value_name("STR")
+             .required(false)
+             .default_value("none")
+             .validator(|p| p.parse::<compress::Padding>().map(|_| () ).map_err(|e| e.to_string() ) )
+             .takes_value(true))
+        .arg(Arg::with_name("cipher")
+             .help("Cipher of the .ce files, aes256-gcm by default, --rekey keeps the one of each file if not given")
+             .short("x")
+             .long("cipher")
+             .value_name("STR")
+             .required(false)
+             .conflicts_with("relabel")
+             .possible_values(&["aes128-gcm", "aes256-gcm", "chacha20-poly1305"])
+             .takes_value(true))
+        .arg(Arg::with_name("no_allele_freq")
+             .help("Usage policy: the allele frequencies of the .ce files may not be released")
+             .long("no_allele_freq")
+             .required(false)
+             .conflicts_with("relabel")
+             .takes_value(false))
+        .arg(Arg::with_name("max_k")
+             .help("Usage policy: count of top SNPs the .ce files may be analysed for at most")
+             .long("max_k")
+             .value_name("INT")
+             .required(false)
+             .conflicts_with("relabel")
+             .takes_value(true))
+        .arg(Arg::with_name("expiry")
+             .help("Usage policy: last day the .ce files may be analysed, as YYYY-MM-DD, advisory where the audit log has host time only")
+             .long("expiry")
+             .value_name("DATE")
+             .required(false)
+             .conflicts_with("relabel")
+             .takes_value(true))
+        .arg(Arg::with_name("study")
+             .help("Usage policy: study allowed to analyse the .ce files, any without, up to 4")
+             .long("study")
+             .value_name("ID")
+             .required(false)
+             .multiple(true)
+             .number_of_values(1)
+             .conflicts_with("relabel")
+             .takes_value(true))
         .get_matches();
 
-    // This is safe because args control and case are required.
-    let (control,case) = (matches.value_of("control").unwrap(), matches.value_of("case").unwrap());
-
     let out_path = matches.value_of("out_path").unwrap();
 
+    // This is safe because build has a default value among the possible ones.
+    let build: shared::contig::Build = matches.value_of("build").unwrap().parse().unwrap();
+    let duplicates: compress::Duplicates = matches.value_of("duplicates").unwrap().parse().unwrap();
+    // This is safe because padding has a default value and is validated.
+    let padding: compress::Padding = matches.value_of("padding").unwrap().parse().unwrap();
+    // This is safe because of the possible values of cipher.
+    let cipher: Option<Cipher> = matches.value_of("cipher").map(|c| c.parse().unwrap() );
+    // Sealed in the header of the .ce files written.
+    let usage = {
+        let max_k = matches.value_of("max_k").map_or( 0, |k| match k.parse::<u32>() {
+            Ok(k) if k > 0 => k,
+            _              => panic!("--max_k: invalid value {}", k),
+        });
+        let expiry = matches.value_of("expiry").map(|d| match shared::policy::parse_date( d ) {
+            Ok(days) => days,
+            Err(why) => panic!("--expiry {}: {}", d, why),
+        });
+        let studies: Vec<&str> = matches.values_of("study").map_or( Vec::new(), |v| v.collect() );
+        match shared::policy::Usage::new( !matches.is_present("no_allele_freq"), max_k, expiry, &studies ) {
+            Ok(usage) => usage,
+            Err(why)  => panic!("--study: {}", why),
+        }
+    };
+
+    if matches.is_present("keygen") {
+        // This is safe because keygen requires a key.
+        let key_path = matches.value_of("key").unwrap();
+        match keys::create_key( Path::new( key_path ) ) {
+            Ok(key)  => println!("Data key {} written to {}", keys::hex( &keys::key_id( &key ) ), key_path ),
+            Err(why) => panic!("{}: {}", key_path, why),
+        }
+        return
+    }
+
+    if let Some(path) = matches.value_of("result_keygen") {
+        match results::create_keypair( Path::new( path ) ) {
+            Ok(public_path) => println!("Keypair written to {}, public key to {}", path, public_path.display() ),
+            Err(why)        => panic!("{}: {}", path, why),
+        }
+        return
+    }
+
+    if let Some(paths) = matches.values_of("decrypt") {
+        // This is safe because decrypt requires the keypair.
+        let pair_path = matches.value_of("result_key").unwrap();
+        let pair = match results::read_keypair( Path::new( pair_path ) ) {
+            Ok(pair) => pair,
+            Err(why) => panic!("{}: {}", pair_path, why),
+        };
+        for path in paths {
+            match results::decrypt( Path::new( path ), &pair ) {
+                Ok(out_path) => println!("{} decrypted to {}", path, out_path.display() ),
+                Err(why)     => panic!("{}: {}", path, why),
+            }
+        }
+        return
+    }
+
+    if let Some(path) = matches.value_of("verify") {
+        // This is safe because verify requires inputs.
+        let enclave = enclave( &matches );
+        let inputs: Vec<&Path> = matches.values_of("inputs").unwrap().map( Path::new ).collect();
+        let files: Vec<&Path> = matches.values_of("results").map_or( Vec::new(), |v| v.map( Path::new ).collect() );
+        match results::verify( Path::new( path ), &enclave, &inputs, &files ) {
+            Ok(statement) => {
+                println!("{}: signed by the enclave", path );
+                println!("{} .ce files, {} keys per block, {} keys per iteration, top {} SNPs{}, {} aggregation",
+                         statement.nb_files(), statement.keys_per_block(), statement.nb_keys(), statement.snp_cnt(),
+                         if statement.output_allele_freq() { ", allele frequencies" } else { "" },
+                         statement.aggregation() );
+                println!("Policy: {}", statement.policy() );
+                println!("Study {}, date {}", shared::policy::Study( statement.study() ), shared::policy::Date( statement.date() ) );
+                println!("Results encrypted to {}", keys::hex( statement.recipient() ) );
+                println!("{} results files checked", files.len() );
+            },
+            Err(why) => panic!("{}: {}", path, why),
+        }
+        return
+    }
+
+    if let Some(path) = matches.value_of("audit") {
+        let enclave = enclave( &matches );
+        match results::verify_audit( Path::new( path ), &enclave ) {
+            Ok(records) => {
+                for record in records.iter() {
+                    let statement = record.statement();
+                    let time = record.timestamp();
+                    // Host time: not bound to the trusted time, see `shared::audit`.
+                    println!("{}\t{} {:02}:{:02}:{:02}{}\tstudy {}\t{} .ce files\ttop {} SNPs{}\tinputs {}\tresults {}",
+                             record.seq(), shared::policy::Date( (time / 86400) as u32 ),
+                             time / 3600 % 24, time / 60 % 60, time % 60,
+                             if record.flags() & shared::audit::TIME_RESET != 0 { " (time reset)" }
+                             else if record.host_time() { " (host time)" } else { "" },
+                             shared::policy::Study( statement.study() ), statement.nb_files(), statement.snp_cnt(),
+                             if statement.output_allele_freq() { ", allele frequencies" } else { "" },
+                             keys::hex( statement.inputs() ), keys::hex( statement.chisq() ) );
+                }
+                println!("{}: {} records signed by the enclave and chained", path, records.len() );
+            },
+            Err(why) => panic!("{}: {}", path, why),
+        }
+        return
+    }
+
+    if let Some(dir) = matches.value_of("rekey") {
+        // This is safe because rekey requires the old key.
+        let key_path = matches.value_of("key").unwrap();
+        let old_key = match keys::read_key( Path::new( key_path ) ) {
+            Ok(key)  => key,
+            Err(why) => panic!("{}: {}", key_path, why),
+        };
+        let data_key = data_key( &matches, matches.value_of("new_key") );
+        rekey( dir, &old_key, &data_key, cipher );
+        return
+    }
+
+    // Without a key file each run has its own data key.
+    let data_key = if matches.is_present("relabel") {
+        None
+    } else {
+        Some( data_key( &matches, matches.value_of("key") ) )
+    };
+
     let (out_path_cont, out_path_case) = {
         if out_path.len() == 0 {
             (PathBuf::from("out/control"), PathBuf::from("out/case"))
@@ -92,54 +445,86 @@ fn main() {
         }
     };
 
-    // Found control files.
-    let mut controls: Vec<_> = WalkDir::new( control )
-        .min_depth( 1 )
-        .follow_links( true )
-        .into_iter()
-        .filter_entry(|e| e.path().extension().unwrap() == "vcf")
-        .map( |e| {
-            let path = e.unwrap().path().to_path_buf();
-            let mut to_path = out_path_cont.clone();
-            to_path.push( &path.file_stem().unwrap() );
-            to_path.set_extension( "ce" );
-            let _ = fs::remove_file( &to_path );
-            (path, to_path)
-        }).collect();
-    println!("Control path {}: found {} vcf files", control, controls.len() );
-
-    // Found case files.
-    let mut cases: Vec<_> = WalkDir::new( case )
-        .min_depth(1)
-        .follow_links( true )
-        .into_iter()
-        .filter_entry(|e| e.path().extension().unwrap() == "vcf")
-        .map( |e| {
-            let path = e.unwrap().path().to_path_buf();
-            let mut to_path = out_path_case.clone();
-            to_path.push( &path.file_stem().unwrap() );
-            to_path.set_extension( "ce" );
-            let _ = fs::remove_file( &to_path );
-            (path, to_path)
-        }).collect();
-    println!("Case path {}: found {} vcf files", case, cases.len() );
-
-    println!("Compressed and encrypted files output path: ({},{})",
-             out_path_cont.to_str().unwrap(), out_path_case.to_str().unwrap());
-
-    fs::create_dir_all(&out_path_cont).unwrap();
-    fs::create_dir_all(&out_path_case).unwrap();
+    // The sample sheet is already checked, inputs of a directory are named
+    // after their file.
+    let target = |out_path: &PathBuf, sample: String, phenotype: Phenotype| {
+        if !shared::sheet::is_file_name( &sample ) {
+            panic!("{}: {}", sample, shared::Error::InvalidSample);
+        }
+        let mut to_path = out_path.clone();
+        to_path.push( format!("{}.ce", sample) );
+        let _ = fs::remove_file( &to_path );
+        compress::Target { sample, phenotype, out_path: to_path }
+    };
+
+    // Inputs with the samples to take from each.
+    let mut inputs: Vec<(PathBuf, Vec<compress::Target>)> = Vec::new();
+
+    for &(dir, out_path, phenotype) in [(matches.value_of("control"), &out_path_cont, Phenotype::Control),
+                                        (matches.value_of("case"), &out_path_case, Phenotype::Case)].iter() {
+        if let Some(dir) = dir {
+            // Found files.
+            let before = inputs.len();
+            inputs.extend( WalkDir::new( dir )
+                .min_depth( 1 )
+                .follow_links( true )
+                .into_iter()
+                .filter_map(|e| e.ok() )
+                .filter_map(|e| input::vcf_stem( e.path() ).map(|stem| (e.path().to_path_buf(), stem) ) )
+                .map( |(path, sample)| (path, vec![target( out_path, sample, phenotype )]) ) );
+            println!("{:?} path {}: found {} vcf (.gz) files", phenotype, dir, inputs.len() - before );
+        }
+    }
+
+    if let Some(sheet) = matches.value_of("sheet") {
+        let out_path = if out_path.is_empty() { PathBuf::from("out") } else { PathBuf::from(out_path) };
+        let files = match read_sheet( Path::new( sheet ) ) {
+            Ok(files) => files,
+            Err(why)  => panic!("{}: {}", sheet, why),
+        };
+        println!("Sample sheet {}: {} samples in {} vcf (.gz) files", sheet,
+                 files.iter().fold( 0, |n, f| n + f.1.len() ), files.len() );
+        if matches.is_present("relabel") {
+            // This is safe because relabel requires a key.
+            let key_path = matches.value_of("key").unwrap();
+            let key = match keys::read_key( Path::new( key_path ) ) {
+                Ok(key)  => key,
+                Err(why) => panic!("{}: {}", key_path, why),
+            };
+            relabel( &out_path, &key, files );
+            return
+        }
+        for (path, samples) in files {
+            // Multi-sample files are read once per batch of samples.
+            let mut targets: Vec<_> = samples.into_iter()
+                .map(|(sample, phenotype)| target( &out_path, sample, phenotype ) ).collect();
+            while !targets.is_empty() {
+                let rest = targets.split_off( cmp::min( targets.len(), SAMPLES_PER_PASS ) );
+                inputs.push( (path.clone(), targets) );
+                targets = rest;
+            }
+        }
+        println!("Compressed and encrypted files output path: {}", out_path.to_str().unwrap());
+        fs::create_dir_all(&out_path).unwrap();
+    } else {
+        println!("Compressed and encrypted files output path: ({},{})",
+                 out_path_cont.to_str().unwrap(), out_path_case.to_str().unwrap());
+        fs::create_dir_all(&out_path_cont).unwrap();
+        fs::create_dir_all(&out_path_case).unwrap();
+    }
 
     // Compress and encrypt all files using parallele iterator.
+    let data_key = data_key.unwrap();
+    println!("Usage policy: {}", usage );
     rayon::initialize(rayon::Configuration::new().num_threads(4)).unwrap();
-    let nb = AtomicUsize::new( 1 );
-    controls.par_iter_mut().chain( cases.par_iter_mut() )
+    let nb = AtomicUsize::new( 0 );
+    inputs.par_iter_mut()
         .for_each(|e| {
-            print!("{:8}\r", nb.fetch_add( 1, Ordering::Relaxed ) );
+            print!("{:8}\r", nb.fetch_add( e.1.len(), Ordering::Relaxed ) + e.1.len() );
             std::io::stdout().flush().unwrap();
             
-            if let Err(why) = compress::compress(&e.0, &e.1, shared::KEYS_PER_BLOCK_DEFAULT) {
-                panic!("{}", why);
+            if let Err(why) = compress::compress(&e.0, &e.1, &data_key, shared::KEYS_PER_BLOCK_DEFAULT, build, cipher.unwrap_or( shared::cipher::DEFAULT ), duplicates, padding, &usage) {
+                panic!("{}: {}", e.0.display(), why);
             }
         });
 
@@ -147,3 +532,109 @@ fn main() {
     let secs = dur.as_secs();
     println!("\nexecution time: {}m{:02}.{:03}", (secs/60), (secs%60), dur.subsec_nanos()/1_000_000 );
 }
+
+
+// Keys of the enclave, with the expected MRENCLAVE, once attested.
+fn enclave( matches: &ArgMatches ) -> shared::provision::EnclaveKey {
+    if !matches.is_present("enclave") {
+        panic!("--enclave, --mrenclave, --attestation and --ias_root are required to trust the enclave");
+    }
+    // This is safe because the enclave args are required together.
+    let enclave_path = matches.value_of("enclave").unwrap();
+    let mr_enclave = matches.value_of("mrenclave").unwrap();
+    let report = matches.value_of("attestation").unwrap();
+    let ias_root = matches.value_of("ias_root").unwrap();
+    let mut expected = [0u8;32];
+    if let Err(why) = keys::parse_hex( mr_enclave, &mut expected ) {
+        panic!("MRENCLAVE {}: {}", mr_enclave, why);
+    }
+    match keys::read_enclave( Path::new( enclave_path ), &expected, Path::new( report ), Path::new( ias_root ),
+                              matches.is_present("debug_enclave"), matches.is_present("test_attestation") ) {
+        Ok(enclave) => enclave,
+        Err(why)    => panic!("{}: {}", enclave_path, why),
+    }
+}
+
+
+// Data key of the dataset wrapped to the enclave, taken from the key file if
+// there is one.
+fn data_key( matches: &ArgMatches, key_path: Option<&str> ) -> keys::DataKey {
+    let enclave = enclave( matches );
+    let key = match key_path {
+        Some(key_path) if Path::new( key_path ).exists() => keys::read_key( Path::new( key_path ) ),
+        Some(key_path) => keys::create_key( Path::new( key_path ) ),
+        None           => keys::new_key(),
+    };
+    let key = match key {
+        Ok(key)  => key,
+        Err(why) => panic!("{}: {}", key_path.unwrap_or("data key"), why),
+    };
+    let data_key = match keys::DataKey::wrap( key, enclave.public() ) {
+        Ok(data_key) => data_key,
+        Err(why)     => panic!("{}: {}", matches.value_of("enclave").unwrap(), why),
+    };
+    println!("Data key {}", keys::hex( data_key.id() ) );
+    data_key
+}
+
+
+// Encrypt the .ce files of a directory again, in place.
+fn rekey( dir: &str, old_key: &[u8;shared::kdf::DATA_KEY_SIZE], data_key: &keys::DataKey, cipher: Option<Cipher> ) {
+    let files: Vec<PathBuf> = WalkDir::new( dir )
+        .min_depth( 1 )
+        .follow_links( true )
+        .into_iter()
+        .filter_map(|e| e.ok() )
+        .map(|e| e.path().to_path_buf() )
+        .filter(|p| p.extension().and_then(|e| e.to_str() ) == Some("ce") )
+        .collect();
+    println!("Rekey path {}: found {} .ce files", dir, files.len() );
+    files.par_iter()
+        .for_each(|path| {
+            if let Err(why) = compress::rekey( path, old_key, data_key, cipher ) {
+                panic!("{}: {}", path.display(), why);
+            }
+        });
+}
+
+
+// Change the phenotypes of the .ce files of a sheet.
+fn relabel( out_path: &Path, key: &[u8;shared::kdf::DATA_KEY_SIZE], files: SheetFiles ) {
+    for (sample, phenotype) in files.into_iter().flat_map(|f| f.1 ) {
+        let path = out_path.join( format!("{}.ce", sample) );
+        if let Err(why) = compress::relabel( &path, key, phenotype ) {
+            panic!("{}: {}", path.display(), why);
+        }
+    }
+}
+
+
+// Samples of a sheet with their phenotype, by input file.
+type SheetFiles = Vec<(PathBuf,Vec<(String,Phenotype)>)>;
+
+// Group the samples of a sheet by input file, excluded ones included.
+// Relative paths are taken from the directory of the sheet.
+fn read_sheet( path: &Path ) -> Result<SheetFiles,compress::Error> {
+    let mut text = String::new();
+    try!( try!( fs::File::open( path ) ).read_to_string( &mut text ) );
+    let dir = path.parent().unwrap_or( Path::new("") );
+
+    let mut files: SheetFiles = Vec::new();
+    let mut seen = HashSet::new();
+    for entry in try!( shared::sheet::entries( &text ) ) {
+        let entry = try!( entry );
+        let file = match entry.file {
+            Some(file) => dir.join( file ),
+            None       => return Err(compress::Error::Shared( shared::Error::InvalidSheet )),
+        };
+        if !seen.insert( entry.sample.to_string() ) {
+            return Err(compress::Error::Duplicate);
+        }
+        let sample = (entry.sample.to_string(), entry.phenotype);
+        match files.iter().position(|f| f.0 == file ) {
+            Some(i) => files[i].1.push( sample ),
+            None    => files.push( (file, vec![sample]) ),
+        }
+    }
+    Ok(files)
+}
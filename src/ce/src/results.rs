@@ -0,0 +1,218 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Results of `app`, encrypted by the enclave to the researcher (see
+// `shared::results`), their signature by the enclave (see
+// `shared::signature`) and its audit log (see `shared::audit`).
+
+use std::fs;
+use std::io::{Read,Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path,PathBuf};
+
+use shared;
+use shared::provision::{EnclaveKey,PUBLIC_KEY_SIZE,SECRET_SIZE};
+use shared::results::{self,Output,ResultHeader,RECORD_SIZE,RECORD_DISK_SIZE};
+use shared::signature::{self,SignedResults,SIGNED_SIZE};
+use shared::audit::{AuditRecord,AUDIT_SIZE};
+use shared::cipher::Cipher;
+
+use compress::{self,Error};
+use digest;
+use keys;
+
+extern "C" {
+    fn p256_keygen(private: *mut u8, public: *mut u8) -> i32;
+    fn ecdh_p256_priv(private: *const u8, peer: *const u8, secret: *mut u8) -> i32;
+    fn ecdsa_p256_verify(public: *const u8, hash: *const u8, signature: *const u8) -> i32;
+}
+
+
+// The private key then the public key.
+pub const KEYPAIR_SIZE: usize = 32 + PUBLIC_KEY_SIZE;
+
+
+// Write a new keypair in hex to `path`, only readable by its owner, and its
+// public key to `path`.pub for `app --recipient`.
+pub fn create_keypair( path: &Path ) -> Result<PathBuf,Error> {
+    let mut private = [0u8;32];
+    let mut public = [0u8;PUBLIC_KEY_SIZE];
+    if unsafe { p256_keygen( private.as_mut_ptr(), public.as_mut_ptr() ) } != 0 {
+        return Err(Error::InvalidKey);
+    }
+    let mut public_path = path.as_os_str().to_os_string();
+    public_path.push( ".pub" );
+    let public_path = PathBuf::from( public_path );
+
+    let mut file = try!( fs::OpenOptions::new()
+                         .write(true)
+                         .create_new(true)
+                         .mode(0o600)
+                         .open( path ) );
+    try!( writeln!( file, "{}{}", keys::hex( &private ), keys::hex( &public ) ) );
+    let mut file = try!( fs::File::create( &public_path ) );
+    try!( writeln!( file, "{}", keys::hex( &public ) ) );
+    Ok(public_path)
+}
+
+pub fn read_keypair( path: &Path ) -> Result<[u8;KEYPAIR_SIZE],Error> {
+    let mut text = String::new();
+    try!( try!( fs::File::open( path ) ).read_to_string( &mut text ) );
+    let mut pair = [0u8;KEYPAIR_SIZE];
+    try!( keys::parse_hex( text.trim(), &mut pair ) );
+    Ok(pair)
+}
+
+
+// Decrypt a results file next to it, without its .enc extension. Nothing is
+// written unless all its records are authentic.
+pub fn decrypt( path: &Path, pair: &[u8;KEYPAIR_SIZE] ) -> Result<PathBuf,Error> {
+    let mut data = Vec::new();
+    try!( try!( fs::File::open( path ) ).read_to_end( &mut data ) );
+    if data.len() < results::RESULT_HEADER_SIZE {
+        return Err(Error::Shared( shared::Error::InvalidResults ));
+    }
+    let mut header = ResultHeader::zeroed();
+    shared::as_u8_slice_mut( &mut header ).copy_from_slice( &data[..results::RESULT_HEADER_SIZE] );
+    try!( header.check() );
+    if header.recipient()[..] != pair[32..] {
+        return Err(Error::UnknownKey);
+    }
+
+    let mut secret = [0u8;SECRET_SIZE];
+    if unsafe { ecdh_p256_priv( pair.as_ptr(), header.ephemeral().as_ptr(), secret.as_mut_ptr() ) } != 0 {
+        return Err(Error::Shared( shared::Error::InvalidResults ));
+    }
+    let key = digest::sha256( &results::key_input( &secret, header.ephemeral(), header.recipient() )[..] );
+
+    // The last record is flagged, a file cut after any record is rejected.
+    let records = &data[results::RESULT_HEADER_SIZE..];
+    if records.is_empty() || records.len() % RECORD_DISK_SIZE != 0 {
+        return Err(Error::InvalidBlock);
+    }
+    let nb_records = records.len() / RECORD_DISK_SIZE;
+    let mut text = Vec::with_capacity( records.len() );
+    for (nb, record) in records.chunks( RECORD_DISK_SIZE ).enumerate() {
+        let mut mac = [0u8;16];
+        mac.copy_from_slice( &record[..16] );
+        let mut rec = [0u8;RECORD_SIZE];
+        rec.copy_from_slice( &record[16..] );
+        let aad = results::record_aad( &header, nb as u64, nb + 1 == nb_records );
+        if !compress::decrypt( Cipher::Aes128Gcm, &key[..16], &mut rec, &results::record_iv( nb as u64 ), &aad[..], &mac ) {
+            return Err(Error::InvalidBlock);
+        }
+        text.extend_from_slice( try!( results::record_data( &rec ) ) );
+    }
+
+    let out_path = if path.extension().and_then(|e| e.to_str() ) == Some("enc") {
+        path.with_extension( "" )
+    } else {
+        let mut out_path = path.as_os_str().to_os_string();
+        out_path.push( ".dec" );
+        PathBuf::from( out_path )
+    };
+    try!( try!( fs::File::create( &out_path ) ).write_all( &text ) );
+    Ok(out_path)
+}
+
+
+// Check the results signed by an enclave, its key being already checked (see
+// `keys::read_enclave`): the signature, that the .ce files read are `inputs`
+// and that `files` are the results files as written.
+pub fn verify( path: &Path, enclave: &EnclaveKey, inputs: &[&Path], files: &[&Path] ) -> Result<SignedResults,Error> {
+    let mut data = Vec::new();
+    try!( try!( fs::File::open( path ) ).read_to_end( &mut data ) );
+    if data.len() != SIGNED_SIZE {
+        return Err(Error::Shared( shared::Error::InvalidSignedResults ));
+    }
+    let mut statement = SignedResults::zeroed();
+    shared::as_u8_slice_mut( &mut statement ).copy_from_slice( &data );
+    try!( statement.check() );
+    let hash = digest::sha256( statement.signed() );
+    if unsafe { ecdsa_p256_verify( enclave.signing().as_ptr(), hash.as_ptr(), statement.signature().as_ptr() ) } != 1 {
+        return Err(Error::InvalidSignature);
+    }
+
+    let mut hashes = Vec::with_capacity( inputs.len() );
+    for input in inputs {
+        hashes.push( try!( digest::sha256_file( input ) ) );
+    }
+    hashes.sort();
+    let mut commitment = digest::Sha256::new();
+    commitment.update( &signature::INPUTS_LABEL );
+    for hash in hashes.iter() {
+        commitment.update( hash );
+    }
+    if statement.nb_files() as usize != inputs.len() || commitment.finish() != *statement.inputs() {
+        return Err(Error::InputsMismatch);
+    }
+
+    for file in files {
+        let mut header = ResultHeader::zeroed();
+        try!( try!( fs::File::open( file ) ).read_exact( shared::as_u8_slice_mut( &mut header ) ) );
+        try!( header.check() );
+        let expected = match header.output() {
+            Output::Chisq      => Some(statement.chisq()),
+            Output::AlleleFreq => statement.allele_freq(),
+        };
+        if expected != Some(&try!( digest::sha256_file( file ) )) {
+            return Err(Error::ResultsMismatch);
+        }
+    }
+    Ok(statement)
+}
+
+
+// Check the audit log of an enclave, its key being already checked: the
+// signature of each record and of its statement, and that each record follows
+// the previous one from the first.
+pub fn verify_audit( path: &Path, enclave: &EnclaveKey ) -> Result<Vec<AuditRecord>,Error> {
+    let mut data = Vec::new();
+    try!( try!( fs::File::open( path ) ).read_to_end( &mut data ) );
+    if data.len() % AUDIT_SIZE != 0 {
+        return Err(Error::Shared( shared::Error::InvalidAuditRecord ));
+    }
+    let mut records: Vec<AuditRecord> = Vec::with_capacity( data.len() / AUDIT_SIZE );
+    let mut hash = [0u8;32];
+    for chunk in data.chunks( AUDIT_SIZE ) {
+        let mut record = AuditRecord::zeroed();
+        shared::as_u8_slice_mut( &mut record ).copy_from_slice( chunk );
+        try!( record.check() );
+        let chained = match records.last() {
+            Some(prev) => record.follows( prev, &hash ),
+            None       => record.seq() == 0 && *record.prev() == [0u8;32] && record.flags() == 0,
+        };
+        if !chained {
+            return Err(Error::AuditChain( records.len() as u64 ));
+        }
+        for &(signed, signature) in [(record.signed(), record.signature()),
+                                     (record.statement().signed(), record.statement().signature())].iter() {
+            let digest = digest::sha256( signed );
+            if unsafe { ecdsa_p256_verify( enclave.signing().as_ptr(), digest.as_ptr(), signature.as_ptr() ) } != 1 {
+                return Err(Error::InvalidSignature);
+            }
+        }
+        hash = digest::sha256( record.as_slice() );
+        records.push( record );
+    }
+    Ok(records)
+}
@@ -0,0 +1,142 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// External merge sort of the data lines of a VCF, used for inputs that are
+// not in site order.
+//
+// The lines are read by runs of about `RUN_SIZE` bytes, each run is sorted
+// in memory and spilled next to the output file, then the runs are merged
+// and handed to the encoder. The sort is stable so collapsed duplicates keep
+// the first record.
+
+use std::io;
+use std::fs;
+use std::path;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{BufRead,Write};
+
+use compress::{self,Contigs,Error};
+use digest;
+use input;
+
+
+const RUN_SIZE: usize = 256 << 20;
+
+
+pub fn sort( inp_path: &path::PathBuf,
+             out_path: &path::Path,
+             contigs:  &mut Contigs,
+             checksum: &mut digest::Sha256,
+             encode:   &mut dyn FnMut(&str, &Contigs) -> Result<bool,Error> ) -> Result<(),Error> {
+    let runs = try!( split( inp_path, out_path, contigs, checksum ) );
+    let res = merge( &runs, contigs, encode );
+    for run in runs.iter() {
+        let _ = fs::remove_file( run );
+    }
+    res
+}
+
+
+fn run_path( out_path: &path::Path, nb: usize ) -> path::PathBuf {
+    let mut path = out_path.to_path_buf();
+    path.set_extension( format!("run{}", nb) );
+    path
+}
+
+
+fn site( line: &str, contigs: &Contigs ) -> Result<(u32,u32),Error> {
+    let (site, _) = try!( compress::parse_site( line, contigs ) );
+    Ok( (site.contig, site.pos) )
+}
+
+
+// Write the sorted runs, returns their paths.
+fn split( inp_path: &path::PathBuf,
+          out_path: &path::Path,
+          contigs:  &mut Contigs,
+          checksum: &mut digest::Sha256 ) -> Result<Vec<path::PathBuf>,Error> {
+    let mut reader = try!( input::open(inp_path) );
+    let mut runs = Vec::new();
+    let mut run: Vec<((u32,u32),String)> = Vec::new();
+    let mut run_size = 0;
+    let mut line = String::with_capacity( 64 );
+
+    loop {
+        let len = try!( reader.read_line( &mut line ) );
+        checksum.update( line.as_bytes() );
+        if (len == 0 || run_size >= RUN_SIZE) && !run.is_empty() {
+            run.sort_by_key(|r| r.0 );
+            let path = run_path( out_path, runs.len() );
+            let mut to = io::BufWriter::new( try!( fs::File::create(&path) ) );
+            runs.push( path );
+            for l in run.iter().map(|r| &r.1 ) {
+                try!( writeln!( to, "{}", l ) );
+            }
+            run.clear();
+            run_size = 0;
+        }
+        if len == 0 {
+            break
+        }
+        {
+            let l = line.trim();
+            if try!( compress::read_header_line( l, contigs ) ) {
+                run.push( (try!( site( l, contigs ) ), l.to_string()) );
+                run_size += l.len();
+            }
+        }
+        line.clear();
+    }
+
+    Ok(runs)
+}
+
+
+// Merge the runs, ties go to the lowest run to keep the input order.
+fn merge( runs:    &[path::PathBuf],
+          contigs: &Contigs,
+          encode:  &mut dyn FnMut(&str, &Contigs) -> Result<bool,Error> ) -> Result<(),Error> {
+    let mut readers = Vec::with_capacity( runs.len() );
+    for run in runs {
+        readers.push( io::BufReader::new( try!( fs::File::open(run) ) ).lines() );
+    }
+
+    let mut heap = BinaryHeap::with_capacity( runs.len() );
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if let Some(l) = reader.next() {
+            let l = try!( l );
+            heap.push( (Reverse( (try!( site( &l, contigs ) ), i) ), l) );
+        }
+    }
+
+    while let Some((Reverse((_, i)), l)) = heap.pop() {
+        // Input order does not matter anymore.
+        let _ = try!( encode( &l, contigs ) );
+        if let Some(next) = readers[i].next() {
+            let next = try!( next );
+            heap.push( (Reverse( (try!( site( &next, contigs ) ), i) ), next) );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,288 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// AES-256-GCM decryption (NIST SP 800-38D) with the AES-NI and PCLMULQDQ
+// instructions, after the Intel white papers on AES-NI and on carry-less
+// multiplication. The CPU is not checked, CPUID cannot be run in an enclave
+// and SGX implies AES-NI. The tests need them on the host too.
+
+use core::arch::x86_64::*;
+
+use tag_eq;
+
+
+pub fn decrypt( key: &[u8;32],
+                src: &[u8],
+                iv:  &[u8],
+                aad: &[u8],
+                mac: &[u8;16],
+                dst: &mut [u8] ) -> bool {
+    unsafe { decrypt_ni( key, src, iv, aad, mac, dst ) }
+}
+
+
+#[target_feature(enable = "aes,pclmulqdq,ssse3")]
+unsafe fn decrypt_ni( key: &[u8;32],
+                      src: &[u8],
+                      iv:  &[u8],
+                      aad: &[u8],
+                      mac: &[u8;16],
+                      dst: &mut [u8] ) -> bool {
+    let keys = expand( key );
+    let h = swap( encrypt_block( &keys, _mm_setzero_si128() ) );
+    let mut ghash = _mm_setzero_si128();
+
+    let mut counter = [0u8;16];
+    counter[..12].copy_from_slice( iv );
+
+    for chunk in aad.chunks( 16 ) {
+        ghash = gfmul( _mm_xor_si128( ghash, swap( load( chunk ) ) ), h );
+    }
+
+    for (i, (s, d)) in src.chunks( 16 ).zip( dst.chunks_mut( 16 ) ).enumerate() {
+        // Read once: what is hashed is what is decrypted.
+        let c = load( s );
+        ghash = gfmul( _mm_xor_si128( ghash, swap( c ) ), h );
+        set_counter( &mut counter, i as u32 + 2 );
+        let p = _mm_xor_si128( c, encrypt_block( &keys, load( &counter ) ) );
+        store( p, d );
+    }
+
+    let mut lengths = [0u8;16];
+    for i in 0..8 {
+        lengths[7-i] = ((aad.len() as u64 * 8) >> (8*i)) as u8;
+        lengths[15-i] = ((src.len() as u64 * 8) >> (8*i)) as u8;
+    }
+    ghash = gfmul( _mm_xor_si128( ghash, swap( load( &lengths ) ) ), h );
+
+    set_counter( &mut counter, 1 );
+    let mut tag = [0u8;16];
+    store( _mm_xor_si128( swap( ghash ), encrypt_block( &keys, load( &counter ) ) ), &mut tag );
+
+    if !tag_eq( &tag, mac ) {
+        for b in dst.iter_mut() {
+            *b = 0;
+        }
+        return false;
+    }
+    true
+}
+
+
+// Loads up to 16 bytes, zero padded.
+#[target_feature(enable = "sse2")]
+unsafe fn load( buf: &[u8] ) -> __m128i {
+    let mut block = [0u8;16];
+    block[..buf.len()].copy_from_slice( buf );
+    _mm_loadu_si128( block.as_ptr() as *const __m128i )
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn store( v: __m128i, buf: &mut [u8] ) {
+    let mut block = [0u8;16];
+    _mm_storeu_si128( block.as_mut_ptr() as *mut __m128i, v );
+    let len = buf.len();
+    buf.copy_from_slice( &block[..len] );
+}
+
+// Big endian 32-bit counter in the last bytes.
+fn set_counter( counter: &mut [u8;16], n: u32 ) {
+    for i in 0..4 {
+        counter[15-i] = (n >> (8*i)) as u8;
+    }
+}
+
+// GHASH works on byte reversed blocks.
+#[target_feature(enable = "ssse3")]
+unsafe fn swap( v: __m128i ) -> __m128i {
+    _mm_shuffle_epi8( v, _mm_set_epi8( 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15 ) )
+}
+
+
+#[target_feature(enable = "aes")]
+unsafe fn encrypt_block( keys: &[__m128i;15], block: __m128i ) -> __m128i {
+    let mut b = _mm_xor_si128( block, keys[0] );
+    for k in keys[1..14].iter() {
+        b = _mm_aesenc_si128( b, *k );
+    }
+    _mm_aesenclast_si128( b, keys[14] )
+}
+
+
+#[target_feature(enable = "aes")]
+unsafe fn expand( key: &[u8;32] ) -> [__m128i;15] {
+    let mut keys = [_mm_setzero_si128();15];
+    let mut t1 = _mm_loadu_si128( key.as_ptr() as *const __m128i );
+    let mut t3 = _mm_loadu_si128( key[16..].as_ptr() as *const __m128i );
+    keys[0] = t1;
+    keys[1] = t3;
+
+    macro_rules! round {
+        ($i:expr, $rcon:expr) => {
+            t1 = assist_1( t1, _mm_aeskeygenassist_si128( t3, $rcon ) );
+            keys[$i] = t1;
+            t3 = assist_2( t1, t3 );
+            keys[$i+1] = t3;
+        }
+    }
+    round!( 2, 0x01 );
+    round!( 4, 0x02 );
+    round!( 6, 0x04 );
+    round!( 8, 0x08 );
+    round!( 10, 0x10 );
+    round!( 12, 0x20 );
+    keys[14] = assist_1( t1, _mm_aeskeygenassist_si128( t3, 0x40 ) );
+    keys
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn shift_xor( t: __m128i ) -> __m128i {
+    let mut t = t;
+    let mut t4 = _mm_slli_si128( t, 4 );
+    t = _mm_xor_si128( t, t4 );
+    t4 = _mm_slli_si128( t4, 4 );
+    t = _mm_xor_si128( t, t4 );
+    t4 = _mm_slli_si128( t4, 4 );
+    _mm_xor_si128( t, t4 )
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn assist_1( t1: __m128i, t2: __m128i ) -> __m128i {
+    _mm_xor_si128( shift_xor( t1 ), _mm_shuffle_epi32( t2, 0xff ) )
+}
+
+#[target_feature(enable = "aes")]
+unsafe fn assist_2( t1: __m128i, t3: __m128i ) -> __m128i {
+    let t2 = _mm_shuffle_epi32( _mm_aeskeygenassist_si128( t1, 0x00 ), 0xaa );
+    _mm_xor_si128( shift_xor( t3 ), t2 )
+}
+
+
+// Multiplication in GF(2^128) of byte reversed blocks, with the reduction of
+// the Intel paper (algorithm 5).
+#[target_feature(enable = "pclmulqdq")]
+unsafe fn gfmul( a: __m128i, b: __m128i ) -> __m128i {
+    let mut tmp3 = _mm_clmulepi64_si128( a, b, 0x00 );
+    let mut tmp4 = _mm_clmulepi64_si128( a, b, 0x10 );
+    let mut tmp5 = _mm_clmulepi64_si128( a, b, 0x01 );
+    let mut tmp6 = _mm_clmulepi64_si128( a, b, 0x11 );
+
+    tmp4 = _mm_xor_si128( tmp4, tmp5 );
+    tmp5 = _mm_slli_si128( tmp4, 8 );
+    tmp4 = _mm_srli_si128( tmp4, 8 );
+    tmp3 = _mm_xor_si128( tmp3, tmp5 );
+    tmp6 = _mm_xor_si128( tmp6, tmp4 );
+
+    // Shift the 256-bit product left by one bit.
+    let mut tmp7 = _mm_srli_epi32( tmp3, 31 );
+    let mut tmp8 = _mm_srli_epi32( tmp6, 31 );
+    tmp3 = _mm_slli_epi32( tmp3, 1 );
+    tmp6 = _mm_slli_epi32( tmp6, 1 );
+    let tmp9 = _mm_srli_si128( tmp7, 12 );
+    tmp8 = _mm_slli_si128( tmp8, 4 );
+    tmp7 = _mm_slli_si128( tmp7, 4 );
+    tmp3 = _mm_or_si128( tmp3, tmp7 );
+    tmp6 = _mm_or_si128( tmp6, tmp8 );
+    tmp6 = _mm_or_si128( tmp6, tmp9 );
+
+    // Reduce modulo x^128 + x^7 + x^2 + x + 1.
+    tmp7 = _mm_slli_epi32( tmp3, 31 );
+    tmp8 = _mm_slli_epi32( tmp3, 30 );
+    let tmp9 = _mm_slli_epi32( tmp3, 25 );
+    tmp7 = _mm_xor_si128( tmp7, tmp8 );
+    tmp7 = _mm_xor_si128( tmp7, tmp9 );
+    tmp8 = _mm_srli_si128( tmp7, 4 );
+    tmp7 = _mm_slli_si128( tmp7, 12 );
+    tmp3 = _mm_xor_si128( tmp3, tmp7 );
+
+    let mut tmp2 = _mm_srli_epi32( tmp3, 1 );
+    tmp4 = _mm_srli_epi32( tmp3, 2 );
+    tmp5 = _mm_srli_epi32( tmp3, 7 );
+    tmp2 = _mm_xor_si128( tmp2, tmp4 );
+    tmp2 = _mm_xor_si128( tmp2, tmp5 );
+    tmp2 = _mm_xor_si128( tmp2, tmp8 );
+    tmp3 = _mm_xor_si128( tmp3, tmp2 );
+    _mm_xor_si128( tmp6, tmp3 )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::decrypt;
+
+    fn hex( s: &str ) -> Vec<u8> {
+        (0..s.len()/2).map(|i| u8::from_str_radix( &s[2*i..2*i+2], 16 ).unwrap() ).collect()
+    }
+
+    // Decrypts `ct || tag` and compares with `pt`.
+    fn check( key: &str, iv: &str, aad: &str, ct: &str, pt: &str ) {
+        assert!( is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq"),
+                 "AES-NI and PCLMULQDQ are needed" );
+        let mut k = [0u8;32];
+        k.copy_from_slice( &hex( key ) );
+        let ct = hex( ct );
+        let (src, tag) = ct.split_at( ct.len() - 16 );
+        let mut mac = [0u8;16];
+        mac.copy_from_slice( tag );
+        let mut dst = src.to_vec();
+        assert!( decrypt( &k, src, &hex( iv ), &hex( aad ), &mac, &mut dst ) );
+        assert_eq!( dst, hex( pt ) );
+
+        mac[0] ^= 1;
+        assert!( !decrypt( &k, src, &hex( iv ), &hex( aad ), &mac, &mut dst ) );
+        assert!( dst.iter().all(|&b| b == 0 ) );
+    }
+
+    const K: &str = "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308";
+    const IV: &str = "cafebabefacedbaddecaf888";
+    const P: &str = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+                             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255";
+    const C: &str = "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+                             8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015ad";
+
+    // Test cases 13 to 16 of McGrew & Viega, "The Galois/Counter Mode of
+    // Operation (GCM)".
+
+    #[test]
+    fn empty() {
+        check( &"00".repeat( 32 ), &"00".repeat( 12 ), "", "530f8afbc74536b9a963b4f1c4cb738b", "" );
+    }
+
+    #[test]
+    fn one_block() {
+        check( &"00".repeat( 32 ), &"00".repeat( 12 ), "",
+               "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919", &"00".repeat( 16 ) );
+    }
+
+    #[test]
+    fn four_blocks() {
+        check( K, IV, "", &[C, "b094dac5d93471bdec1a502270e3cc6c"].concat(), P );
+    }
+
+    #[test]
+    fn partial_block_and_aad() {
+        check( K, IV, "feedfacedeadbeeffeedfacedeadbeefabaddad2",
+               &[&C[..120], "76fc6ece0f4e1768cddf8853bb2d551b"].concat(), &P[..120] );
+    }
+}
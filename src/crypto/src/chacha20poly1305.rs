@@ -0,0 +1,248 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// ChaCha20-Poly1305 decryption (RFC 8439) in portable Rust. Poly1305 uses
+// 26-bit limbs, after poly1305-donna.
+
+use tag_eq;
+
+
+pub fn decrypt( key: &[u8;32],
+                src: &[u8],
+                iv:  &[u8],
+                aad: &[u8],
+                mac: &[u8;16],
+                dst: &mut [u8] ) -> bool {
+    let mut poly_key = [0u8;32];
+    poly_key.copy_from_slice( &chacha20( key, 0, iv )[..32] );
+    let mut poly = Poly1305::new( &poly_key );
+
+    for chunk in aad.chunks( 16 ) {
+        poly.block( chunk );
+    }
+
+    for (i, (s, d)) in src.chunks( 64 ).zip( dst.chunks_mut( 64 ) ).enumerate() {
+        // Read once: what is authenticated is what is decrypted.
+        let mut block = [0u8;64];
+        block[..s.len()].copy_from_slice( s );
+        for chunk in block[..s.len()].chunks( 16 ) {
+            poly.block( chunk );
+        }
+        let stream = chacha20( key, i as u32 + 1, iv );
+        for ((d, c), k) in d.iter_mut().zip( block.iter() ).zip( stream.iter() ) {
+            *d = c ^ k;
+        }
+    }
+
+    let mut lengths = [0u8;16];
+    for i in 0..8 {
+        lengths[i] = ((aad.len() as u64) >> (8*i)) as u8;
+        lengths[8+i] = ((src.len() as u64) >> (8*i)) as u8;
+    }
+    poly.block( &lengths );
+
+    if !tag_eq( &poly.finish(), mac ) {
+        for b in dst.iter_mut() {
+            *b = 0;
+        }
+        return false;
+    }
+    true
+}
+
+
+fn le32( b: &[u8] ) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+
+fn chacha20( key: &[u8;32], counter: u32, nonce: &[u8] ) -> [u8;64] {
+    let mut init = [0u32;16];
+    init[0] = 0x61707865;
+    init[1] = 0x3320646e;
+    init[2] = 0x79622d32;
+    init[3] = 0x6b206574;
+    for i in 0..8 {
+        init[4+i] = le32( &key[4*i..] );
+    }
+    init[12] = counter;
+    for i in 0..3 {
+        init[13+i] = le32( &nonce[4*i..] );
+    }
+
+    let mut x = init;
+    macro_rules! quarter {
+        ($a:expr, $b:expr, $c:expr, $d:expr) => {
+            x[$a] = x[$a].wrapping_add( x[$b] ); x[$d] = (x[$d] ^ x[$a]).rotate_left( 16 );
+            x[$c] = x[$c].wrapping_add( x[$d] ); x[$b] = (x[$b] ^ x[$c]).rotate_left( 12 );
+            x[$a] = x[$a].wrapping_add( x[$b] ); x[$d] = (x[$d] ^ x[$a]).rotate_left( 8 );
+            x[$c] = x[$c].wrapping_add( x[$d] ); x[$b] = (x[$b] ^ x[$c]).rotate_left( 7 );
+        }
+    }
+    for _ in 0..10 {
+        quarter!( 0, 4,  8, 12 );
+        quarter!( 1, 5,  9, 13 );
+        quarter!( 2, 6, 10, 14 );
+        quarter!( 3, 7, 11, 15 );
+        quarter!( 0, 5, 10, 15 );
+        quarter!( 1, 6, 11, 12 );
+        quarter!( 2, 7,  8, 13 );
+        quarter!( 3, 4,  9, 14 );
+    }
+
+    let mut out = [0u8;64];
+    for i in 0..16 {
+        let v = x[i].wrapping_add( init[i] );
+        for j in 0..4 {
+            out[4*i+j] = (v >> (8*j)) as u8;
+        }
+    }
+    out
+}
+
+
+struct Poly1305 {
+    r:   [u32;5],
+    h:   [u32;5],
+    pad: [u32;4],
+}
+
+impl Poly1305 {
+    fn new( key: &[u8;32] ) -> Poly1305 {
+        Poly1305 {
+            r: [ le32( &key[0..] ) & 0x3ffffff,
+                 (le32( &key[3..] ) >> 2) & 0x3ffff03,
+                 (le32( &key[6..] ) >> 4) & 0x3ffc0ff,
+                 (le32( &key[9..] ) >> 6) & 0x3f03fff,
+                 (le32( &key[12..] ) >> 8) & 0x00fffff ],
+            h: [0u32;5],
+            pad: [ le32( &key[16..] ), le32( &key[20..] ), le32( &key[24..] ), le32( &key[28..] ) ],
+        }
+    }
+
+    // Up to 16 bytes, zero padded as the AEAD construction pads its inputs.
+    fn block( &mut self, m: &[u8] ) {
+        let mut buf = [0u8;16];
+        buf[..m.len()].copy_from_slice( m );
+
+        let (r0, r1, r2, r3, r4) = (self.r[0], self.r[1], self.r[2], self.r[3], self.r[4]);
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+
+        h[0] += le32( &buf[0..] ) & 0x3ffffff;
+        h[1] += (le32( &buf[3..] ) >> 2) & 0x3ffffff;
+        h[2] += (le32( &buf[6..] ) >> 4) & 0x3ffffff;
+        h[3] += (le32( &buf[9..] ) >> 6) & 0x3ffffff;
+        h[4] += (le32( &buf[12..] ) >> 8) | (1 << 24);
+
+        let mul = |a: u32, b: u32| a as u64 * b as u64;
+        let d0 = mul(h[0],r0) + mul(h[1],s4) + mul(h[2],s3) + mul(h[3],s2) + mul(h[4],s1);
+        let mut d1 = mul(h[0],r1) + mul(h[1],r0) + mul(h[2],s4) + mul(h[3],s3) + mul(h[4],s2);
+        let mut d2 = mul(h[0],r2) + mul(h[1],r1) + mul(h[2],r0) + mul(h[3],s4) + mul(h[4],s3);
+        let mut d3 = mul(h[0],r3) + mul(h[1],r2) + mul(h[2],r1) + mul(h[3],r0) + mul(h[4],s4);
+        let mut d4 = mul(h[0],r4) + mul(h[1],r3) + mul(h[2],r2) + mul(h[3],r1) + mul(h[4],r0);
+
+        let mut c = d0 >> 26; h[0] = d0 as u32 & 0x3ffffff;
+        d1 += c; c = d1 >> 26; h[1] = d1 as u32 & 0x3ffffff;
+        d2 += c; c = d2 >> 26; h[2] = d2 as u32 & 0x3ffffff;
+        d3 += c; c = d3 >> 26; h[3] = d3 as u32 & 0x3ffffff;
+        d4 += c; c = d4 >> 26; h[4] = d4 as u32 & 0x3ffffff;
+        h[0] += c as u32 * 5;
+        let c = h[0] >> 26; h[0] &= 0x3ffffff;
+        h[1] += c;
+    }
+
+    fn finish( self ) -> [u8;16] {
+        let mut h = self.h;
+
+        let mut c = h[1] >> 26; h[1] &= 0x3ffffff;
+        h[2] += c; c = h[2] >> 26; h[2] &= 0x3ffffff;
+        h[3] += c; c = h[3] >> 26; h[3] &= 0x3ffffff;
+        h[4] += c; c = h[4] >> 26; h[4] &= 0x3ffffff;
+        h[0] += c * 5; c = h[0] >> 26; h[0] &= 0x3ffffff;
+        h[1] += c;
+
+        // h - p, kept if h >= p.
+        let mut g = [0u32;5];
+        g[0] = h[0] + 5; c = g[0] >> 26; g[0] &= 0x3ffffff;
+        g[1] = h[1] + c; c = g[1] >> 26; g[1] &= 0x3ffffff;
+        g[2] = h[2] + c; c = g[2] >> 26; g[2] &= 0x3ffffff;
+        g[3] = h[3] + c; c = g[3] >> 26; g[3] &= 0x3ffffff;
+        g[4] = (h[4] + c).wrapping_sub( 1 << 26 );
+
+        let mask = (g[4] >> 31).wrapping_sub( 1 );
+        for i in 0..5 {
+            h[i] = (h[i] & !mask) | (g[i] & mask);
+        }
+
+        let words = [ h[0] | h[1] << 26,
+                      h[1] >> 6 | h[2] << 20,
+                      h[2] >> 12 | h[3] << 14,
+                      h[3] >> 18 | h[4] << 8 ];
+        let mut tag = [0u8;16];
+        let mut f = 0u64;
+        for i in 0..4 {
+            f = words[i] as u64 + self.pad[i] as u64 + (f >> 32);
+            for j in 0..4 {
+                tag[4*i+j] = (f as u32 >> (8*j)) as u8;
+            }
+        }
+        tag
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::decrypt;
+
+    fn hex( s: &str ) -> Vec<u8> {
+        (0..s.len()/2).map(|i| u8::from_str_radix( &s[2*i..2*i+2], 16 ).unwrap() ).collect()
+    }
+
+    // RFC 8439, section 2.8.2.
+    #[test]
+    fn rfc8439() {
+        let mut key = [0u8;32];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = 0x80 + i as u8;
+        }
+        let iv = hex( "070000004041424344454647" );
+        let aad = hex( "50515253c0c1c2c3c4c5c6c7" );
+        let src = hex( "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+                        3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+                        92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+                        3ff4def08e4b7a9de576d26586cec64b6116" );
+        let mut mac = [0u8;16];
+        mac.copy_from_slice( &hex( "1ae10b594f09e26a7e902ecbd0600691" ) );
+        let mut dst = src.to_vec();
+        assert!( decrypt( &key, &src, &iv, &aad, &mac, &mut dst ) );
+        assert_eq!( &dst[..], &b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+                                 tip for the future, sunscreen would be it."[..] );
+
+        mac[15] ^= 0x80;
+        assert!( !decrypt( &key, &src, &iv, &aad, &mac, &mut dst ) );
+        assert!( dst.iter().all(|&b| b == 0 ) );
+    }
+}
@@ -0,0 +1,42 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+
+// AEAD ciphers of the enclave that the SGX SDK does not have, see
+// `enclave::aead`. They are kept out of the enclave crate, which has no test
+// target, so that `cargo test` runs their known-answer tests on the host
+// (`make test`).
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+pub mod aes256gcm;
+pub mod chacha20poly1305;
+
+
+// Compares two tags in constant time.
+pub fn tag_eq( a: &[u8;16], b: &[u8;16] ) -> bool {
+    a.iter().zip( b.iter() ).fold( 0u8, |d, (x, y)| d | (x ^ y) ) == 0
+}
@@ -0,0 +1,56 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Decryption of the .ce blocks with the cipher of their file.
+//
+// The SGX SDK only has AES-128-GCM, AES-256-GCM is done with the AES-NI and
+// PCLMULQDQ instructions, which every SGX capable CPU has, and
+// ChaCha20-Poly1305 in portable Rust, both in the `crypto` crate. The ciphertext is in untrusted memory,
+// both read each of its bytes once, and clear `dst` if the tag is wrong.
+
+use sgx_tcrypto::*;
+
+use shared::cipher::{self,Cipher};
+
+use crypto::{aes256gcm,chacha20poly1305};
+
+
+// Checks the tag of `src` and decrypts it in `dst`, of the same size. `key`
+// is a file key, see `shared::kdf::file_key`.
+pub fn decrypt( cipher: Cipher,
+                key:    &[u8;cipher::KEY_SIZE_MAX],
+                src:    &[u8],
+                iv:     &[u8],
+                aad:    &[u8],
+                mac:    &[u8;16],
+                dst:    &mut [u8] ) -> bool {
+    assert!( src.len() == dst.len() && iv.len() == 12 );
+    match cipher {
+        Cipher::Aes128Gcm => {
+            let mut k = [0u8;16];
+            k.copy_from_slice( &key[..16] );
+            rsgx_rijndael128GCM_decrypt( &k, src, iv, aad, mac, dst ).is_ok()
+        },
+        Cipher::Aes256Gcm        => aes256gcm::decrypt( key, src, iv, aad, mac, dst ),
+        Cipher::ChaCha20Poly1305 => chacha20poly1305::decrypt( key, src, iv, aad, mac, dst ),
+    }
+}
@@ -18,6 +18,9 @@
 //   knowledge of the CeCILL-C license and that you accept its terms.
 //
 
+use core::fmt;
+use core::mem;
+use core::str;
 
 
 const CHI2DF3_UB: f64 = 35.5;
@@ -93,6 +96,71 @@ pub fn chi2df3_sf(x: f64) -> f64 {
     res * in_limit
 }
 
+const PVALUE_DIGITS: usize = 14;
+// 10^14 = 2^14 * 5^14.
+const PVALUE_FIVES: u128 = 6103515625;
+
+/**
+ * @brief Conditional selection of integers
+ * @return a if cond==1 else b
+ */
+fn cond_select_u64(cond: u64, a: u64, b: u64) -> u64 {
+    b ^ ((a ^ b) & 0u64.wrapping_sub(cond))
+}
+
+/**
+ * @brief 1 if a < b, without branches
+ */
+fn lt_u64(a: u64, b: u64) -> u64 {
+    (a ^ ((a ^ b) | (a.wrapping_sub(b) ^ b))) >> 63
+}
+
+/**
+ * @brief p * 10^14 rounded to the nearest, half up, computed exactly from
+ * the mantissa and exponent of p in [0;1]
+ */
+fn pvalue_scaled(p: f64) -> u64 {
+    let bits: u64 = unsafe { mem::transmute(p) };
+    let exp = (bits >> 52) & 0x7ff;
+    let normal = lt_u64(0, exp);
+    let m = (bits & 0xf_ffff_ffff_ffff) | (normal << 52);
+    // p = m / 2^(1075 - exp), 1074 for subnormals; 10^14 = 2^14 * 5^14.
+    let shift = 1075 - cond_select_u64(normal, exp, 1) - 14;
+    // Past 127 bits the product rounds to 0 as well.
+    let shift = cond_select_u64(lt_u64(127, shift), 127, shift) as u32;
+
+    let x = m as u128 * PVALUE_FIVES;
+    let q = x >> shift;
+    // Round up when twice the remainder reaches 2^shift.
+    let r2 = (x - (q << shift)) << 1;
+    let half = 1u128 << shift;
+    let (rh, rl, hh, hl) = ((r2 >> 64) as u64, r2 as u64, (half >> 64) as u64, half as u64);
+    let below = lt_u64(rh, hh) | ((lt_u64(rh, hh) ^ lt_u64(hh, rh) ^ 1) & lt_u64(rl, hl));
+    q as u64 + (below ^ 1)
+}
+
+/**
+ * @brief P-value printed with 14 decimals, as `{:.14}` but with a fixed
+ * count of operations. The value is clamped to [0;1].
+ */
+pub struct PValue(pub f64);
+
+impl fmt::Display for PValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = cond_select(self.0 < 0.0, 0.0, cond_select(self.0 > 1.0, 1.0, self.0));
+        let mut v = pvalue_scaled(p);
+        let mut text = [b'0'; PVALUE_DIGITS+2];
+        text[1] = b'.';
+        for i in 0..PVALUE_DIGITS {
+            text[PVALUE_DIGITS+1-i] = b'0' + (v % 10) as u8;
+            v /= 10;
+        }
+        text[0] = b'0' + v as u8;
+        // This is safe because only digits and a dot were written.
+        write!(f, "{}", unsafe { str::from_utf8_unchecked( &text ) })
+    }
+}
+
 const PSEUDO: f64 = 1.0e-15;
 
 /**
@@ -108,6 +176,41 @@ pub fn chisquare_stat(n: f64, n1: f64, n2: f64, n1g: f64, n2g: f64) -> f64 {
     // af = (n1g+n2g)/n
 }
 
+/**
+ * @brief Largest change of `chisquare_stat` when the genotype of one sample
+ * changes, for cohorts of n1 and n2 alleles. Keys no sample carries count as
+ * 0.
+ *
+ * For ng carriers all in the second group the statistic is
+ * f(ng) = n1 n ng / (n2 (n - ng)), convex: the largest step of 2 alleles is
+ * the last, f(n2) - f(n2 - 2) = 2 n^2 / (n2 (n1 + 2)), or the same with the
+ * groups swapped. It bounds the steps of the other counts, which a search of
+ * all of them confirms for cohorts up to 40 samples each.
+ */
+pub fn chisquare_sensitivity(n1: f64, n2: f64) -> f64 {
+    let n = n1+n2;
+    let a = 2.0*n*n / (n2*(n1+2.0));
+    let b = 2.0*n*n / (n1*(n2+2.0));
+    if a > b { a } else { b }
+}
+
+/**
+ * @brief Largest `chisquare_stat` of a key with ng carrier alleles: all of
+ * them in one group, as many as it has. The statistic is the same with
+ * n - ng carriers.
+ */
+pub fn chisquare_max(n1: f64, n2: f64, ng: f64) -> f64 {
+    let n = n1+n2;
+    if ng <= 0.0 || ng >= n {
+        return 0.0;
+    }
+    let in_first = if ng < n1 { ng } else { n1 };
+    let in_second = if ng < n2 { ng } else { n2 };
+    let a = chisquare_stat(n, n1, n2, in_first, ng - in_first);
+    let b = chisquare_stat(n, n1, n2, ng - in_second, in_second);
+    if a > b { a } else { b }
+}
+
 
 // pub fn chisquare_stats(n: f64, n1: f64, n2: f64, n1g: f64, n2g: f64) -> (f64,f64,f64) {
 //     // let n = n1+n2;
@@ -19,9 +19,37 @@
 //
 
 
+// Logging of the enclave, through ocall_print_string to the standard output
+// of the application, that is to the host.  Everything logged leaves the
+// enclave in clear, hence the rule: never log a secret of the enclave, no
+// seed, randomiser or key, and no per-SNP data (keys, counts, statistics).
+// Only sizes, totals and public parameters may be logged.
+//
+// The debug level is compiled out unless the feature debug_log is enabled,
+// which the Makefile does for SGX_DEBUG=1 builds only.
+
 use core::fmt::{self,Write};
 use ocall;
 
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str( match *self {
+            Level::Error => "error",
+            Level::Warn  => "warn",
+            Level::Info  => "info",
+            Level::Debug => "debug",
+        })
+    }
+}
+
 pub struct Console;
 
 impl Write for Console {
@@ -31,18 +59,29 @@ impl Write for Console {
     }    
 }
 
-//#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ({
-        use core::fmt::Write;
-        let _ = console::Console.write_fmt(format_args!($($arg)*));
-    })
+pub fn log(level: Level, args: fmt::Arguments) {
+    let _ = write!(Console, "{}: {}\n", level, args);
 }
 
-//#[macro_export]
-macro_rules! println {
-    ($fmt:expr) => ( print!(concat!($fmt, "\n")) );
-    ($fmt:expr, $($arg:tt)*) => ( print!(concat!($fmt, "\n"), $($arg)*) );
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ( console::log($level, format_args!($($arg)*)) )
 }
 
+macro_rules! error {
+    ($($arg:tt)*) => ( log!(console::Level::Error, $($arg)*) )
+}
+
+#[allow(unused_macros)]
+macro_rules! warn {
+    ($($arg:tt)*) => ( log!(console::Level::Warn, $($arg)*) )
+}
 
+macro_rules! info {
+    ($($arg:tt)*) => ( log!(console::Level::Info, $($arg)*) )
+}
+
+// The arguments are still type checked, but the call is removed at compile
+// time without debug_log.
+macro_rules! debug {
+    ($($arg:tt)*) => ( if cfg!(feature = "debug_log") { log!(console::Level::Debug, $($arg)*) } )
+}
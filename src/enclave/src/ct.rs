@@ -0,0 +1,114 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Constant-time helpers: conditions are 0 or 1 as `u32`, the results are
+// computed with masks rather than branches so that neither the time nor the
+// memory accesses depend on them.
+
+use core::mem;
+use core::slice;
+use core::ops::{BitAnd,BitXor};
+
+use shared::Key;
+
+
+// 1 if `x < y`.
+pub fn lt( x: u64, y: u64 ) -> u32 {
+    ((x ^ ((x ^ y) | (x.wrapping_sub( y ) ^ y))) >> 63) as u32
+}
+
+// 1 if `x == y`.
+pub fn eq( x: u64, y: u64 ) -> u32 {
+    let d = x ^ y;
+    (((d | d.wrapping_neg()) >> 63) ^ 1) as u32
+}
+
+// `a` if `cond` is 1, `b` otherwise.
+pub fn select( cond: u32, a: u64, b: u64 ) -> u64 {
+    b ^ ((a ^ b) & 0u64.wrapping_sub( cond as u64 ))
+}
+
+
+// Genomic order of the keys, as `Ord for Key`.
+pub fn key_lt( a: &Key, b: &Key ) -> u32 {
+    let ah = (a.contig() as u64) << 32 | a.pos() as u64;
+    let bh = (b.contig() as u64) << 32 | b.pos() as u64;
+    lt( ah, bh ) | (eq( ah, bh ) & lt( a.allele() as u64, b.allele() as u64 ))
+}
+
+// As `PartialEq for Key`.
+pub fn key_eq( a: &Key, b: &Key ) -> u32 {
+    eq( a.chrom_allele(), b.chrom_allele() ) & eq( a.pos() as u64, b.pos() as u64 )
+}
+
+
+// `T` seen as words of `W`, which its size and alignment allow.
+fn words<T, W>( v: &mut T ) -> &mut [W] {
+    unsafe { slice::from_raw_parts_mut( v as *mut T as *mut W, mem::size_of::<T>() / mem::size_of::<W>() ) }
+}
+
+fn fits<T, W>() -> bool {
+    mem::size_of::<T>() % mem::size_of::<W>() == 0 && mem::align_of::<T>() >= mem::align_of::<W>()
+}
+
+fn swap_words<W>( a: &mut [W], b: &mut [W], mask: W )
+    where W: Copy + BitXor<Output=W> + BitAnd<Output=W> {
+    for (x, y) in a.iter_mut().zip( b.iter_mut() ) {
+        let t = (*x ^ *y) & mask;
+        *x = *x ^ t;
+        *y = *y ^ t;
+    }
+}
+
+fn move_words<W>( dst: &mut [W], src: &[W], mask: W )
+    where W: Copy + BitXor<Output=W> + BitAnd<Output=W> {
+    for (x, y) in dst.iter_mut().zip( src.iter() ) {
+        *x = *x ^ ((*x ^ *y) & mask);
+    }
+}
+
+// Swap `a` and `b` if `cond` is 1. The words are chosen at compile time.
+pub fn cswap<T: Copy>( a: &mut T, b: &mut T, cond: u32 ) {
+    let mask = 0u64.wrapping_sub( cond as u64 );
+    if fits::<T,u64>() {
+        swap_words( words::<T,u64>( a ), words::<T,u64>( b ), mask )
+    } else if fits::<T,u32>() {
+        swap_words( words::<T,u32>( a ), words::<T,u32>( b ), mask as u32 )
+    } else {
+        assert!( fits::<T,u16>() );
+        swap_words( words::<T,u16>( a ), words::<T,u16>( b ), mask as u16 )
+    }
+}
+
+// Copy `src` to `dst` if `cond` is 1.
+pub fn cmove<T: Copy>( dst: &mut T, src: &T, cond: u32 ) {
+    let mask = 0u64.wrapping_sub( cond as u64 );
+    let mut src = *src;
+    if fits::<T,u64>() {
+        move_words( words::<T,u64>( dst ), words::<T,u64>( &mut src ), mask )
+    } else if fits::<T,u32>() {
+        move_words( words::<T,u32>( dst ), words::<T,u32>( &mut src ), mask as u32 )
+    } else {
+        assert!( fits::<T,u16>() );
+        move_words( words::<T,u16>( dst ), words::<T,u16>( &mut src ), mask as u16 )
+    }
+}
@@ -0,0 +1,178 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+use core::fmt;
+use alloc::vec::Vec;
+use alloc::string::String;
+
+use shared::{contig,Key,Name};
+use shared::side::{Record,Tag};
+
+
+// Names read from the block side tables, used to print the keys.
+//
+// Inserting a name searches and shifts these vectors at its key, whatever the
+// aggregation: the host watching the memory of the enclave learns which keys
+// have a record. Those are the keys of alleles too long to be packed, of IDs
+// other than rsIDs and the first key of each contig in a block, see
+// `shared::side`; the packed SNPs, most of a VCF, leave no trace here.
+pub struct Dict {
+    // `REF\tALT` of the named alleles, sorted by key.
+    alleles: Vec<(Key,String)>,
+    // Contig names as written in the inputs, sorted by index.
+    contigs: Vec<(u32,String)>,
+    // Named IDs, sorted by hash.
+    ids:     Vec<(u64,String)>,
+    // Keys of several named alleles, whose counts mix different variants.
+    // They are left out of the results.
+    collisions: Vec<Key>,
+    // Indices of several named contigs, sorted, whose keys are left out too.
+    contig_collisions: Vec<u32>,
+}
+
+
+impl Dict {
+    pub fn new() -> Dict {
+        Dict { alleles: Vec::new(), contigs: Vec::new(), ids: Vec::new(), collisions: Vec::new(),
+               contig_collisions: Vec::new() }
+    }
+
+    // Add the record of a side table, `blk` are the keys of its block. The
+    // panic messages go to the host, they carry no key, index or name.
+    pub fn insert(&mut self, blk: &[Key], rec: &Record) {
+        let key = match blk.get( rec.idx ) {
+            Some(&key) => key,
+            None => panic!("side table index out of block"),
+        };
+        match rec.tag {
+            Tag::Allele => {
+                assert!( key.is_named_allele(), "side table allele for a packed key" );
+                let mut alleles = rec.data.splitn( 2, '\t' );
+                let allele = match (alleles.next(), alleles.next()) {
+                    (Some(refe), Some(alt)) => Key::encode_allele( refe, alt ).ok(),
+                    _                       => None,
+                };
+                assert!( allele == Some( key.allele() ), "side table allele does not match its key" );
+                if !insert( &mut self.alleles, key, rec.data ) {
+                    if let Err(pos) = self.collisions.binary_search( &key ) {
+                        self.collisions.insert( pos, key );
+                    }
+                }
+            },
+            Tag::Id => {
+                assert!( key.is_named_id(), "side table ID for a rsID key" );
+                assert!( Key::encode_id( rec.data ) == key.id(), "side table ID does not match its key" );
+                // The ID hash is 63 bits long, it identifies the text alone.
+                if let Err(pos) = self.ids.binary_search_by(|e| e.0.cmp(&key.id()) ) {
+                    self.ids.insert( pos, (key.id(), String::from(rec.data)) );
+                }
+            },
+            Tag::Contig => {
+                let index = key.contig();
+                assert!( contig::index( rec.data ) == index, "side table contig does not match its key" );
+                match self.contigs.binary_search_by(|e| e.0.cmp(&index) ) {
+                    // Primary chromosomes may be spelled differently by the
+                    // inputs, the first name is kept. Another name of a named
+                    // contig is a collision of its hash.
+                    Ok(pos) => if contig::is_named( index ) && self.contigs[pos].1 != rec.data {
+                        if let Err(pos) = self.contig_collisions.binary_search( &index ) {
+                            self.contig_collisions.insert( pos, index );
+                        }
+                    },
+                    Err(pos) => self.contigs.insert( pos, (index, String::from(rec.data)) ),
+                }
+            },
+            // Padding records refer to the first key of their block.
+            Tag::Padding => (),
+        }
+    }
+
+    pub fn allele(&self, key: &Key) -> Option<&str> {
+        match self.alleles.binary_search_by(|e| e.0.cmp(key) ) {
+            Ok(pos) => Some(&self.alleles[pos].1),
+            Err(_)  => None,
+        }
+    }
+
+    // True if the alleles or the contig of this key collide with other ones,
+    // see `insert`.
+    pub fn is_collision(&self, key: &Key) -> bool {
+        self.collisions.binary_search( key ).is_ok() || self.contig_collisions.binary_search( &key.contig() ).is_ok()
+    }
+
+    pub fn contig(&self, index: u32) -> Option<&str> {
+        match self.contigs.binary_search_by(|e| e.0.cmp(&index) ) {
+            Ok(pos) => Some(&self.contigs[pos].1),
+            Err(_)  => None,
+        }
+    }
+
+    pub fn id(&self, id: u64) -> Option<&str> {
+        match self.ids.binary_search_by(|e| e.0.cmp(&id) ) {
+            Ok(pos) => Some(&self.ids[pos].1),
+            Err(_)  => None,
+        }
+    }
+
+    // Keep only the names of `keys`, contigs are few and always kept.
+    pub fn retain(&mut self, keys: &[Key]) {
+        self.alleles.retain(|e| keys.contains( &e.0 ) );
+        self.collisions.retain(|k| keys.contains( k ) );
+        self.ids.retain(|e| keys.iter().any(|k| k.id() == e.0 ) );
+    }
+
+    pub fn write(&self, key: &Key, name: Name, f: &mut fmt::Formatter) -> fmt::Result {
+        match name {
+            Name::Contig => match self.contig( key.contig() ) {
+                Some(s) => write!(f, "{}", s),
+                None    => contig::write_name( key.contig(), f ),
+            },
+            Name::Id => match self.id( key.id() ) {
+                Some(s) => write!(f, "{}", s),
+                None    => write!(f, "<{:016x}>", key.id()),
+            },
+            Name::Allele => match self.allele( key ) {
+                Some(s) => write!(f, "{}", s),
+                None    => write!(f, "<{:08x}>\t.", key.allele()),
+            },
+        }
+    }
+}
+
+
+// False if the key already has another name. The allele and contig hashes
+// are not keyed: `ce` computes them on the host of the data owner, before any
+// enclave key exists, so the keyed SipHash of the hash map (`shared::siphash`)
+// cannot cover them. Anyone writing a .ce file may make two alleles of a site,
+// or two contigs, collide, so this must not stop the analysis: the colliding
+// keys are left out, which a data owner may use to hide the SNPs of another.
+// Named alleles and contigs are only safe between data owners who trust each
+// other.
+fn insert(names: &mut Vec<(Key,String)>, key: Key, name: &str) -> bool {
+    match names.binary_search_by(|e| e.0.cmp(&key) ) {
+        Ok(pos)  => names[pos].1 == name,
+        Err(pos) => {
+            names.insert( pos, (key, String::from(name)) );
+            true
+        },
+    }
+}
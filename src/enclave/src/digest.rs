@@ -0,0 +1,54 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Running SHA-256 of the .ce files consumed and of the results written, see
+// `shared::signature`.
+
+use sgx_tcrypto::SgxShaHandle;
+
+
+pub struct Sha256 {
+    handle: SgxShaHandle,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        let handle = SgxShaHandle::new();
+        if let Err(err) = handle.init() {
+            panic!("sha256 init: {:?}", err);
+        }
+        Sha256 { handle: handle }
+    }
+
+    pub fn update( &mut self, data: &[u8] ) {
+        if let Err(err) = self.handle.update_slice( data ) {
+            panic!("sha256 update: {:?}", err);
+        }
+    }
+
+    pub fn finish( &mut self ) -> [u8;32] {
+        match self.handle.get_hash() {
+            Ok(hash) => hash,
+            Err(err) => panic!("sha256 get_hash: {:?}", err),
+        }
+    }
+}
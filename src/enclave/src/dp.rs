@@ -0,0 +1,229 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Differentially private release of the results, optional.
+//
+// Two datasets are neighbours when the genotypes of one sample differ, the
+// cohort sizes are public in the file headers. The chi-square statistic then
+// changes by at most `chisquare::chisquare_sensitivity` of the cohort sizes.
+// A key no file carries counts as a key of statistic 0, and so does a key
+// suppressed by the minor allele count of the policy: the sensitivity also
+// covers a key crossing it, see `calibrate`.
+//
+// The keys carried are not a public domain, so the top SNPs are selected in a
+// limited domain (Durfee & Rogers, 2019): only the `k + 1` keys of highest
+// statistic, kept in `TopK`, are candidates. The first `k` get Gumbel noise,
+// which draws the same as k rounds of the exponential mechanism, and so does
+// a threshold `bottom_offset` above the statistic of the `k + 1`-th. The keys
+// whose noisy statistic passes the noisy threshold are released, at most `k`.
+// A key a single sample adds to the candidates passes with a probability
+// below the delta of its round: each round is (epsilon, delta)-DP.
+//
+// Half of (epsilon, delta) selects the top SNPs, a quarter of delta goes to
+// the thresholds of the `k` rounds. The other half releases their statistic
+// with Laplace noise, the p-values are computed from it. The k rounds of each
+// half compose, basically or with the advanced composition, whichever allows
+// more per round.
+//
+// Each allele frequency released has its own (epsilon, delta): the allele
+// count gets Laplace noise, the key is released if the noisy count reaches
+// `af_threshold`, which a key carried by a single sample does with a
+// probability below delta, and the frequency is computed from it.
+
+use core::fmt;
+use core::f64;
+use core::cmp::Ordering;
+use core::intrinsics::{logf64,expf64,sqrtf64};
+
+use alloc::vec::Vec;
+
+use sgx_trts::trts::rsgx_read_rand;
+use shared::{as_u8_slice_mut,Key};
+
+use chisquare;
+
+
+pub struct Privacy {
+    pub epsilon:    f64,
+    pub delta:      f64,
+    // Per allele frequency, 0 without allele frequencies.
+    pub af_epsilon: f64,
+    k:              usize,
+    // Set with the cohort sizes.
+    sensitivity:    f64,
+    nb_alleles:     f64,
+    af_threshold:   f64,
+}
+
+impl Privacy {
+    pub fn new( epsilon: f64, delta: f64, af_epsilon: f64, k: usize ) -> Privacy {
+        assert!( epsilon > 0.0 && epsilon < f64::INFINITY, "invalid epsilon {}", epsilon );
+        assert!( delta > 0.0 && delta < 1.0, "invalid delta {}", delta );
+        assert!( af_epsilon >= 0.0 && af_epsilon < f64::INFINITY, "invalid allele frequency epsilon {}", af_epsilon );
+        Privacy { epsilon: epsilon, delta: delta, af_epsilon: af_epsilon, k: k,
+                  sensitivity: 0.0, nb_alleles: 0.0, af_threshold: 0.0 }
+    }
+
+    // Cohorts of `n1` and `n2` alleles. A key kept by the policy with
+    // `min_mac` or `min_mac + 1` minor alleles may be suppressed in a
+    // neighbour, its statistic drops to 0.
+    pub fn calibrate( &mut self, n1: f64, n2: f64, min_mac: u32 ) {
+        self.sensitivity = chisquare::chisquare_sensitivity( n1, n2 );
+        if min_mac > 0 {
+            for &mac in [min_mac, min_mac + 1].iter() {
+                let jump = chisquare::chisquare_max( n1, n2, mac as f64 );
+                if jump > self.sensitivity {
+                    self.sensitivity = jump;
+                }
+            }
+        }
+        self.nb_alleles = n1 + n2;
+        if self.af_epsilon > 0.0 {
+            // A single sample carries 2 alleles at most.
+            self.af_threshold = 2.0 + unsafe { self.count_scale() * logf64( 1.0 / (2.0 * self.delta) ) };
+        }
+    }
+
+    // Of each of the k rounds.
+    fn selection_epsilon(&self) -> f64 {
+        per_round( self.epsilon / 2.0, self.delta / 4.0, self.k )
+    }
+
+    fn selection_delta(&self) -> f64 {
+        self.delta / (4.0 * self.k as f64)
+    }
+
+    fn statistic_epsilon(&self) -> f64 {
+        per_round( self.epsilon / 2.0, self.delta / 2.0, self.k )
+    }
+
+    pub fn selection_scale(&self) -> f64 {
+        2.0 * self.sensitivity / self.selection_epsilon()
+    }
+
+    pub fn statistic_scale(&self) -> f64 {
+        self.sensitivity / self.statistic_epsilon()
+    }
+
+    // Of the threshold over the `k + 1`-th statistic. A candidate of a single
+    // dataset is at most 2 sensitivities above it, one of the `k` passes the
+    // noisy threshold with a probability below `k exp(-(offset - 2 s) / scale)`.
+    pub fn bottom_offset(&self) -> f64 {
+        let k = self.k as f64;
+        2.0 * self.sensitivity + unsafe { self.selection_scale() * logf64( k / self.selection_delta() ) }
+    }
+
+    fn count_scale(&self) -> f64 {
+        2.0 / self.af_epsilon
+    }
+
+    // The keys released and their statistic, out of the `k + 1` of highest
+    // statistic in decreasing order, fewer if fewer keys were kept.
+    pub fn release( &self, tops: &[(Key,f64)] ) -> Vec<(Key,f64)> {
+        let (candidates, bottom) = if tops.len() > self.k { (&tops[..self.k], tops[self.k].1) } else { (tops, 0.0) };
+        let scale = self.selection_scale();
+        let threshold = bottom + self.bottom_offset() + gumbel( scale );
+        let mut selected: Vec<(f64,Key,f64)> = candidates.iter()
+            .map(|&(key, chi2)| (chi2 + gumbel( scale ), key, chi2) )
+            .filter(|&(score, _, _)| score > threshold )
+            .collect();
+        selected.sort_by(|a, b| b.0.partial_cmp( &a.0 ).unwrap_or( Ordering::Equal ) );
+        selected.iter().map(|&(_, key, chi2)| (key, self.statistic( chi2 )) ).collect()
+    }
+
+    // Statistic released for a key selected, never negative.
+    fn statistic( &self, chi2: f64 ) -> f64 {
+        let chi2 = chi2 + laplace( self.statistic_scale() );
+        if chi2 < 0.0 { 0.0 } else { chi2 }
+    }
+
+    // Noisy allele count of a key, None if the key is not released.
+    pub fn allele_count( &self, count: f64 ) -> Option<f64> {
+        assert!( self.af_epsilon > 0.0, "allele frequencies without noise" );
+        let count = count + laplace( self.count_scale() );
+        if count >= self.af_threshold { Some(count) } else { None }
+    }
+
+    // Allele frequency of a noisy count, in [0;1].
+    pub fn allele_freq( &self, count: f64 ) -> f64 {
+        let af = count / self.nb_alleles;
+        if af > 1.0 { 1.0 } else { af }
+    }
+}
+
+// The header lines of the chi-square results, `nb_af` frequencies released.
+pub struct Spent<'a>( pub &'a Privacy, pub usize );
+
+impl<'a> fmt::Display for Spent<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let &Spent(p, nb_af) = self;
+        try!( writeln!(f, "#Differential privacy: epsilon {} delta {} for the top {} SNPs, chi-square sensitivity {}",
+                       p.epsilon, p.delta, p.k, p.sensitivity ) );
+        try!( writeln!(f, "#Selection among the top {} with Gumbel noise of scale {} and a threshold {} above the next, epsilon {} delta {} per round",
+                       p.k, p.selection_scale(), p.bottom_offset(), p.selection_epsilon(), p.selection_delta() ) );
+        try!( writeln!(f, "#Statistics with Laplace noise of scale {}, epsilon {} per round",
+                       p.statistic_scale(), p.statistic_epsilon() ) );
+        if p.af_epsilon > 0.0 {
+            let nb = nb_af as f64;
+            try!( writeln!(f, "#Allele frequencies: {} with an allele count of Laplace noise of scale {} above {}, epsilon {} delta {} each, epsilon {} delta {} in total",
+                           nb_af, p.count_scale(), p.af_threshold, p.af_epsilon, p.delta, p.af_epsilon * nb, p.delta * nb ) );
+        }
+        Ok(())
+    }
+}
+
+
+// Epsilon of each of `k` rounds for (epsilon, delta) in total: epsilon / k,
+// or by the advanced composition, epsilon = sqrt(2 k ln(1/delta)) e
+// + k e (exp(e) - 1), if larger.
+fn per_round( epsilon: f64, delta: f64, k: usize ) -> f64 {
+    let k = k as f64;
+    let basic = epsilon / k;
+    if delta == 0.0 {
+        return basic;
+    }
+    let total = |e: f64| unsafe { sqrtf64( 2.0 * k * logf64( 1.0 / delta ) ) * e + k * e * (expf64( e ) - 1.0) };
+    let (mut lo, mut hi) = (0.0, epsilon);
+    for _ in 0..100 {
+        let mid = (lo + hi) / 2.0;
+        if total( mid ) <= epsilon { lo = mid; } else { hi = mid; }
+    }
+    if lo > basic { lo } else { basic }
+}
+
+
+// In (0;1).
+fn uniform() -> f64 {
+    let mut r = 0u64;
+    if let Err(why) = rsgx_read_rand( as_u8_slice_mut( &mut r ) ) {
+        panic!("rsgx_read_rand: {:?}", why);
+    }
+    ((r >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+}
+
+fn laplace( scale: f64 ) -> f64 {
+    unsafe { scale * (logf64( uniform() ) - logf64( uniform() )) }
+}
+
+fn gumbel( scale: f64 ) -> f64 {
+    unsafe { -scale * logf64( -logf64( uniform() ) ) }
+}
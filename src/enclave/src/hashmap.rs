@@ -29,8 +29,8 @@ use core::ops::{Drop, Deref, DerefMut};
 use sgx_types::{sgx_spinlock_t, sgx_spin_lock, sgx_spin_unlock, SGX_SPINLOCK_INITIALIZER};
 use sgx_trts::trts::rsgx_read_rand;
 
-use core::fmt::Display;
-use shared::{Hash, as_u8_slice_mut};
+use shared::Hash;
+use shared::siphash::{SipKey,SIP_KEY_SIZE};
 
 use console;
 use core::mem;
@@ -106,7 +106,7 @@ impl<'a, V: ?Sized> Drop for Guard<'a, V>
 pub struct HashMap<K,V>{
     map:       Box<[Bucket<K,V>]>,
     count:     AtomicUsize,
-    rand:      u64,
+    key:       SipKey,
     bits:      u32,
 }
 
@@ -115,16 +115,16 @@ impl<K,V> HashMap<K,V> {
     pub fn new(cap: usize) -> HashMap<K,V> {
         let mut vec = Vec::with_capacity( cap as usize );
         
-        let mut rand = 0u64;
-        match rsgx_read_rand( as_u8_slice_mut( &mut rand ) ) {
+        // Secret SipHash key of the table, never leaves the enclave.
+        let mut seed = [0u8;SIP_KEY_SIZE];
+        match rsgx_read_rand( &mut seed ) {
             Ok(_) => (),
             Err(why) => panic!("rsgx_read_rand: {:?}", why),
         };
         
-        println!("HashMap::new(cap:{}) => {}ko, size_of::<Bucket>()={}, rand={:x}",
-                 cap, cap*mem::size_of_val(&vec)/1024,
-                 mem::size_of::<Bucket<K,V>>(),
-                 rand );
+        debug!("HashMap::new(cap:{}) => {}ko, size_of::<Bucket>()={}",
+               cap, cap*mem::size_of_val(&vec)/1024,
+               mem::size_of::<Bucket<K,V>>() );
         
         unsafe { vec.set_len( cap as usize ) };
         let bits = (cap as u64).leading_zeros();
@@ -133,7 +133,7 @@ impl<K,V> HashMap<K,V> {
                                     count: ATOMIC_USIZE_INIT,
                                     // collision: ATOMIC_USIZE_INIT,
                                     bits: bits,
-                                    rand: rand };
+                                    key: SipKey::new( &seed ) };
         hashmap.clear();
         hashmap
     }
@@ -185,10 +185,10 @@ impl<'a, K: 'a, V: 'a> Iterator for Iter<'a,K,V> {
 }
 
 
-impl<K: Hash+Copy+PartialEq+Display, V: Default> HashMap<K,V> {
+impl<K: Hash+Copy+PartialEq, V: Default> HashMap<K,V> {
 
     fn hash(&self, key: K) -> u64 {
-        (key.hash() ^ self.rand) >> (64-self.bits)
+        key.hash( &self.key ) >> (64-self.bits)
     }
     
     pub fn insert(&mut self, key: K) -> Guard<V> {
@@ -227,7 +227,7 @@ impl<K: Hash+Copy+PartialEq+Display, V: Default> HashMap<K,V> {
             i += 1;
             nb += 1;
             if nb == cap {
-                panic!("HashMap full {} for {}", self.len(), key );
+                panic!("HashMap full");
             }
         };
         // unreachable!()
@@ -19,85 +19,248 @@
 //
 
 
-use alloc::boxed::Box;
 use alloc::vec::Vec;
-use alloc::string::String;
+use alloc::boxed::Box;
 
 use core::cmp;
-use core::fmt::Write;
+use core::slice;
+use core::fmt::{self,Write};
 
 use types::{Value,BlockInfo,Vcf};
-use shared::{self,Kind,Key,KEY_MIN,KEY_MAX,Typ};
-use ocall;
+use shared::{self,Aggregation,Kind,Key,KEY_MIN,KEY_MAX,Name};
+use shared::provision::PUBLIC_KEY_SIZE;
+use shared::policy::{Policy,Analysis};
+use shared::results::Output;
+use shared::signature::{self,SignedResults};
+use shared::audit::AuditRecord;
+use dict::Dict;
+use results;
+use digest::Sha256;
+use keys;
+use pse;
 use hashmap;
-use chisquare;
+use oblivious::{self,Entry};
+use chisquare::{self,PValue};
+use topk::TopK;
+use dp::{self,Privacy};
+use ct;
 use console;
+use ocall;
 use spin::Mutex;
 
 
 
+// Counts of the keys of an iteration, see `shared::Aggregation`.
+enum Counts {
+    Hashmap(hashmap::HashMap<Key,Value>),
+    Oblivious(Mutex<oblivious::Counts>),
+}
+
+impl Counts {
+    fn new( aggregation: Aggregation, cap: usize ) -> Counts {
+        match aggregation {
+            Aggregation::Hashmap   => Counts::Hashmap( hashmap::HashMap::new( cap ) ),
+            Aggregation::Oblivious => Counts::Oblivious( Mutex::new( oblivious::Counts::new( cap ) ) ),
+        }
+    }
+
+    fn len(&mut self) -> usize {
+        match *self {
+            Counts::Hashmap(ref map)       => map.len(),
+            Counts::Oblivious(ref mut obl) => obl.get_mut().len(),
+        }
+    }
+
+    fn clear(&mut self) {
+        match *self {
+            Counts::Hashmap(ref mut map)   => map.clear(),
+            Counts::Oblivious(ref mut obl) => obl.get_mut().clear(),
+        }
+    }
+
+    // Count the keys still pending, once the blocks of an iteration are read.
+    fn merge(&mut self) {
+        if let Counts::Oblivious(ref mut obl) = *self {
+            obl.get_mut().merge();
+        }
+    }
+
+    fn iter<'a>(&'a mut self) -> Box<Iterator<Item=(Key,Value)> + 'a> {
+        match *self {
+            Counts::Hashmap(ref map)       => Box::new( map.iter().map(|(&k,&v)| (k,v)) ),
+            Counts::Oblivious(ref mut obl) => Box::new( obl.get_mut().iter() ),
+        }
+    }
+}
+
+
+// 1 if the SNP is not suppressed by the policy: its minor allele count, out
+// of the `n` alleles of both groups, is at least `min_mac`.
+fn kept( policy: &Policy, n: f64, v: &Value ) -> u32 {
+    let ac = v.0 as u64 + v.1 as u64;
+    let other = (n as u64).wrapping_sub( ac );
+    let mac = ct::select( ct::lt( ac, other ), ac, other );
+    ct::lt( mac, policy.min_mac as u64 ) ^ 1
+}
+
+
+
 // To store the data between enclave calls.
 pub struct GlobalData {
-    // Files
-    files:      Box<[Vcf]>,
-    // nb_files:   usize,
+    // Files, in registration order.
+    files:      Vec<Vcf>,
+    // Checked against the usage policy of each file.
+    analysis:   Analysis,
+    // Cohort sizes, counted from the authenticated file headers.
     nb_control: f64,
     nb_case:    f64,
+    // No file can be registered once the analysis started.
+    started:    bool,
     // Buffer.
-    buffers:        Mutex<Vec<Box<[Key]>>>,
+    buffers:        Mutex<Vec<Vec<Key>>>,
     keys_per_block: usize, 
     // Keys.
     nb_keys:   u32,
     prev_key:  Key,
     // Our container.
-    map:        hashmap::HashMap<Key,Value>,
+    map:        Counts,
     max_len:    usize,
     total_key:  usize,
     last_key:   Key,
+    // Names of the keys.
+    dict:       Mutex<Dict>,
     // Top Most
-    top_most:   Vec<(Key,f64)>,
-    output_allele_freq: bool
+    top_most:   TopK,
+    // Noise added to the results, if any.
+    privacy:    Option<Privacy>,
+    // SNPs below the minor allele count of the policy are suppressed.
+    policy:     Policy,
+    suppressed: usize,
+    // SNPs left out for a collision of their allele hash, see `Dict`.
+    collided:   usize,
+    // Allele frequencies written.
+    nb_af:      usize,
+    // Results, encrypted for the researcher.
+    chisq_out:  results::Writer,
+    af_out:     Option<results::Writer>,
+    // Signed once the analysis is over.
+    statement:  SignedResults,
+    // Appended to the audit log with the statement.
+    audit:      AuditRecord,
+    signed:     bool,
 }
 
 
 
 impl GlobalData {
-    pub fn new( nb_control:         usize,
-                nb_case:            usize,
+    pub fn new( nb_files:           usize,
                 keys_per_block:     u32,
                 nb_keys:            u32,
-                snp_cnt:            usize,
-                output_allele_freq: bool ) -> GlobalData {
-        let nb_files = nb_control + nb_case;
-
-        let mut files  = Vec::with_capacity( nb_files as usize);
-        unsafe {
-            files.set_len( nb_files );
+                analysis:           Analysis,
+                aggregation:        Aggregation,
+                privacy:            Option<Privacy>,
+                policy:             Policy,
+                audit:              AuditRecord,
+                recipient:          &[u8;PUBLIC_KEY_SIZE] ) -> GlobalData {
+        // Its header comes at the end, with the privacy budget spent.
+        let chisq_out = results::Writer::new( Output::Chisq, recipient );
+        let af_out = if analysis.output_allele_freq {
+            let mut af_out = results::Writer::new( Output::AlleleFreq, recipient );
+            writeln!(&mut af_out, "#Allele frequecies of SNPs from two groups").unwrap();
+            writeln!(&mut af_out, "#Policy: minor allele count at least {}", policy.min_mac ).unwrap();
+            if let Some(ref privacy) = privacy {
+                if privacy.af_epsilon > 0.0 {
+                    writeln!(&mut af_out, "#Differential privacy: epsilon {} delta {} per allele frequency",
+                             privacy.af_epsilon, privacy.delta ).unwrap();
+                }
+            }
+            writeln!(&mut af_out, "#CHROM\tPOS\tID\tREF\tALT\talleleFreq").unwrap();
+            Some(af_out)
+        } else {
+            None
+        };
+        // The allele frequencies are noisy too.
+        if let Some(ref privacy) = privacy {
+            assert!( !analysis.output_allele_freq || privacy.af_epsilon > 0.0,
+                     "differential privacy: allele frequencies without noise" );
         }
-        
+        let top_most = TopK::new( analysis.snp_cnt as usize + privacy.is_some() as usize );
+        let statement = SignedResults::new( keys_per_block, nb_keys, &analysis, aggregation, &policy, recipient );
         GlobalData {
-            // nb_files:       nb_files,
-            nb_control:     nb_control as f64,
-            nb_case:        nb_case as f64,
-            files:          files.into_boxed_slice(),
+            files:          Vec::with_capacity( nb_files ),
+            analysis:       analysis,
+            nb_control:     0.0,
+            nb_case:        0.0,
+            started:        false,
             buffers:        Mutex::new( Vec::with_capacity( 8 ) ),
             keys_per_block: keys_per_block as usize,
             nb_keys:        nb_keys ,
             prev_key:       KEY_MIN,
-            map:            hashmap::HashMap::new( nb_keys as usize * 34 ),
+            map:            Counts::new( aggregation, nb_keys as usize * 34 ),
             max_len:        0,
             total_key:      0,
             last_key:       KEY_MAX,
-            top_most:       Vec::with_capacity( snp_cnt ),
-            output_allele_freq: output_allele_freq,
+            dict:           Mutex::new( Dict::new() ),
+            top_most:       top_most,
+            privacy:        privacy,
+            policy:         policy,
+            suppressed:     0,
+            collided:       0,
+            nb_af:          0,
+            chisq_out:      chisq_out,
+            af_out:         af_out,
+            statement:      statement,
+            audit:          audit,
+            signed:         false,
         }
     }
     
-    pub fn register(&mut self, fid:  usize, kind: Kind) {
-        self.files[fid as usize] = Vcf::new( kind );
+    // The phenotype of the file is taken from its header, never from the host.
+    pub fn register(&mut self, fid:  usize, header: &shared::FileHeader) {
+        assert!( !self.started, "file {}: registered after the analysis started", fid );
+        assert_eq!( fid, self.files.len(), "file {}: not registered in order", fid );
+        if let Err(why) = header.check() {
+            panic!("file {}: {}", fid, why);
+        }
+        let file_key = match ::keys::file_key( header ) {
+            Some(key) => key,
+            None      => panic!("file {}: data key not wrapped to this enclave", fid),
+        };
+        assert!( ::verify_header( header, &file_key ), "file {}: invalid header MAC", fid );
+        // The data owner may not allow this analysis.
+        if let Err(why) = header.usage().allows( &self.analysis ) {
+            panic!("file {}: {}", fid, why);
+        }
+        assert!( header.keys_per_block() as usize <= self.keys_per_block,
+                 "file {}: {} keys per block, {} expected", fid, header.keys_per_block(), self.keys_per_block );
+        assert!( self.files.iter().all(|vcf| vcf.file_id != *header.file_id() ),
+                 "file {}: registered twice", fid );
+
+        let kind = match header.phenotype().kind() {
+            Some(kind) => kind,
+            None       => panic!("file {}: excluded sample", fid),
+        };
+        match kind {
+            Kind::Control => self.nb_control += 1.0,
+            Kind::Case    => self.nb_case += 1.0,
+        }
+        let mut vcf = Vcf::new( kind, file_key, header.cipher(), *header.file_id() );
+        vcf.digest.update( shared::as_u8_slice( header ) );
+        self.files.push( vcf );
     }
 
     pub fn begin(&mut self, fid:  usize) -> u32 {
+        // The cohort sizes are known once the files are registered.
+        if !self.started {
+            assert!( self.nb_case >= self.policy.min_cases as f64,
+                     "policy: {} cases, at least {} required", self.nb_case, self.policy.min_cases );
+            assert!( self.nb_control >= self.policy.min_controls as f64,
+                     "policy: {} controls, at least {} required", self.nb_control, self.policy.min_controls );
+            if let Some(ref mut privacy) = self.privacy {
+                privacy.calibrate( self.nb_control * 2.0, self.nb_case * 2.0, self.policy.min_mac );
+            }
+        }
+        self.started = true;
         let key = self.prev_key;
         let vcf = &mut self.files[fid as usize];
 
@@ -110,6 +273,7 @@ impl GlobalData {
         };
 
         vcf.clear();
+        vcf.next_blk = blk_nb;
         
         blk_nb
     }
@@ -118,43 +282,122 @@ impl GlobalData {
         let n = (self.nb_control + self.nb_case) * 2.0;
 
         // Sort SNP before writing allele file. 
-        let mut vec: Vec<(Key,Value)> = self.map.iter().map(|(&k,&v)| (k,v)).collect();
+        let mut vec: Vec<(Key,Value)> = self.map.iter().collect();
         vec.sort_by(|a,b| a.0.cmp(&b.0) );
-    
-        let mut line_cnt = 0;
-        let mut buff = String::new();
-        for &(k,v) in vec.iter() {
-            if line_cnt == 256 {
-                unsafe {
-                    ocall::ocall_append_file(buff.as_ptr() as *const u8, buff.len(), false);
-                };
-                line_cnt = 0;
-                buff.clear();
-            }
-            let af = (v.0+v.1) as f64 / n;
-            writeln!(&mut buff, "{}\t{}", k, af ).unwrap();
-            line_cnt+=1;
+        // The dictionary is not locked while writing: the keys resolve their
+        // names through it, see `resolve`, and its lock is not reentrant.
+        {
+            let dict = self.dict.lock();
+            vec.retain(|&(k,_)| !dict.is_collision( &k ) );
         }
 
-        if buff.is_empty() == false {
-            unsafe {
-                ocall::ocall_append_file(buff.as_ptr() as *const u8, buff.len(), false);
-            };    
+        // This is safe because it is only called with an allele frequency file.
+        let af_out = self.af_out.as_mut().unwrap();
+        for &(k,v) in vec.iter() {
+            // The rows written show anyway which SNPs were suppressed. With
+            // differential privacy, the policy applies to the noisy count.
+            let af = match self.privacy {
+                None => {
+                    if kept( &self.policy, n, &v ) == 0 {
+                        continue;
+                    }
+                    (v.0+v.1) as f64 / n
+                },
+                Some(ref privacy) => match privacy.allele_count( (v.0+v.1) as f64 ) {
+                    Some(count) => {
+                        let mac = if count < n - count { count } else { n - count };
+                        if mac < self.policy.min_mac as f64 {
+                            continue;
+                        }
+                        privacy.allele_freq( count )
+                    },
+                    None => continue,
+                },
+            };
+            self.nb_af += 1;
+            writeln!(af_out, "{}\t{}", k, af ).unwrap();
         }
     }
     
-    fn output_top_snp(&self) {
-        let mut buff = String::new();
-        for &(k,chi2) in self.top_most.iter() {
-            writeln!(&mut buff, "{}\t{:.14}", k, chisquare::chi2df3_sf(chi2) ).unwrap();
+    fn output_top_snp(&mut self) {
+        writeln!(&mut self.chisq_out, "#Top most significant SNPs(sorted)").unwrap();
+        writeln!(&mut self.chisq_out, "#Policy: {}", self.policy ).unwrap();
+        // These counts are exact, they are left out with differential privacy.
+        if self.privacy.is_none() {
+            writeln!(&mut self.chisq_out, "#Suppressed {} SNPs with a minor allele count below {}",
+                     self.suppressed, self.policy.min_mac ).unwrap();
+            writeln!(&mut self.chisq_out, "#Left out {} SNPs whose allele or contig hash collides", self.collided ).unwrap();
         }
-
-        unsafe {
-            ocall::ocall_append_file(buff.as_ptr() as *const u8, buff.len(), true);
+        let tops: Vec<(Key,f64)> = self.top_most.iter().collect();
+        let tops = match self.privacy {
+            Some(ref privacy) => {
+                write!(&mut self.chisq_out, "{}", dp::Spent( privacy, self.nb_af ) ).unwrap();
+                privacy.release( &tops )
+            },
+            None => tops,
         };
+        writeln!(&mut self.chisq_out, "#CHROM\tPOS\tID\tREF\tALT\tp-value").unwrap();
+        for &(k,chi2) in tops.iter() {
+            writeln!(&mut self.chisq_out, "{}\t{}", k, PValue( chisquare::chi2df3_sf(chi2) ) ).unwrap();
+        }
+        let chisq = self.chisq_out.finish();
+        let suppressed = if self.privacy.is_none() { Some(self.suppressed) } else { None };
+        let min_mac = self.policy.min_mac;
+        let allele_freq = self.af_out.as_mut().map(|af_out| {
+            if let Some(suppressed) = suppressed {
+                writeln!(af_out, "#Suppressed {} SNPs with a minor allele count below {}", suppressed, min_mac ).unwrap();
+            }
+            af_out.finish()
+        });
+        self.statement.set_results( &chisq, allele_freq.as_ref() );
+    }
+
+
+    // Commit to the files read and sign the statement, see `shared::signature`,
+    // then append it to the audit log, see `shared::audit`.
+    fn sign(&mut self) {
+        let mut hashes: Vec<[u8;32]> = Vec::with_capacity( self.files.len() );
+        for (fid, vcf) in self.files.iter().enumerate() {
+            match vcf.hash {
+                Some(hash) => hashes.push( hash ),
+                None       => panic!("file {}: footer not read", fid),
+            }
+        }
+        hashes.sort();
+        let mut inputs = Sha256::new();
+        inputs.update( &signature::INPUTS_LABEL );
+        for hash in hashes.iter() {
+            inputs.update( hash );
+        }
+        self.statement.set_inputs( self.files.len() as u32, &inputs.finish() );
+        let sig = keys::sign( self.statement.signed() );
+        self.statement.set_signature( &sig );
+
+        // The counter counts this record before it leaves the enclave, its
+        // number is never signed again.
+        assert!( pse::increment_counter( keys::counter() ).map_or( true, |c| c == self.audit.seq() + 1 ),
+                 "audit log: {}", shared::Error::AuditCounter );
+        self.audit.set_statement( &self.statement );
+        let sig = keys::sign( self.audit.signed() );
+        self.audit.set_signature( &sig );
+        let record = self.audit.as_slice();
+        unsafe { ocall::ocall_append_audit( record.as_ptr(), record.len() ) };
+        self.signed = true;
+    }
+
+    // The signed statement, once the analysis is over.
+    pub fn statement(&self) -> &SignedResults {
+        assert!( self.signed, "analysis not over" );
+        &self.statement
     }
 
     pub fn end(&mut self) -> bool {
+        // A file the host stopped sending early is truncated.
+        if let Some(fid) = self.files.iter().position(|vcf| !vcf.done ) {
+            panic!("file {}: truncated", fid);
+        }
+
+        self.map.merge();
         self.max_len = cmp::max( self.max_len, self.map.len() );
     
         // Compute chisquare.
@@ -162,30 +405,22 @@ impl GlobalData {
         let n2 = self.nb_case * 2.0;
         let n = n1+n2;
 
-        for (&k,v) in self.map.iter() {
-            let chi2 = chisquare::chisquare_stat(n, n1, n2, v.0 as f64, v.1 as f64);
-
-            if self.top_most.len() < self.top_most.capacity() {
-                self.top_most.push( (k,chi2) );
-                if self.top_most.len() == self.top_most.capacity() {
-                    self.top_most.sort_by(|a,b| a.1.partial_cmp(&b.1).unwrap().reverse() );
-                }
-            } else {
-                if self.top_most.last().unwrap().1 < chi2 {
-                    let _ = self.top_most.pop();
-                    let pos =
-                        match self.top_most.binary_search_by( |&(_,ch)| ch.partial_cmp(&chi2).unwrap().reverse() ) {
-                            Ok(pos) => pos+1,
-                            Err(pos) => pos,
-                        };
-                    self.top_most.insert(pos, (k,chi2) );
-                }
+        {
+            let dict = self.dict.lock();
+            for (k,v) in self.map.iter() {
+                let chi2 = chisquare::chisquare_stat(n, n1, n2, v.0 as f64, v.1 as f64);
+                // The counts of a collision mix several variants.
+                let collided = dict.is_collision( &k ) as u32;
+                let kept = kept( &self.policy, n, &v );
+                self.suppressed += (kept ^ 1) as usize;
+                self.collided += (collided & kept) as usize;
+                self.top_most.insert( k, chi2, kept & (collided ^ 1) );
             }
         }
 
         self.total_key += self.map.len();
     
-        if self.output_allele_freq {
+        if self.af_out.is_some() {
             self.comp_and_output_af();
         }
 
@@ -194,70 +429,148 @@ impl GlobalData {
             self.last_key = KEY_MAX;
             // clear the list of the key.
             self.map.clear();
+            // keep only the names needed for the final output.
+            let keys: Vec<Key> = self.top_most.iter().map(|(k,_)| k).collect();
+            self.dict.lock().retain( &keys );
             true
         } else {
-            println!("found {} keys", self.total_key );
+            info!("found {} keys", self.total_key );
+            info!("policy: suppressed {} SNPs with a minor allele count below {}", self.suppressed, self.policy.min_mac );
             self.output_top_snp();
+            self.sign();
             false
         }
     }
     
-    fn acquire_buffer(&mut self) -> Box<[Key]> {
+    pub fn resolve(&mut self, key: &Key, name: Name, f: &mut fmt::Formatter) -> fmt::Result {
+        self.dict.lock().write( key, name, f )
+    }
+    
+    fn acquire_buffer(&mut self) -> Vec<Key> {
         match self.buffers.lock().pop() {
             Some(buf) => buf,
             None      => {
                 let mut vec = Vec::with_capacity( self.keys_per_block );
                 unsafe { vec.set_len( self.keys_per_block ); }
-                vec.into_boxed_slice()
+                vec
             },
         }
     }
 
-    fn release_buffer(&mut self, buf: Box<[Key]>) {
+    fn release_buffer(&mut self, buf: Vec<Key>) {
         self.buffers.lock().push( buf );
     }
     
     pub fn run(&mut self, fid: usize, blk_nb: u32, buf: *const u8, len: usize) -> u32 {
+        // The block as the host sent it, in enclave memory.
+        let block = unsafe { slice::from_raw_parts( buf, len ) };
         let mut buffer = self.acquire_buffer();
         let mut end_flag = false;
 
+        // Blocks come in order from the one `begin` asked for.
+        assert_eq!( blk_nb, self.files[fid].next_blk, "file {}: block {} out of order", fid, blk_nb );
+
         // Decrypt the block.
-        let nb_keys = ::decode( buf, len, shared::as_u8_slice_mut( &mut buffer[..] ) );
-        assert_ne!( nb_keys, 0 );
+        let (nb_keys, side_size) = ::decode( buf, len, self.files[fid].cipher, &self.files[fid].file_key, &self.files[fid].file_id, blk_nb, &mut buffer );
+
+        if nb_keys == 0 {
+            let footer = &shared::as_u8_slice( &buffer[..] )[..side_size];
+            let vcf = &mut self.files[fid];
+            match shared::read_footer( footer ) {
+                Ok((keys, blocks)) => assert!( keys == vcf.nb_keys && blocks == vcf.nb_blocks && blocks + 1 == blk_nb,
+                                               "file {}: footer does not match its blocks", fid ),
+                Err(why) => panic!("file {}: {}", fid, why),
+            }
+            if vcf.hash.is_none() {
+                vcf.digest.update( block );
+                vcf.hash = Some( vcf.digest.finish() );
+            }
+            vcf.done = true;
+            self.release_buffer( buffer );
+            return blk_nb;
+        }
 
         {
-            let blk = &mut buffer[..nb_keys];
+            let (blk, side) = {
+                let (keys, rest) = buffer.split_at( nb_keys );
+                (keys, &shared::as_u8_slice( rest )[..side_size])
+            };
+
+            // Keys must be sorted, see `Ord for Key`.
+            let sorted = blk.windows(2).fold( 1, |sorted, w| sorted & ct::key_lt( &w[0], &w[1] ) );
+            assert!( sorted == 1, "block {} of file {} is not sorted", blk_nb, fid );
+
+            // Register the names of the side table.
+            if !side.is_empty() {
+                let mut dict = self.dict.lock();
+                for rec in shared::side::records( side ) {
+                    match rec {
+                        Ok(rec)  => dict.insert( blk, &rec ),
+                        Err(why) => panic!("{}", why),
+                    }
+                }
+            }
+
             let vcf = &mut self.files[fid as usize];
 
             vcf.blocks.push( BlockInfo { blk_nb: blk_nb, key: blk[0] } );
+            vcf.next_blk = blk_nb + 1;
+            if blk_nb == vcf.nb_blocks + 1 {
+                vcf.digest.update( block );
+                vcf.nb_blocks = blk_nb;
+                vcf.nb_keys += nb_keys as u64;
+            }
     
-            let pos = match blk.binary_search( &self.prev_key ) {
-                Ok(pos)  => pos+1,
-                Err(pos) => pos,
+            // With the oblivious aggregation, an entry is appended for each key
+            // of the block, a dummy for the ones not counted.
+            let mut entries = match self.map {
+                Counts::Hashmap(_)   => Vec::new(),
+                Counts::Oblivious(_) => Vec::with_capacity( blk.len() ),
             };
 
-            for &key in &blk[pos..] {
-
-                let cnt = match key.typ() {
-                    Typ::Heterozygous => 1, 
-                    Typ::Homozygous   => 2,
-                };
-
-                if key <= self.last_key {
-                    vcf.key_count += 1;
+            // Every key of the block is looked at, without early exit: the
+            // ones up to the previous iteration, the padding keys and the ones
+            // past the end of this iteration are not counted.
+            let mut ended = 0;
+            for &key in blk.iter() {
+                let active = ct::key_lt( &self.prev_key, &key ) & (key.is_padding() as u32 ^ 1) & (ended ^ 1);
+                let counted = active & (ct::key_lt( &self.last_key, &key ) ^ 1);
+                ended |= active & (counted ^ 1);
+                vcf.key_count += counted;
+
+                // 1 for heterozygous, 2 for homozygous.
+                let cnt = counted * (key.typ() as u32 + 1);
+                match self.map {
+                    // The hashmap probes memory at the key anyway.
+                    Counts::Hashmap(ref mut map) => {
+                        if counted != 0 {
+                            let mut v = map.insert( key );
+                            v.update( vcf.kind, cnt );
+                        }
+                    },
+                    Counts::Oblivious(_) => {
+                        let mut v = Value::default();
+                        v.update( vcf.kind, cnt );
+                        let mut entry = Entry::new( key, v );
+                        ct::cmove( &mut entry, &Entry::dummy(), counted ^ 1 );
+                        entries.push( entry );
+                    },
+                }
 
-                    let mut v = self.map.insert( key );
-                    v.update( vcf.kind, cnt );
-            
-                    if vcf.key_count == self.nb_keys && self.last_key == KEY_MAX {
-                        self.last_key = key;
-                        end_flag = true ;
-                        break
-                    }
-                } else {
-                    end_flag = true ;
-                    break;
+                // The first file to count its keys of the iteration ends it for
+                // all. This is taken once per iteration, which the block
+                // returned tells the host anyway.
+                let last = counted & ct::eq( vcf.key_count as u64, self.nb_keys as u64 ) & ct::key_eq( &self.last_key, &KEY_MAX );
+                if last != 0 {
+                    self.last_key = key;
                 }
+                ended |= last;
+            }
+            end_flag = ended != 0;
+            vcf.done = end_flag;
+
+            if let Counts::Oblivious(ref mut obl) = self.map {
+                obl.lock().append( &entries );
             }
         }
     
@@ -0,0 +1,297 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Keypair of the enclave, the data keys of the .ce files are wrapped to it
+// (see `shared::provision`). It is sealed to MRENCLAVE, so only this enclave
+// can load it again.
+//
+// A second keypair, sealed with it, signs the results (see
+// `shared::signature`). The results are encrypted to the researcher with an
+// ephemeral keypair, see `results`.
+//
+// The monotonic counter of the audit log is created with the keypairs and
+// sealed with them, zeros without PSE, see `shared::audit` and `pse`.
+//
+// Files from several data owners come under different data keys, they are
+// unwrapped once and kept by ID to derive the key of each file.
+
+use sgx_types::*;
+use sgx_tcrypto::*;
+use sgx_tse::rsgx_create_report;
+use sgx_tseal::SgxSealedData;
+
+use alloc::vec::Vec;
+
+use shared;
+use shared::cipher;
+use shared::kdf;
+use shared::provision::{self,EnclaveKey,WrappedKey,PUBLIC_KEY_SIZE};
+use shared::signature::SIGNATURE_SIZE;
+
+use crypto::aes256gcm;
+use pse;
+
+
+struct KeyPair {
+    private:         sgx_ec256_private_t,
+    public:          sgx_ec256_public_t,
+    signing_private: sgx_ec256_private_t,
+    signing_public:  sgx_ec256_public_t,
+    counter:         [u8;pse::COUNTER_SIZE],
+}
+
+static mut KEY_PAIR: Option<KeyPair> = None;
+
+// Data keys unwrapped so far, by ID.
+static mut DATA_KEYS: Option<Vec<([u8;kdf::KEY_ID_SIZE],[u8;kdf::DATA_KEY_SIZE])>> = None;
+
+fn key_pair() -> &'static KeyPair {
+    match unsafe { KEY_PAIR.as_ref() } {
+        Some(pair) => pair,
+        None       => panic!("enclave key not loaded"),
+    }
+}
+
+
+// The SGX SDK takes the coordinates in little endian.
+fn to_sgx( public: &[u8;PUBLIC_KEY_SIZE] ) -> sgx_ec256_public_t {
+    let mut key = sgx_ec256_public_t::default();
+    for i in 0..32 {
+        key.gx[i] = public[31-i];
+        key.gy[i] = public[63-i];
+    }
+    key
+}
+
+fn from_sgx( key: &sgx_ec256_public_t ) -> [u8;PUBLIC_KEY_SIZE] {
+    let mut public = [0u8;PUBLIC_KEY_SIZE];
+    for i in 0..32 {
+        public[31-i] = key.gx[i];
+        public[63-i] = key.gy[i];
+    }
+    public
+}
+
+
+// The private key then the public key, as the SDK has them, of both keypairs,
+// then the counter.
+const SEALED_TEXT_SIZE: usize = 2 * (32 + PUBLIC_KEY_SIZE) + pse::COUNTER_SIZE;
+
+pub fn sealed_size() -> usize {
+    SgxSealedData::<[u8]>::calc_raw_sealed_data_size( 0, SEALED_TEXT_SIZE as u32 ) as usize
+}
+
+
+// Generate a keypair, seal it in `sealed` and write its public key in `key`,
+// with a report bound to it for `target`, the quoting enclave. The host has it
+// signed into the quote of `key`.
+pub fn generate( sealed: &mut [u8], target: &sgx_target_info_t, report: &mut sgx_report_t, key: &mut EnclaveKey ) {
+    assert!( sealed.len() >= sealed_size(), "sealed key buffer too small" );
+    let ecc = SgxEccHandle::new();
+    let pairs = ecc.open()
+        .and_then(|_| ecc.create_key_pair() )
+        .and_then(|pair| ecc.create_key_pair().map(|signing| (pair, signing) ) );
+    let _ = ecc.close();
+    let pair = match pairs {
+        Ok(((private, public), (signing_private, signing_public))) =>
+            KeyPair { private: private, public: public, signing_private: signing_private, signing_public: signing_public,
+                      counter: pse::create_counter() },
+        Err(err) => panic!("key pair generation: {:?}", err),
+    };
+
+    let mut text = [0u8;SEALED_TEXT_SIZE];
+    text[..32].copy_from_slice( &pair.private.r );
+    text[32..64].copy_from_slice( &pair.public.gx );
+    text[64..96].copy_from_slice( &pair.public.gy );
+    text[96..128].copy_from_slice( &pair.signing_private.r );
+    text[128..160].copy_from_slice( &pair.signing_public.gx );
+    text[160..192].copy_from_slice( &pair.signing_public.gy );
+    text[192..].copy_from_slice( &pair.counter );
+    let attribute_mask = sgx_attributes_t { flags: TSEAL_DEFAULT_FLAGSMASK, xfrm: 0 };
+    let data = match SgxSealedData::<[u8]>::seal_data_ex( SGX_KEYPOLICY_MRENCLAVE, attribute_mask, TSEAL_DEFAULT_MISCMASK,
+                                                          &[], &text[..] ) {
+        Ok(data) => data,
+        Err(err) => panic!("key sealing: {:?}", err),
+    };
+    let res = unsafe { data.to_raw_sealed_data_t( sealed.as_mut_ptr() as *mut sgx_sealed_data_t, sealed.len() as u32 ) };
+    assert!( res.is_some(), "key sealing failed" );
+
+    // The report data is the hash of the public key, then of the signing key.
+    let public = from_sgx( &pair.public );
+    let signing = from_sgx( &pair.signing_public );
+    let mut report_data = sgx_report_data_t::default();
+    report_data.d[..32].copy_from_slice( &sha256( &public[..] ) );
+    report_data.d[32..].copy_from_slice( &sha256( &signing[..] ) );
+    *report = match rsgx_create_report( target, &report_data ) {
+        Ok(report) => report,
+        Err(err)   => panic!("rsgx_create_report return {:?}", err),
+    };
+    *key = EnclaveKey::new( &public, &signing );
+    unsafe { KEY_PAIR = Some( pair ); }
+}
+
+
+// Load the keypair sealed by `generate`.
+pub fn load( sealed: &mut [u8] ) {
+    let data = unsafe { SgxSealedData::<[u8]>::from_raw_sealed_data_t( sealed.as_mut_ptr() as *mut sgx_sealed_data_t,
+                                                                        sealed.len() as u32 ) };
+    let data = match data {
+        Some(data) => data,
+        None       => panic!("invalid sealed key"),
+    };
+    let unsealed = match data.unseal_data() {
+        Ok(unsealed) => unsealed,
+        Err(err)     => panic!("key unsealing: {:?}", err),
+    };
+    let text = unsealed.get_decrypt_txt();
+    assert_eq!( text.len(), SEALED_TEXT_SIZE, "invalid sealed key" );
+    let mut pair = KeyPair { private: sgx_ec256_private_t::default(), public: sgx_ec256_public_t::default(),
+                             signing_private: sgx_ec256_private_t::default(), signing_public: sgx_ec256_public_t::default(),
+                             counter: [0u8;pse::COUNTER_SIZE] };
+    pair.private.r.copy_from_slice( &text[..32] );
+    pair.public.gx.copy_from_slice( &text[32..64] );
+    pair.public.gy.copy_from_slice( &text[64..96] );
+    pair.signing_private.r.copy_from_slice( &text[96..128] );
+    pair.signing_public.gx.copy_from_slice( &text[128..160] );
+    pair.signing_public.gy.copy_from_slice( &text[160..192] );
+    pair.counter.copy_from_slice( &text[192..] );
+    unsafe { KEY_PAIR = Some( pair ); }
+}
+
+
+// The monotonic counter of the audit log.
+pub fn counter() -> &'static [u8;pse::COUNTER_SIZE] {
+    &key_pair().counter
+}
+
+
+pub fn sha256( data: &[u8] ) -> [u8;32] {
+    match rsgx_sha256_slice( data ) {
+        Ok(hash) => hash,
+        Err(err) => panic!("rsgx_sha256_slice return {:?}", err),
+    }
+}
+
+
+// ECDSA signature of `data` with the signing key, r then s in big endian.
+pub fn sign( data: &[u8] ) -> [u8;SIGNATURE_SIZE] {
+    let pair = key_pair();
+    let ecc = SgxEccHandle::new();
+    let res = ecc.open().and_then(|_| ecc.ecdsa_sign_slice( data, &pair.signing_private ) );
+    let _ = ecc.close();
+    let sig = match res {
+        Ok(sig)  => sig,
+        Err(err) => panic!("ecdsa_sign_slice return {:?}", err),
+    };
+    // The SDK gives little endian 32-bit words.
+    let mut signature = [0u8;SIGNATURE_SIZE];
+    for i in 0..8 {
+        for j in 0..4 {
+            signature[4*i+j] = (sig.x[7-i] >> (8*(3-j))) as u8;
+            signature[32+4*i+j] = (sig.y[7-i] >> (8*(3-j))) as u8;
+        }
+    }
+    signature
+}
+
+
+// Whether `signature`, as `sign` writes it, is one of `data` by the signing
+// key.
+pub fn verify( data: &[u8], signature: &[u8;SIGNATURE_SIZE] ) -> bool {
+    let pair = key_pair();
+    let mut sig = sgx_ec256_signature_t::default();
+    for i in 0..8 {
+        for j in 0..4 {
+            sig.x[7-i] |= (signature[4*i+j] as u32) << (8*(3-j));
+            sig.y[7-i] |= (signature[32+4*i+j] as u32) << (8*(3-j));
+        }
+    }
+    let ecc = SgxEccHandle::new();
+    let res = ecc.open().and_then(|_| ecc.ecdsa_verify_slice( data, &pair.signing_public, &sig ) );
+    let _ = ecc.close();
+    match res {
+        Ok(valid) => valid,
+        Err(err)  => panic!("ecdsa_verify_slice return {:?}", err),
+    }
+}
+
+
+// Ephemeral ECDH with a public key, returns the ephemeral public key and the
+// shared secret in big endian.
+pub fn ephemeral_dh( peer: &[u8;PUBLIC_KEY_SIZE] ) -> ([u8;PUBLIC_KEY_SIZE],[u8;provision::SECRET_SIZE]) {
+    let ecc = SgxEccHandle::new();
+    let shared = ecc.open()
+        .and_then(|_| ecc.create_key_pair() )
+        .and_then(|(private, public)| ecc.compute_shared_dhkey( &private, &to_sgx( peer ) ).map(|s| (public, s) ) );
+    let _ = ecc.close();
+    let (public, mut secret) = match shared {
+        Ok((public, shared)) => (from_sgx( &public ), shared.s),
+        Err(err)             => panic!("ephemeral key exchange: {:?}", err),
+    };
+    secret.reverse();
+    (public, secret)
+}
+
+
+// Key of a file, None if its data key is not wrapped to this enclave or does
+// not match the ID of the header.
+pub fn file_key( header: &shared::FileHeader ) -> Option<[u8;cipher::KEY_SIZE_MAX]> {
+    let data_keys = unsafe { DATA_KEYS.get_or_insert_with( Vec::new ) };
+    let data_key = match data_keys.iter().find(|k| k.0 == *header.key_id() ) {
+        Some(&(_, key)) => key,
+        None => {
+            let key = match unwrap( header.wrapped_key() ) {
+                Some(key) => key,
+                None      => return None,
+            };
+            if kdf::key_id( &sha256, &key ) != *header.key_id() {
+                return None;
+            }
+            data_keys.push( (*header.key_id(), key) );
+            key
+        },
+    };
+    Some( kdf::file_key( &sha256, &data_key, header.file_id(), header.cipher() ) )
+}
+
+
+// Unwrap a data key, None if it is not wrapped to this enclave.
+fn unwrap( wrapped: &WrappedKey ) -> Option<[u8;kdf::DATA_KEY_SIZE]> {
+    let pair = key_pair();
+    let ecc = SgxEccHandle::new();
+    let shared = ecc.open().and_then(|_| ecc.compute_shared_dhkey( &pair.private, &to_sgx( wrapped.public() ) ) );
+    let _ = ecc.close();
+    let mut secret = match shared {
+        Ok(shared) => shared.s,
+        Err(_)     => return None,
+    };
+    secret.reverse();
+
+    let input = provision::kek_input( &secret, wrapped.public(), &from_sgx( &pair.public ) );
+    let kek = sha256( &input[..] );
+    let mut key = [0u8;kdf::DATA_KEY_SIZE];
+    if aes256gcm::decrypt( &kek, wrapped.key(), wrapped.iv(), &[], wrapped.mac(), &mut key ) {
+        Some(key)
+    } else {
+        None
+    }
+}
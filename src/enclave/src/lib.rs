@@ -25,34 +25,52 @@
 #![feature(integer_atomics)]
 #![feature(lang_items)]
 #![feature(global_allocator)]
+#![feature(stdsimd)]
+#![feature(target_feature)]
 
 
 extern crate sgx_types;
 extern crate sgx_tcrypto;
 extern crate sgx_trts;
+extern crate sgx_tse;
+extern crate sgx_tseal;
 extern crate sgx_alloc;
-use sgx_tcrypto::*;
 
 
 
 extern crate alloc;
 
 extern crate shared;
+extern crate crypto;
 
 
 use core::mem;
 use core::slice;
 use core::fmt;
 
+use alloc::vec::Vec;
+
+use shared::cipher::{self,Cipher};
+
 #[macro_use]
 mod console;
 mod ocall;
 mod types;
 mod chisquare;
+mod ct;
+mod topk;
+mod dp;
+mod keys;
+mod pse;
+mod digest;
+mod results;
+mod aead;
 
 
 mod spin;
+mod dict;
 mod hashmap;
+mod oblivious;
 mod imp_hashmap;
 use imp_hashmap as imp;
 
@@ -73,33 +91,131 @@ fn data() -> &'static mut imp::GlobalData {
 #[lang="panic_fmt"]
 #[no_mangle]
 pub extern fn enclave_panic(msg: fmt::Arguments, file: &'static str, line: u32) -> ! {
-    println!("{}:{}: {}", file, line, msg);
+    error!("{}:{}: {}", file, line, msg);
     sgx_trts::trts::rsgx_abort();
 }
 
 
 
+// Generate the keypair of the enclave, returns the size of the sealed key.
+// The report is for the quoting enclave of `target`.
 #[no_mangle]
-pub extern "C" fn encl_init(nb_control:     u32,
-                            nb_case:        u32,
+pub extern "C" fn encl_keygen(sealed: *mut u8, sealed_len: u32,
+                              target: *const u8, target_len: usize,
+                              report: *mut u8, report_len: usize,
+                              key: *mut u8, key_len: usize) -> u32 {
+    assert_eq!( key_len, mem::size_of::<shared::provision::EnclaveKey>(), "enclave key size mismatch" );
+    assert_eq!( target_len, mem::size_of::<sgx_types::sgx_target_info_t>(), "target info size mismatch" );
+    assert_eq!( report_len, mem::size_of::<sgx_types::sgx_report_t>(), "report size mismatch" );
+    let sealed = unsafe { slice::from_raw_parts_mut( sealed, sealed_len as usize ) };
+    let target = unsafe { &*(target as *const sgx_types::sgx_target_info_t) };
+    let report = unsafe { &mut *(report as *mut sgx_types::sgx_report_t) };
+    let key = unsafe { &mut *(key as *mut shared::provision::EnclaveKey) };
+    keys::generate( sealed, target, report, key );
+    keys::sealed_size() as u32
+}
+
+
+#[no_mangle]
+pub extern "C" fn encl_load_key(sealed: *const u8, len: u32) {
+    let mut sealed = unsafe { slice::from_raw_parts( sealed, len as usize ) }.to_vec();
+    keys::load( &mut sealed );
+}
+
+
+#[no_mangle]
+pub extern "C" fn encl_init(nb_files:       u32,
                             keys_per_block: u32,
                             nb_keys:        u32,
                             snp_cnt:        usize,
-                            output_allele_freq: bool ) {
+                            output_allele_freq: bool,
+                            aggregation:    u8,
+                            epsilon:        f64,
+                            delta:          f64,
+                            af_epsilon:     f64,
+                            min_cases:      u32,
+                            min_controls:   u32,
+                            min_mac:        u32,
+                            max_k:          u32,
+                            study:          *const u8,
+                            study_len:      usize,
+                            timestamp:      u64,
+                            reset_time:     bool,
+                            audit:          *const u8,
+                            audit_len:      usize,
+                            recipient:      *const u8,
+                            recipient_len:  usize ) {
+    // The results are encrypted to this key.
+    assert_eq!( recipient_len, shared::provision::PUBLIC_KEY_SIZE, "recipient key size mismatch" );
+    let recipient = unsafe { &*(recipient as *const [u8;shared::provision::PUBLIC_KEY_SIZE]) };
+    let aggregation = match shared::Aggregation::from_u8( aggregation ) {
+        Some(aggregation) => aggregation,
+        None              => panic!("{}", shared::Error::UnknownAggregation),
+    };
+    // The record of this analysis follows the last one of the audit log, the
+    // one the counter stands at, if any.
+    let (trusted_time, time_source) = pse::trusted_time();
+    let count = pse::read_counter( keys::counter() );
+    let audit = if audit_len == 0 {
+        assert!( count.map_or( true, |c| c == 0 ), "audit log: {}", shared::Error::AuditCounter );
+        shared::audit::AuditRecord::first( timestamp, trusted_time, &time_source )
+    } else {
+        assert_eq!( audit_len, shared::audit::AUDIT_SIZE, "audit record size mismatch" );
+        let mut last = shared::audit::AuditRecord::zeroed();
+        shared::as_u8_slice_mut( &mut last ).copy_from_slice( unsafe { slice::from_raw_parts( audit, audit_len ) } );
+        if let Err(why) = last.check() {
+            panic!("audit log: {}", why);
+        }
+        assert!( keys::verify( last.signed(), last.signature() ), "audit log: invalid signature of the last record" );
+        assert!( count.map_or( true, |c| last.seq() + 1 == c ), "audit log: {}", shared::Error::AuditCounter );
+        match last.next( &keys::sha256( last.as_slice() ), timestamp, trusted_time, &time_source, reset_time ) {
+            Ok(record) => record,
+            Err(why)   => panic!("audit log: {}", why),
+        }
+    };
+    // The usage of each file is checked against it, see `encl_register`, at
+    // the day of the audit record once checked against the trusted time.
+    assert_eq!( study_len, shared::policy::STUDY_SIZE, "study identifier size mismatch" );
+    let study = unsafe { &*(study as *const [u8;shared::policy::STUDY_SIZE]) };
+    let analysis = shared::policy::Analysis { output_allele_freq: output_allele_freq,
+                                              snp_cnt:            snp_cnt as u64,
+                                              study:              *study,
+                                              date:               (audit.timestamp() / 86400) as u32 };
+    let policy = shared::policy::Policy { min_cases: min_cases, min_controls: min_controls, min_mac: min_mac, max_k: max_k };
+    assert!( snp_cnt <= max_k as usize, "policy: top {} SNPs, {} at most", snp_cnt, max_k );
+    // Differential privacy when epsilon is given.
+    let privacy = if epsilon != 0.0 {
+        Some( dp::Privacy::new( epsilon, delta, af_epsilon, snp_cnt ) )
+    } else {
+        None
+    };
     // Init
     unsafe {
-        DATA = Some( imp::GlobalData::new( nb_control as usize,
-                                           nb_case as usize,
+        DATA = Some( imp::GlobalData::new( nb_files as usize,
                                            keys_per_block,
                                            nb_keys,
-                                           snp_cnt,
-                                           output_allele_freq ) );
+                                           analysis,
+                                           aggregation,
+                                           privacy,
+                                           policy,
+                                           audit,
+                                           recipient ) );
     }
+    shared::set_resolver( resolve );
+}
+
+
+// Print the names carried by the side tables.
+fn resolve(key: &shared::Key, name: shared::Name, f: &mut fmt::Formatter) -> fmt::Result {
+    data().resolve( key, name, f )
 }
 
 #[no_mangle]
-pub extern "C" fn encl_register(fid:  u32, kind: shared::Kind ) {
-    data().register( fid as usize, kind )
+pub extern "C" fn encl_register(fid: u32, hdr: *const u8, len: usize ) {
+    assert_eq!( len, mem::size_of::<shared::FileHeader>(), "file header size mismatch" );
+    let mut header = shared::FileHeader::zeroed();
+    shared::as_u8_slice_mut( &mut header ).copy_from_slice( unsafe { slice::from_raw_parts( hdr, len ) } );
+    data().register( fid as usize, &header )
 }
 
 
@@ -116,6 +232,15 @@ pub extern "C" fn encl_end() -> bool {
 }
 
 
+// The results signed by the enclave, once `encl_end` returned false.
+#[no_mangle]
+pub extern "C" fn encl_sign(statement: *mut u8, len: usize) {
+    assert_eq!( len, mem::size_of::<shared::signature::SignedResults>(), "signed results size mismatch" );
+    let out = unsafe { slice::from_raw_parts_mut( statement, len ) };
+    out.copy_from_slice( data().statement().as_slice() );
+}
+
+
 #[no_mangle]
 pub extern "C" fn encl_run(fid: u32, blk_nb: u32, buf: *const u8, len: usize) -> u32 {
     data().run( fid as usize, blk_nb, buf, len )    
@@ -123,9 +248,24 @@ pub extern "C" fn encl_run(fid: u32, blk_nb: u32, buf: *const u8, len: usize) ->
 
 
 
-pub fn decode<'a>( buf: *const u8,
-                   len: usize,
-                   out: &'a mut [u8]) -> usize {
+// Check the MAC of a file header with its file key, the phenotype and the
+// sample come from the host and are only trusted once it matches.
+pub fn verify_header( header: &shared::FileHeader, key: &[u8;cipher::KEY_SIZE_MAX] ) -> bool {
+    aead::decrypt( header.cipher(), key, &[], header.iv(), header.authenticated(), header.mac(), &mut [] )
+}
+
+
+// Decrypt a block in `out`, returns the number of keys and the size of the
+// side table that follows them.
+// The block is authenticated with the ID of its file and the number the
+// enclave expects, see `shared::block_aad`.
+pub fn decode( buf: *const u8,
+               len: usize,
+               cipher: Cipher,
+               key: &[u8;cipher::KEY_SIZE_MAX],
+               file_id: &[u8;16],
+               blk_nb: u32,
+               out: &mut Vec<shared::Key> ) -> (usize,usize) {
 
     let (hdr,blk) = unsafe {
         let hdr = &*(buf as *const shared::Header);
@@ -135,14 +275,29 @@ pub fn decode<'a>( buf: *const u8,
     };
 
     assert_eq!(blk.len()+mem::size_of::<shared::Header>(), len, "buffer size mismatch");
+    let (nb_keys, side_size) = match (hdr.nb_keys(), hdr.side_size()) {
+        (Ok(nb_keys), Ok(side_size)) => (nb_keys, side_size),
+        _ => panic!("block {}: invalid header", blk_nb),
+    };
+
+    // Make room for the keys and the side table.
+    let key_size = mem::size_of::<shared::Key>();
+    let cap = (blk.len() + key_size - 1) / key_size;
+    if out.len() < cap {
+        let additional = cap - out.len();
+        out.reserve( additional );
+        unsafe { out.set_len( cap ); }
+    }
 
     // Decode de buffer.
-    let res = rsgx_rijndael128GCM_decrypt( &shared::AES_KEY, blk, hdr.iv(), &[], hdr.mac(),  out );
-    if let Err(err) = res {
-        panic!("rsgx_rijndael128GCM_decrypt return {:?}", err );
+    let aad = shared::block_aad( file_id, blk_nb, nb_keys );
+    let ok = aead::decrypt( cipher, key, blk, hdr.iv(), &aad, hdr.mac(),
+                            &mut shared::as_u8_slice_mut( &mut out[..] )[..blk.len()] );
+    if !ok {
+        panic!("block {}: {} decryption failed", blk_nb, cipher );
     }
 
-    hdr.nb_keys() as usize
+    (nb_keys as usize, side_size as usize)
 }
 
 
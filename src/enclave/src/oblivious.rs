@@ -0,0 +1,196 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Counts of the keys of an iteration with memory accesses that do not depend
+// on the keys, see `shared::Aggregation`.
+//
+// The buffer has a fixed size, a power of two: its first half holds the keys
+// counted so far, sorted, its second half the entries appended since. Once
+// the second half is full both are sorted together with a bitonic network,
+// equal keys are summed into the last of their run by a linear scan that
+// turns the others into dummies, and a second sort moves the dummies to the
+// end. Every step touches the same addresses whatever the keys, comparisons
+// and swaps are done with masks rather than branches.
+//
+// Each merge sorts the whole buffer twice, `O(n log^2 n)` for `n / 2` new
+// entries where the hashmap does one probe per entry.
+//
+// Only the counting is oblivious. The names of the side tables still go to
+// `Dict` at their key, and the collisions are searched there for each key
+// counted, see `Dict`.
+
+use alloc::vec::Vec;
+use core::mem;
+
+use shared::{Key,KEY_MAX};
+use shared::contig;
+use types::Value;
+use console;
+use ct;
+
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct Entry {
+    key:   Key,
+    pad:   u16,
+    value: Value,
+}
+
+// Sorts after every key, padding keys included.
+const DUMMY: Entry = Entry { key: KEY_MAX, pad: 0, value: Value(0,0) };
+
+impl Entry {
+    pub fn new( key: Key, value: Value ) -> Entry {
+        Entry { key: key, pad: 0, value: value }
+    }
+
+    // Takes the place of a key not counted, so that as many entries are
+    // appended whatever the keys.
+    pub fn dummy() -> Entry {
+        DUMMY
+    }
+
+    fn is_dummy(&self) -> u32 {
+        ct::eq( self.key.contig() as u64, contig::PADDING as u64 )
+    }
+}
+
+
+// Sort in increasing order, the length is a power of two. The pairs compared
+// only depend on the length.
+fn bitonic_sort( buf: &mut [Entry] ) {
+    let n = buf.len();
+    let mut k = 2;
+    while k <= n {
+        let mut j = k / 2;
+        while j > 0 {
+            for i in 0..n {
+                let l = i ^ j;
+                if l > i {
+                    let (lo, hi) = buf.split_at_mut( l );
+                    let (a, b) = (&mut lo[i], &mut hi[0]);
+                    // Increasing where `i & k` is 0, decreasing elsewhere.
+                    let cond = if i & k == 0 { ct::key_lt( &b.key, &a.key ) } else { ct::key_lt( &a.key, &b.key ) };
+                    ct::cswap( a, b, cond );
+                }
+            }
+            j /= 2;
+        }
+        k *= 2;
+    }
+}
+
+
+pub struct Counts {
+    buf:  Vec<Entry>,
+    // Keys counted, at the start of `buf`.
+    len:  usize,
+    // Entries appended to the second half.
+    fill: usize,
+}
+
+impl Counts {
+    // `cap` entries rounded up to a power of two, for at least `cap / 2` keys.
+    pub fn new( cap: usize ) -> Counts {
+        let size = cap.next_power_of_two();
+        debug!("oblivious::Counts::new(cap:{}) => {}ko", cap, size * mem::size_of::<Entry>() / 1024 );
+        let mut buf = Vec::with_capacity( size );
+        for _ in 0..size {
+            buf.push( DUMMY );
+        }
+        Counts { buf: buf, len: 0, fill: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len() / 2
+    }
+
+    // Keys counted, once merged.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn clear(&mut self) {
+        for e in self.buf.iter_mut() {
+            *e = DUMMY;
+        }
+        self.len = 0;
+        self.fill = 0;
+    }
+
+    pub fn append( &mut self, entries: &[Entry] ) {
+        for e in entries {
+            if self.fill == self.capacity() {
+                self.merge();
+            }
+            let pos = self.capacity() + self.fill;
+            self.buf[pos] = *e;
+            self.fill += 1;
+        }
+    }
+
+    // Count the entries appended, they can be read with `iter`.
+    pub fn merge(&mut self) {
+        let cap = self.capacity();
+        for e in self.buf[cap+self.fill..].iter_mut() {
+            *e = DUMMY;
+        }
+        bitonic_sort( &mut self.buf );
+
+        // Sum each run of equal keys into its last entry.
+        let mut dummies = self.buf[0].is_dummy() as usize;
+        for i in 1..self.buf.len() {
+            let (lo, hi) = self.buf.split_at_mut( i );
+            let (prev, cur) = (&mut lo[i-1], &mut hi[0]);
+            let cond = ct::key_eq( &prev.key, &cur.key ) & (cur.is_dummy() ^ 1);
+            let mask = 0u32.wrapping_sub( cond );
+            cur.value.0 = cur.value.0.wrapping_add( prev.value.0 & mask );
+            cur.value.1 = cur.value.1.wrapping_add( prev.value.1 & mask );
+            ct::cmove( prev, &DUMMY, cond );
+            dummies += (cond | cur.is_dummy()) as usize;
+        }
+        bitonic_sort( &mut self.buf );
+
+        let len = self.buf.len() - dummies;
+        assert!( len <= cap, "oblivious::Counts full" );
+        self.len = len;
+        self.fill = 0;
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter { inner: self.buf[..self.len].iter() }
+    }
+}
+
+
+pub struct Iter<'a> {
+    inner: ::core::slice::Iter<'a,Entry>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (Key,Value);
+
+    fn next(&mut self) -> Option<(Key,Value)> {
+        self.inner.next().map(|e| (e.key, e.value) )
+    }
+}
@@ -21,6 +21,7 @@
 
 extern "C" {
     pub fn ocall_append_file(buff_ptr: *const u8, buff_len: usize, chisq_file: bool);
+    pub fn ocall_append_audit(buff_ptr: *const u8, buff_len: usize);
     pub fn ocall_print_string(str: *const u8, len: usize);
 }
 
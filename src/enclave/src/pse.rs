@@ -0,0 +1,127 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Platform services of the audit log, see `shared::audit`: the monotonic
+// counter of its records and the trusted time. Each call opens its own session
+// with the platform service enclave.
+//
+// Only with the `pse` feature (`make SGX_PSE=1`): the PSE is deprecated and
+// missing from recent SDKs on Linux and from simulation mode. Without it there is no counter, it
+// stays at zero in the sealed keypair and reads as `None`, and the trusted
+// time is 0 from `NO_TIME_SOURCE`.
+
+#[cfg(feature = "pse")]
+use sgx_types::*;
+
+#[cfg(not(feature = "pse"))]
+use shared::audit::NO_TIME_SOURCE;
+
+#[cfg(feature = "pse")]
+extern "C" {
+    fn sgx_create_pse_session() -> sgx_status_t;
+    fn sgx_close_pse_session() -> sgx_status_t;
+    fn sgx_create_monotonic_counter(counter_uuid: *mut sgx_mc_uuid_t, counter_value: *mut u32) -> sgx_status_t;
+    fn sgx_read_monotonic_counter(counter_uuid: *const sgx_mc_uuid_t, counter_value: *mut u32) -> sgx_status_t;
+    fn sgx_increment_monotonic_counter(counter_uuid: *const sgx_mc_uuid_t, counter_value: *mut u32) -> sgx_status_t;
+    fn sgx_get_trusted_time(current_time: *mut sgx_time_t, time_source_nonce: *mut sgx_time_source_nonce_t) -> sgx_status_t;
+}
+
+
+pub const COUNTER_SIZE: usize = 16;
+
+#[cfg(feature = "pse")]
+fn session<F: FnOnce() -> sgx_status_t>( what: &str, f: F ) {
+    let res = unsafe { sgx_create_pse_session() };
+    assert!( res == sgx_status_t::SGX_SUCCESS, "platform service session: {:?}", res );
+    let res = f();
+    unsafe { sgx_close_pse_session() };
+    assert!( res == sgx_status_t::SGX_SUCCESS, "{}: {:?}", what, res );
+}
+
+
+// A new counter, at 0, as sealed with the keypair.
+#[cfg(feature = "pse")]
+pub fn create_counter() -> [u8;COUNTER_SIZE] {
+    let mut uuid = sgx_mc_uuid_t::default();
+    let mut value = 0u32;
+    session( "monotonic counter creation", || unsafe { sgx_create_monotonic_counter( &mut uuid, &mut value ) } );
+    assert!( value == 0, "new monotonic counter not at 0" );
+    let mut counter = [0u8;COUNTER_SIZE];
+    counter[..3].copy_from_slice( &uuid.counter_id );
+    counter[3..].copy_from_slice( &uuid.nonce );
+    counter
+}
+
+#[cfg(not(feature = "pse"))]
+pub fn create_counter() -> [u8;COUNTER_SIZE] {
+    [0u8;COUNTER_SIZE]
+}
+
+#[cfg(feature = "pse")]
+fn uuid( counter: &[u8;COUNTER_SIZE] ) -> sgx_mc_uuid_t {
+    let mut uuid = sgx_mc_uuid_t::default();
+    uuid.counter_id.copy_from_slice( &counter[..3] );
+    uuid.nonce.copy_from_slice( &counter[3..] );
+    uuid
+}
+
+#[cfg(feature = "pse")]
+pub fn read_counter( counter: &[u8;COUNTER_SIZE] ) -> Option<u64> {
+    let uuid = uuid( counter );
+    let mut value = 0u32;
+    session( "monotonic counter read", || unsafe { sgx_read_monotonic_counter( &uuid, &mut value ) } );
+    Some( value as u64 )
+}
+
+#[cfg(not(feature = "pse"))]
+pub fn read_counter( _counter: &[u8;COUNTER_SIZE] ) -> Option<u64> {
+    None
+}
+
+// The value once incremented.
+#[cfg(feature = "pse")]
+pub fn increment_counter( counter: &[u8;COUNTER_SIZE] ) -> Option<u64> {
+    let uuid = uuid( counter );
+    let mut value = 0u32;
+    session( "monotonic counter increment", || unsafe { sgx_increment_monotonic_counter( &uuid, &mut value ) } );
+    Some( value as u64 )
+}
+
+#[cfg(not(feature = "pse"))]
+pub fn increment_counter( _counter: &[u8;COUNTER_SIZE] ) -> Option<u64> {
+    None
+}
+
+
+// Seconds from an origin the nonce of the time source stands for.
+#[cfg(feature = "pse")]
+pub fn trusted_time() -> (u64, [u8;32]) {
+    let mut time: sgx_time_t = 0;
+    let mut source: sgx_time_source_nonce_t = [0u8;32];
+    session( "trusted time", || unsafe { sgx_get_trusted_time( &mut time, &mut source ) } );
+    (time, source)
+}
+
+#[cfg(not(feature = "pse"))]
+pub fn trusted_time() -> (u64, [u8;32]) {
+    (0, NO_TIME_SOURCE)
+}
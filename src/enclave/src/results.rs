@@ -0,0 +1,112 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Results files, encrypted for the researcher (see `shared::results`) and
+// appended by the host as they come.
+
+use core::cmp;
+use core::fmt;
+use alloc::vec::Vec;
+
+use sgx_tcrypto::rsgx_rijndael128GCM_encrypt;
+
+use shared;
+use shared::provision::PUBLIC_KEY_SIZE;
+use shared::results::{self,Output,ResultHeader,RECORD_DATA_SIZE,RECORD_DISK_SIZE};
+
+use keys;
+use ocall;
+use digest::Sha256;
+
+
+pub struct Writer {
+    header: ResultHeader,
+    key:    [u8;16],
+    // Data of the next record.
+    buf:    Vec<u8>,
+    nb:     u64,
+    // Of the file as written.
+    digest: Sha256,
+}
+
+impl Writer {
+    // Write the header of a new results file.
+    pub fn new( output: Output, recipient: &[u8;PUBLIC_KEY_SIZE] ) -> Writer {
+        let (ephemeral, secret) = keys::ephemeral_dh( recipient );
+        let mut key = [0u8;16];
+        key.copy_from_slice( &keys::sha256( &results::key_input( &secret, &ephemeral, recipient )[..] )[..16] );
+        let mut writer = Writer { header: ResultHeader::new( output, &ephemeral, recipient ), key: key,
+                                  buf: Vec::with_capacity( RECORD_DATA_SIZE ), nb: 0, digest: Sha256::new() };
+        let header = shared::as_u8_slice( &writer.header ).to_vec();
+        writer.append( &header );
+        writer
+    }
+
+    pub fn write( &mut self, mut data: &[u8] ) {
+        while !data.is_empty() {
+            let n = cmp::min( data.len(), RECORD_DATA_SIZE - self.buf.len() );
+            self.buf.extend_from_slice( &data[..n] );
+            data = &data[n..];
+            // A full record is written once more data comes, the last one
+            // is flagged by `finish`.
+            if !data.is_empty() {
+                self.flush( false );
+            }
+        }
+    }
+
+    // Write the last record, the file is truncated without it. Returns the
+    // SHA-256 of the file.
+    pub fn finish( &mut self ) -> [u8;32] {
+        self.flush( true );
+        self.digest.finish()
+    }
+
+    fn append( &mut self, data: &[u8] ) {
+        self.digest.update( data );
+        unsafe { ocall::ocall_append_file( data.as_ptr(), data.len(), self.header.output() == Output::Chisq ) };
+    }
+
+    fn flush( &mut self, last: bool ) {
+        let rec = results::record( &self.buf );
+        let aad = results::record_aad( &self.header, self.nb, last );
+        let mut out = [0u8;RECORD_DISK_SIZE];
+        let mut mac = [0u8;16];
+        if let Err(err) = rsgx_rijndael128GCM_encrypt( &self.key, &rec[..], &results::record_iv( self.nb ), &aad[..],
+                                                       &mut out[16..], &mut mac ) {
+            panic!("rsgx_rijndael128GCM_encrypt return {:?}", err);
+        }
+        out[..16].copy_from_slice( &mac );
+        self.append( &out[..] );
+        self.buf.clear();
+        self.nb += 1;
+    }
+}
+
+
+impl fmt::Write for Writer {
+    fn write_str( &mut self, s: &str ) -> fmt::Result {
+        self.write( s.as_bytes() );
+        Ok(())
+    }
+}
+
@@ -78,6 +78,12 @@ impl<T: ?Sized> Mutex<T>
             data: unsafe { &mut *self.data.get() },
         }
     }
+
+    // No lock needed, the borrow is exclusive.
+    pub fn get_mut(&mut self) -> &mut T
+    {
+        unsafe { &mut *self.data.get() }
+    }
 }   
 
 
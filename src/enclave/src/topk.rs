@@ -0,0 +1,87 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// The keys of highest chi-square statistic, kept in time and memory accesses
+// that do not depend on the statistics. With differential privacy one more is
+// kept than released, see `dp`.
+//
+// The table always holds `k` entries in decreasing order, empty ones ranking
+// below any key. Each key offered goes through the whole table, swapped with
+// the entries it outranks: `O(k)` per key rather than a binary search, an
+// insertion and a pop.
+
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use core::mem;
+
+use shared::{Key,KEY_MAX};
+use ct;
+
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+struct Top {
+    key:  Key,
+    pad:  u16,
+    chi2: f64,
+    // Bits of the statistic, flipped to order as unsigned integers, 0 when empty.
+    rank: u64,
+}
+
+const EMPTY: Top = Top { key: KEY_MAX, pad: 0, chi2: 0.0, rank: 0 };
+
+
+pub struct TopK {
+    tops: Vec<Top>,
+}
+
+impl TopK {
+    pub fn new( k: usize ) -> TopK {
+        let mut tops = Vec::with_capacity( k );
+        for _ in 0..k {
+            tops.push( EMPTY );
+        }
+        TopK { tops: tops }
+    }
+
+    // A key of the same statistic as another stays after it. A key not `kept`
+    // (0), suppressed by the policy, ranks as an empty entry and never enters.
+    pub fn insert( &mut self, key: Key, chi2: f64, kept: u32 ) {
+        assert!( chi2 == chi2, "NaN statistic" );
+        // The sign bit is set for positive statistics, all bits are flipped
+        // for negative ones. Only NaN could map to 0.
+        let bits: u64 = unsafe { mem::transmute( chi2 ) };
+        let rank = bits ^ ((((bits as i64) >> 63) as u64) | 0x8000_0000_0000_0000);
+        let rank = ct::select( kept, rank, 0 );
+        let mut new = Top { key: key, pad: 0, chi2: chi2, rank: rank };
+        for top in self.tops.iter_mut() {
+            let cond = ct::lt( top.rank, new.rank );
+            ct::cswap( top, &mut new, cond );
+        }
+    }
+
+    // The keys and their statistic in decreasing order, fewer than
+    // `k` if fewer were offered.
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=(Key,f64)> + 'a> {
+        Box::new( self.tops.iter().take_while(|top| top.rank != 0 ).map(|top| (top.key, top.chi2) ) )
+    }
+}
@@ -23,6 +23,9 @@ use core::fmt;
 use alloc::vec::Vec;
 
 use shared::{Kind,Key,KEY_MIN};
+use shared::cipher::{self,Cipher};
+
+use digest::Sha256;
 
 
 #[derive(Clone,Copy,Debug)]
@@ -60,18 +63,36 @@ impl fmt::Debug for BlockInfo {
 pub struct Vcf {
     pub blocks:    Vec<BlockInfo>,
     pub kind:      Kind,
+    // Derived from the data key of the file header.
+    pub file_key:  [u8;cipher::KEY_SIZE_MAX],
+    pub cipher:    Cipher,
+    // Additional data of the blocks.
+    pub file_id:   [u8;16],
     pub last_key:  Key,
     pub key_count: u32,
+    // Block expected from the host.
+    pub next_blk:  u32,
+    // The iteration went past its last key or reached the footer.
+    pub done:      bool,
+    // Blocks and keys seen since the start of the file.
+    pub nb_blocks: u32,
+    pub nb_keys:   u64,
+    // Of the header and the blocks consumed, the SHA-256 of the file once the
+    // footer came.
+    pub digest:    Sha256,
+    pub hash:      Option<[u8;32]>,
 }
 
 impl Vcf {
-    pub fn new(kind: Kind) -> Vcf {
-        Vcf { kind: kind, blocks: Vec::new(), last_key: KEY_MIN, key_count: 0 }
+    pub fn new(kind: Kind, file_key: [u8;cipher::KEY_SIZE_MAX], cipher: Cipher, file_id: [u8;16]) -> Vcf {
+        Vcf { kind: kind, file_key: file_key, cipher: cipher, file_id: file_id, blocks: Vec::new(), last_key: KEY_MIN, key_count: 0,
+              next_blk: 1, done: false, nb_blocks: 0, nb_keys: 0, digest: Sha256::new(), hash: None }
     }
 
     pub fn clear(&mut self) {
         self.blocks.clear();
         self.key_count = 0 ;
+        self.done = false;
     }
 }
 
@@ -0,0 +1,233 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Remote attestation of the enclave key, see `provision`.
+//
+// `app --keygen` has the quoting enclave (QE) sign the report of the enclave
+// into an EPID quote. The quote is sent to the Intel Attestation Service (IAS),
+// whose attestation verification report is a JSON object holding the status
+// of the quote and its body, signed by a certificate of the IAS. `ce` checks
+// the signature and the certificate chain with OpenSSL, then takes the
+// MRENCLAVE and the report data from the body of the report, never from the
+// quote alone.
+//
+// The report is only parsed here: its fields are flat strings, as the IAS
+// writes them.
+//
+// Intel has retired the IAS and EPID attestation, DCAP quotes are not
+// supported. Without the IAS, and in SGX_MODE=SIM where there is no quoting
+// enclave at all, `app --keygen --test_attestation` writes a report of its own
+// whose status is `QUOTE_SIMULATION`, for `test/attest.sh` to sign with a
+// self-signed test root. Only `ce --test_attestation` accepts that status: a
+// test report says nothing of the platform, the key is as trusted as the host
+// that ran `app`.
+
+use Error;
+
+
+// `sgx_quote_t` up to the signature: version, signature type, EPID group,
+// SVNs, basename, then the report body of the enclave.
+pub const QUOTE_BODY_SIZE: usize = 432;
+const REPORT_BODY_OFFSET: usize = 48;
+const ATTRIBUTES_OFFSET: usize = REPORT_BODY_OFFSET + 48;
+const MR_ENCLAVE_OFFSET: usize = REPORT_BODY_OFFSET + 64;
+const REPORT_DATA_OFFSET: usize = REPORT_BODY_OFFSET + 320;
+
+// `SGX_FLAGS_DEBUG`: the host may read the memory of a debug enclave.
+const FLAGS_DEBUG: u8 = 0x02;
+
+// The only status trusted: the platform is up to date and not revoked.
+pub const QUOTE_OK: &str = "OK";
+
+// Status of the test reports, see above.
+pub const QUOTE_SIMULATION: &str = "SIMULATION";
+
+
+// Body of a quote, as the quote or the IAS report has it.
+pub struct QuoteBody<'a>(pub &'a [u8]);
+
+impl<'a> QuoteBody<'a> {
+    pub fn mr_enclave(&self) -> &[u8] {
+        &self.0[MR_ENCLAVE_OFFSET..MR_ENCLAVE_OFFSET+32]
+    }
+
+    pub fn report_data(&self) -> &[u8] {
+        &self.0[REPORT_DATA_OFFSET..REPORT_DATA_OFFSET+64]
+    }
+
+    pub fn is_debug(&self) -> bool {
+        self.0[ATTRIBUTES_OFFSET] & FLAGS_DEBUG != 0
+    }
+}
+
+
+// Attestation verification report of the IAS.
+pub struct Report<'a> {
+    pub id:         &'a str,
+    pub status:     &'a str,
+    pub quote_body: [u8;QUOTE_BODY_SIZE],
+}
+
+impl<'a> Report<'a> {
+    pub fn parse( json: &'a str ) -> Result<Report<'a>, Error> {
+        let id = try!( field( json, "id" ) );
+        let status = try!( field( json, "isvEnclaveQuoteStatus" ) );
+        let mut quote_body = [0u8;QUOTE_BODY_SIZE];
+        if try!( base64_decode( try!( field( json, "isvEnclaveQuoteBody" ) ), &mut quote_body ) ) != QUOTE_BODY_SIZE {
+            return Err(Error::InvalidAttestation);
+        }
+        Ok( Report { id, status, quote_body } )
+    }
+
+    // The body of the quote, if the IAS trusts the platform, or of a test
+    // report if `test`.
+    pub fn trusted_body<'b>(&'b self, test: bool) -> Result<QuoteBody<'b>, Error> {
+        if self.status != QUOTE_OK && !(test && self.status == QUOTE_SIMULATION) {
+            return Err(Error::QuoteNotTrusted);
+        }
+        Ok( QuoteBody( &self.quote_body ) )
+    }
+}
+
+
+// The string value of a top level field of a JSON object. Escapes are left
+// as they are, base64 decoding skips the `\` of `\/`.
+fn field<'a>( json: &'a str, name: &str ) -> Result<&'a str, Error> {
+    let mut rest = json;
+    while let Some(pos) = rest.find( name ) {
+        let (before, after) = (&rest[..pos], &rest[pos+name.len()..]);
+        rest = after;
+        if !before.ends_with('"') || !after.starts_with('"') {
+            continue;
+        }
+        let value = after[1..].trim();
+        if !value.starts_with(':') {
+            continue;
+        }
+        let value = value[1..].trim();
+        if !value.starts_with('"') {
+            return Err(Error::InvalidAttestation);
+        }
+        let value = &value[1..];
+        let mut escaped = false;
+        for (i, c) in value.char_indices() {
+            match c {
+                '"' if !escaped => return Ok( &value[..i] ),
+                '\\'            => escaped = !escaped,
+                _               => escaped = false,
+            }
+        }
+        return Err(Error::InvalidAttestation);
+    }
+    Err(Error::InvalidAttestation)
+}
+
+
+const BASE64: &[u8;64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_value( c: u8 ) -> Option<u32> {
+    BASE64.iter().position(|&b| b == c ).map(|v| v as u32 )
+}
+
+// Decode standard base64 into `out`, returns the length written. Padding is
+// optional, `\` are skipped.
+pub fn base64_decode( s: &str, out: &mut [u8] ) -> Result<usize, Error> {
+    let mut len = 0;
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let mut padded = false;
+    for c in s.bytes().filter(|&c| c != b'\\' ) {
+        if c == b'=' {
+            padded = true;
+            continue;
+        }
+        let v = match base64_value( c ) {
+            Some(v) if !padded => v,
+            _                  => return Err(Error::InvalidAttestation),
+        };
+        acc = acc << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            if len == out.len() {
+                return Err(Error::InvalidAttestation);
+            }
+            out[len] = (acc >> bits) as u8;
+            len += 1;
+        }
+    }
+    // Left over bits must be zero.
+    if bits >= 6 || acc & ((1 << bits) - 1) != 0 {
+        return Err(Error::InvalidAttestation);
+    }
+    Ok(len)
+}
+
+// Length of the padded base64 encoding of `len` bytes: 4 per 3 bytes, the
+// last ones padded.
+pub fn base64_len( len: usize ) -> usize {
+    match len % 3 {
+        0 => len / 3 * 4,
+        _ => len / 3 * 4 + 4,
+    }
+}
+
+// Encode `data` into `out`, which must be `base64_len` long.
+pub fn base64_encode( data: &[u8], out: &mut [u8] ) {
+    assert_eq!( out.len(), base64_len( data.len() ) );
+    for (chunk, to) in data.chunks(3).zip( out.chunks_mut(4) ) {
+        let n = chunk.iter().enumerate().fold( 0u32, |n, (i, &b)| n | (b as u32) << (16 - 8*i) );
+        for i in 0..4 {
+            to[i] = if i <= chunk.len() { BASE64[(n >> (18 - 6*i)) as usize & 63] } else { b'=' };
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+    use Error;
+    use super::*;
+
+    fn report( status: &str, body: &[u8;QUOTE_BODY_SIZE] ) -> String {
+        let mut encoded = [0u8;576];
+        base64_encode( body, &mut encoded );
+        format!("{{\"id\":\"1\",\"isvEnclaveQuoteStatus\":\"{}\",\"isvEnclaveQuoteBody\":\"{}\"}}",
+                status, ::core::str::from_utf8( &encoded ).unwrap() )
+    }
+
+    #[test]
+    fn test_reports_only_trusted_in_test() {
+        let mut body = [0u8;QUOTE_BODY_SIZE];
+        body[MR_ENCLAVE_OFFSET] = 7;
+        for &(status, ok, test) in [(QUOTE_OK, true, true), (QUOTE_SIMULATION, false, true),
+                                    ("GROUP_OUT_OF_DATE", false, false)].iter() {
+            let json = report( status, &body );
+            let parsed = Report::parse( &json ).unwrap();
+            assert_eq!( parsed.id, "1" );
+            assert_eq!( parsed.trusted_body( false ).map(|b| b.mr_enclave()[0] ).ok(), if ok { Some(7) } else { None } );
+            assert_eq!( parsed.trusted_body( true ).map(|b| b.mr_enclave()[0] ).ok(), if test { Some(7) } else { None } );
+        }
+        assert_eq!( Report::parse( "{\"id\":\"1\"}" ).err(), Some(Error::InvalidAttestation) );
+    }
+}
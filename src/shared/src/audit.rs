@@ -0,0 +1,292 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Audit log of the analyses, kept by the host and written by the enclave.
+//
+// At the end of an analysis the enclave appends an `AuditRecord` to the log:
+// its number in the log, a timestamp given by the host, the trusted time of
+// the platform, the SHA-256 of the previous record and the statement of the
+// analysis (see `signature`), all signed with its signing key. The host gives
+// the last record of the log to `encl_init`, the enclave checks its signature
+// and numbers the new record after it.
+//
+// A record changed, removed or inserted breaks the chain. The number of
+// records is also kept in an SGX monotonic counter, created with the keypair
+// of the enclave and sealed with it. The enclave refuses a last record that is
+// not the one the counter stands at, so the host cannot truncate the log, roll
+// it back, or start a new chain once one exists. The counter is incremented
+// before the record leaves the enclave, so a number is never signed twice and
+// the log cannot fork. A record the host does not write stops the log: no
+// analysis is possible with this keypair any more, a new one starts a new log.
+//
+// The timestamp of the host is only bound to the trusted time, which counts
+// seconds from an arbitrary origin: from a record to the next, the timestamp
+// must advance as much as the trusted time, within `CLOCK_SLACK`, as long as
+// the time source does not change. The timestamp of the first record is the
+// one of the host. A change of the time source (a reset of the platform
+// clock) is refused unless the host asks for it (`app --reset_time`), the
+// record is then flagged `TIME_RESET` and its timestamp is again the one of the
+// host; `host_time` tells these records apart.
+//
+// The counter and the trusted time come from the platform services (PSE),
+// which Intel has deprecated: they need the Management Engine, are missing
+// from recent SGX SDKs on Linux and from simulation mode. They are only used by an
+// enclave built with `SGX_PSE=1`. Otherwise the counter stays at zero in the
+// sealed keypair and the time source of every record is zero: nothing stops
+// the host from rolling the log back or forking it from an older record, and
+// every timestamp is the one of the host, only checked not to go back.
+
+use core::mem;
+
+use Error;
+use {as_u8_slice,as_u8_slice_mut};
+use signature::{SignedResults,SIGNATURE_SIZE};
+
+
+pub const AUDIT_MAGIC: [u8;8] = *b"SGNTXAUD";
+// 2 took any change of the time source.
+pub const AUDIT_VERSION: u16 = 3;
+
+// The time source changed since the previous record, as asked by the host.
+pub const TIME_RESET: u16 = 1;
+
+// Time source of the records of an enclave without PSE.
+pub const NO_TIME_SOURCE: [u8;32] = [0u8;32];
+
+// Seconds the timestamp may drift from the trusted time between two records.
+pub const CLOCK_SLACK: u64 = 600;
+
+
+#[repr(C)]
+pub struct AuditRecord {
+    magic:     [u8;8],
+    version:   u16,
+    flags:     u16,
+    reserved:  [u8;4],
+    // From 0, the first record of the log.
+    seq:       u64,
+    // Seconds since 1970-01-01 UTC, of the host.
+    timestamp: u64,
+    // Seconds of the trusted time of the platform, from an origin
+    // `time_source` stands for, zeros without PSE.
+    trusted_time: u64,
+    time_source:  [u8;32],
+    // SHA-256 of the previous record, zeros for the first.
+    prev:      [u8;32],
+    statement: SignedResults,
+    // Of the SHA-256 of all the fields above.
+    signature: [u8;SIGNATURE_SIZE],
+}
+
+pub const AUDIT_SIZE: usize = mem::size_of::<AuditRecord>();
+
+impl AuditRecord {
+    pub fn zeroed() -> AuditRecord {
+        AuditRecord { magic: [0u8;8], version: 0, flags: 0, reserved: [0u8;4], seq: 0, timestamp: 0,
+                      trusted_time: 0, time_source: [0u8;32], prev: [0u8;32],
+                      statement: SignedResults::zeroed(), signature: [0u8;SIGNATURE_SIZE] }
+    }
+
+    // The first record of a log.
+    pub fn first( timestamp: u64, trusted_time: u64, time_source: &[u8;32] ) -> AuditRecord {
+        let mut record = AuditRecord::zeroed();
+        record.magic = AUDIT_MAGIC;
+        record.version = AUDIT_VERSION;
+        record.timestamp = timestamp;
+        record.trusted_time = trusted_time;
+        record.time_source = *time_source;
+        record
+    }
+
+    // The record after this one, `hash` being its SHA-256. The time source
+    // may only change if `reset`.
+    pub fn next( &self, hash: &[u8;32], timestamp: u64, trusted_time: u64, time_source: &[u8;32],
+                 reset: bool ) -> Result<AuditRecord, Error> {
+        let mut record = AuditRecord::first( timestamp, trusted_time, time_source );
+        record.seq = self.seq + 1;
+        record.prev = *hash;
+        if reset && *time_source != self.time_source {
+            record.flags = TIME_RESET;
+        }
+        try!( record.in_time( self ) );
+        Ok(record)
+    }
+
+    // Whether this record follows `prev` in the log, `hash` being its SHA-256.
+    pub fn follows( &self, prev: &AuditRecord, hash: &[u8;32] ) -> bool {
+        self.seq == prev.seq + 1 && self.prev == *hash && self.in_time( prev ).is_ok()
+    }
+
+    // The timestamp does not go back and, with the same time source, advances
+    // as much as the trusted time. The source only changes with `TIME_RESET`.
+    fn in_time( &self, prev: &AuditRecord ) -> Result<(), Error> {
+        if self.timestamp < prev.timestamp {
+            return Err(Error::AuditTimestamp);
+        }
+        if (self.time_source != prev.time_source) != (self.flags & TIME_RESET != 0) {
+            return Err(Error::AuditTimeSource);
+        }
+        if self.time_source == prev.time_source && self.time_source != NO_TIME_SOURCE {
+            if self.trusted_time < prev.trusted_time {
+                return Err(Error::AuditTrustedTime);
+            }
+            let elapsed = self.trusted_time - prev.trusted_time;
+            let advance = self.timestamp - prev.timestamp;
+            if advance + CLOCK_SLACK < elapsed || elapsed + CLOCK_SLACK < advance {
+                return Err(Error::AuditTrustedTime);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        if self.magic != AUDIT_MAGIC || self.version != AUDIT_VERSION {
+            return Err(Error::InvalidAuditRecord);
+        }
+        self.statement.check()
+    }
+
+    pub fn set_statement(&mut self, statement: &SignedResults) {
+        as_u8_slice_mut( &mut self.statement ).copy_from_slice( statement.as_slice() );
+    }
+
+    pub fn set_signature(&mut self, signature: &[u8;SIGNATURE_SIZE]) {
+        self.signature = *signature;
+    }
+
+    // The bytes signed, all but the signature.
+    pub fn signed(&self) -> &[u8] {
+        &as_u8_slice( self )[..AUDIT_SIZE-SIGNATURE_SIZE]
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        as_u8_slice( self )
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn trusted_time(&self) -> u64 {
+        self.trusted_time
+    }
+
+    pub fn time_source(&self) -> &[u8;32] {
+        &self.time_source
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.flags
+    }
+
+    // Whether the timestamp is only the one of the host, not bound to the
+    // trusted time by the previous record.
+    pub fn host_time(&self) -> bool {
+        self.seq == 0 || self.time_source == NO_TIME_SOURCE || self.flags & TIME_RESET != 0
+    }
+
+    pub fn prev(&self) -> &[u8;32] {
+        &self.prev
+    }
+
+    pub fn statement(&self) -> &SignedResults {
+        &self.statement
+    }
+
+    pub fn signature(&self) -> &[u8;SIGNATURE_SIZE] {
+        &self.signature
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use Error;
+    use as_u8_slice_mut;
+    use signature::{SignedResults,SIGNATURE_SIZE};
+    use super::{AuditRecord,AUDIT_SIZE,CLOCK_SLACK,TIME_RESET,NO_TIME_SOURCE};
+
+    const SOURCE: [u8;32] = [7u8;32];
+
+    fn signed( mut record: AuditRecord, sig: u8 ) -> AuditRecord {
+        record.set_statement( &SignedResults::zeroed() );
+        record.set_signature( &[sig;SIGNATURE_SIZE] );
+        record
+    }
+
+    #[test]
+    fn round_trip() {
+        let record = signed( AuditRecord::first( 86400, 1000, &SOURCE ), 1 );
+        assert_eq!( record.as_slice().len(), AUDIT_SIZE );
+        // The signature comes last, all the rest is signed.
+        assert_eq!( record.signed().len(), AUDIT_SIZE - SIGNATURE_SIZE );
+        assert_eq!( &record.as_slice()[AUDIT_SIZE-SIGNATURE_SIZE..], &[1u8;SIGNATURE_SIZE][..] );
+
+        let mut read = AuditRecord::zeroed();
+        as_u8_slice_mut( &mut read ).copy_from_slice( record.as_slice() );
+        assert_eq!( read.as_slice(), record.as_slice() );
+        assert_eq!( (read.seq(), read.timestamp(), read.trusted_time()), (0, 86400, 1000) );
+        assert_eq!( read.time_source(), &SOURCE );
+        assert_eq!( read.prev(), &[0u8;32] );
+    }
+
+    #[test]
+    fn chain() {
+        let first = signed( AuditRecord::first( 86400, 1000, &SOURCE ), 1 );
+        let hash = [9u8;32];
+        let next = signed( first.next( &hash, 86400 + 500, 1500, &SOURCE, false ).unwrap(), 2 );
+        assert_eq!( next.seq(), 1 );
+        assert_eq!( next.prev(), &hash );
+        assert!( next.follows( &first, &hash ) );
+        assert!( first.host_time() && !next.host_time() );
+        assert!( !next.follows( &first, &[8u8;32] ) );
+        assert!( !first.follows( &next, &hash ) );
+    }
+
+    #[test]
+    fn time() {
+        let first = AuditRecord::first( 86400, 1000, &SOURCE );
+        let hash = [9u8;32];
+        assert_eq!( first.next( &hash, 86399, 1000, &SOURCE, false ).err(), Some(Error::AuditTimestamp) );
+        assert_eq!( first.next( &hash, 86400, 999, &SOURCE, false ).err(), Some(Error::AuditTrustedTime) );
+        // The host clock may drift from the trusted time by `CLOCK_SLACK`.
+        assert!( first.next( &hash, 86400 + CLOCK_SLACK, 1000, &SOURCE, false ).is_ok() );
+        assert!( first.next( &hash, 86400, 1000 + CLOCK_SLACK, &SOURCE, false ).is_ok() );
+        assert_eq!( first.next( &hash, 86400 + CLOCK_SLACK + 1, 1000, &SOURCE, false ).err(), Some(Error::AuditTrustedTime) );
+        assert_eq!( first.next( &hash, 86400, 1001 + CLOCK_SLACK, &SOURCE, false ).err(), Some(Error::AuditTrustedTime) );
+        // Another time source has another origin, the host must ask for it.
+        assert_eq!( first.next( &hash, 86400 + 1, 0, &[8u8;32], false ).err(), Some(Error::AuditTimeSource) );
+        let reset = first.next( &hash, 86400 + 1, 0, &[8u8;32], true ).unwrap();
+        assert_eq!( reset.flags(), TIME_RESET );
+        assert!( reset.follows( &first, &hash ) && reset.host_time() );
+        assert_eq!( first.next( &hash, 86400 + 1, 1001, &SOURCE, true ).unwrap().flags(), 0 );
+        // Without PSE the timestamp is only checked not to go back.
+        let first = AuditRecord::first( 86400, 0, &NO_TIME_SOURCE );
+        let next = first.next( &hash, 86400 * 2, 0, &NO_TIME_SOURCE, false ).unwrap();
+        assert!( next.follows( &first, &hash ) && next.host_time() );
+        assert_eq!( first.next( &hash, 86399, 0, &NO_TIME_SOURCE, false ).err(), Some(Error::AuditTimestamp) );
+    }
+}
@@ -0,0 +1,89 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Block ciphers of the .ce files, recorded in the file header.
+//
+// All are AEADs with a 96-bit nonce and a 128-bit tag, so blocks have the
+// same layout whatever the cipher. Keys are derived to `KEY_SIZE_MAX` bytes
+// and the first `key_size` are used.
+
+use core::fmt;
+use core::str::FromStr;
+
+use Error;
+
+
+pub const KEY_SIZE_MAX: usize = 32;
+
+// Cipher of the new files, unless one is given.
+pub const DEFAULT: Cipher = Cipher::Aes256Gcm;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[repr(u8)]
+pub enum Cipher {
+    Aes128Gcm        = 0,
+    Aes256Gcm        = 1,
+    ChaCha20Poly1305 = 2,
+}
+
+impl Cipher {
+    pub fn from_u8( v: u8 ) -> Option<Cipher> {
+        match v {
+            0 => Some(Cipher::Aes128Gcm),
+            1 => Some(Cipher::Aes256Gcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub fn key_size(&self) -> usize {
+        match *self {
+            Cipher::Aes128Gcm        => 16,
+            Cipher::Aes256Gcm        => 32,
+            Cipher::ChaCha20Poly1305 => 32,
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Cipher::Aes128Gcm        => "aes128-gcm",
+            Cipher::Aes256Gcm        => "aes256-gcm",
+            Cipher::ChaCha20Poly1305 => "chacha20-poly1305",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes128-gcm"        => Ok(Cipher::Aes128Gcm),
+            "aes256-gcm"        => Ok(Cipher::Aes256Gcm),
+            "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            _                   => Err(Error::UnsupportedCipher),
+        }
+    }
+}
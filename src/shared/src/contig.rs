@@ -0,0 +1,114 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Contig indices.
+//
+// Chromosomes of the primary assembly have fixed indices (1 to 25) whatever
+// their spelling ("1", "chr1", "MT", "chrM"). Other contigs (alt, decoy,
+// unplaced, ...) are indexed by a hash of their name with the top bit set,
+// their names travel in the block side table (see `side::Tag::Contig`).
+
+use core::fmt;
+use core::str::FromStr;
+
+use {Error, fnv1a, FNV1A_INIT};
+
+
+const NAMED: u32 = 0x8000_0000;
+
+// Contig of the padding keys, never given to a real contig, see `Key::padding`.
+pub const PADDING: u32 = 0xffff_ffff;
+
+const PRIMARY: [&str; 25] =
+    ["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13",
+     "14", "15", "16", "17", "18", "19", "20", "21", "22", "X", "Y", "MT"];
+
+
+// Index of a primary chromosome.
+pub fn primary(name: &str) -> Option<u32> {
+    let name = &name[if name.starts_with("chr") { 3 } else { 0 }..];
+    let name = if name == "M" { "MT" } else { name };
+    PRIMARY.iter().position(|&p| p == name).map(|i| i as u32 + 1)
+}
+
+pub fn index(name: &str) -> u32 {
+    match primary(name) {
+        Some(i) => i,
+        None    => match fnv1a(name.as_bytes(), FNV1A_INIT) | NAMED {
+            PADDING => PADDING - 1,
+            index   => index,
+        },
+    }
+}
+
+pub fn is_named(index: u32) -> bool {
+    index & NAMED != 0
+}
+
+// Default name of a contig, used when no side table name is known.
+pub fn write_name(index: u32, f: &mut fmt::Formatter) -> fmt::Result {
+    if index >= 1 && index as usize <= PRIMARY.len() {
+        write!(f, "{}", PRIMARY[index as usize - 1])
+    } else {
+        write!(f, "<{:08x}>", index)
+    }
+}
+
+
+// Reference builds, used as contig dictionary of inputs without ##contig lines.
+#[derive(Clone,Copy,Debug)]
+#[repr(u8)]
+pub enum Build {
+    // 1..22, X, Y, MT
+    GRCh37 = 37,
+    // chr1..chr22, chrX, chrY, chrM
+    GRCh38 = 38,
+}
+
+impl Build {
+    pub fn from_u8(build: u8) -> Option<Build> {
+        match build {
+            37 => Some(Build::GRCh37),
+            38 => Some(Build::GRCh38),
+            _  => None,
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        match *self {
+            Build::GRCh37 => PRIMARY.contains(&name),
+            Build::GRCh38 => name.starts_with("chr") && name != "chrMT" && primary(name).is_some(),
+        }
+    }
+}
+
+impl FromStr for Build {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GRCh37" | "hg19" => Ok(Build::GRCh37),
+            "GRCh38" | "hg38" => Ok(Build::GRCh38),
+            _                 => Err(Error::UnknownContig),
+        }
+    }
+}
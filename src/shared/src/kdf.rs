@@ -0,0 +1,171 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Key derivation (HKDF-SHA256, RFC 5869) from the data key of a dataset.
+//
+// Each .ce file is encrypted under its own key, derived from the data key, the
+// file ID and the cipher, and its header names the data key by an ID derived
+// from it.
+// The SHA-256 is given by the caller: OpenSSL in `ce`, the SGX SDK in the
+// enclave.
+
+use cipher::{self,Cipher};
+
+
+pub const DATA_KEY_SIZE: usize = 32;
+pub const KEY_ID_SIZE: usize = 8;
+
+const BLOCK_SIZE: usize = 64;
+// Longest message given to `hmac`.
+const MSG_MAX: usize = 64;
+
+const FILE_KEY_INFO: &[u8] = b"SGNTX file key";
+const FILE_KEY_INFO_SIZE: usize = 14;
+const KEY_ID_INFO: &[u8] = b"SGNTX key id";
+
+
+fn hmac<H: Fn(&[u8]) -> [u8;32]>( sha256: &H, key: &[u8], msg: &[u8] ) -> [u8;32] {
+    assert!( key.len() <= BLOCK_SIZE && msg.len() <= MSG_MAX );
+    let mut buf = [0u8;BLOCK_SIZE+MSG_MAX];
+    let mut pad = [0u8;BLOCK_SIZE];
+    pad[..key.len()].copy_from_slice( key );
+
+    for (b, p) in buf.iter_mut().zip( pad.iter() ) {
+        *b = p ^ 0x36;
+    }
+    buf[BLOCK_SIZE..BLOCK_SIZE+msg.len()].copy_from_slice( msg );
+    let inner = sha256( &buf[..BLOCK_SIZE+msg.len()] );
+
+    for (b, p) in buf.iter_mut().zip( pad.iter() ) {
+        *b = p ^ 0x5c;
+    }
+    buf[BLOCK_SIZE..BLOCK_SIZE+32].copy_from_slice( &inner );
+    sha256( &buf[..BLOCK_SIZE+32] )
+}
+
+// The first block of output, enough for the keys below.
+fn hkdf<H: Fn(&[u8]) -> [u8;32]>( sha256: &H, salt: &[u8], ikm: &[u8], info: &[u8] ) -> [u8;32] {
+    let prk = hmac( sha256, salt, ikm );
+    let mut msg = [0u8;MSG_MAX];
+    msg[..info.len()].copy_from_slice( info );
+    msg[info.len()] = 1;
+    hmac( sha256, &prk, &msg[..info.len()+1] )
+}
+
+
+pub fn key_id<H: Fn(&[u8]) -> [u8;32]>( sha256: &H, data_key: &[u8;DATA_KEY_SIZE] ) -> [u8;KEY_ID_SIZE] {
+    let mut id = [0u8;KEY_ID_SIZE];
+    id.copy_from_slice( &hkdf( sha256, &[], data_key, KEY_ID_INFO )[..KEY_ID_SIZE] );
+    id
+}
+
+// The cipher is in the info, a file key is never used with two ciphers.
+pub fn file_key<H: Fn(&[u8]) -> [u8;32]>( sha256: &H,
+                                          data_key: &[u8;DATA_KEY_SIZE],
+                                          file_id:  &[u8;16],
+                                          cipher:   Cipher ) -> [u8;cipher::KEY_SIZE_MAX] {
+    let mut info = [0u8;FILE_KEY_INFO_SIZE+1];
+    info[..FILE_KEY_INFO_SIZE].copy_from_slice( FILE_KEY_INFO );
+    info[FILE_KEY_INFO_SIZE] = cipher as u8;
+    hkdf( sha256, file_id, data_key, &info )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::hkdf;
+
+    const K: [u32;64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2 ];
+
+    // FIPS 180-4, `ce` and the enclave have their own.
+    fn sha256( data: &[u8] ) -> [u8;32] {
+        let mut h: [u32;8] = [ 0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                               0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19 ];
+        let mut msg = data.to_vec();
+        msg.push( 0x80 );
+        while msg.len() % 64 != 56 {
+            msg.push( 0 );
+        }
+        for i in (0..8).rev() {
+            msg.push( ((data.len() as u64 * 8) >> (8*i)) as u8 );
+        }
+        for block in msg.chunks( 64 ) {
+            let mut w = [0u32;64];
+            for i in 0..16 {
+                w[i] = (0..4).fold( 0, |w, j| w << 8 | block[4*i+j] as u32 );
+            }
+            for i in 16..64 {
+                let s0 = w[i-15].rotate_right(7) ^ w[i-15].rotate_right(18) ^ (w[i-15] >> 3);
+                let s1 = w[i-2].rotate_right(17) ^ w[i-2].rotate_right(19) ^ (w[i-2] >> 10);
+                w[i] = w[i-16].wrapping_add( s0 ).wrapping_add( w[i-7] ).wrapping_add( s1 );
+            }
+            let mut v = h;
+            for i in 0..64 {
+                let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+                let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+                let t1 = v[7].wrapping_add( s1 ).wrapping_add( ch ).wrapping_add( K[i] ).wrapping_add( w[i] );
+                let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+                let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+                let t2 = s0.wrapping_add( maj );
+                v = [t1.wrapping_add( t2 ), v[0], v[1], v[2], v[3].wrapping_add( t1 ), v[4], v[5], v[6]];
+            }
+            for i in 0..8 {
+                h[i] = h[i].wrapping_add( v[i] );
+            }
+        }
+        let mut hash = [0u8;32];
+        for i in 0..32 {
+            hash[i] = (h[i/4] >> (8*(3-i%4))) as u8;
+        }
+        hash
+    }
+
+    fn hex( s: &str ) -> Vec<u8> {
+        (0..s.len()/2).map(|i| u8::from_str_radix( &s[2*i..2*i+2], 16 ).unwrap() ).collect()
+    }
+
+    #[test]
+    fn sha256_abc() {
+        assert_eq!( &sha256( b"abc" )[..], &hex( "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad" )[..] );
+    }
+
+    // Test cases 1 and 3 of RFC 5869, the first block of the OKM. The info of
+    // test case 2 is longer than `MSG_MAX`.
+    #[test]
+    fn rfc5869() {
+        let ikm = [0x0bu8;22];
+        let okm = hkdf( &sha256, &hex( "000102030405060708090a0b0c" ), &ikm, &hex( "f0f1f2f3f4f5f6f7f8f9" ) );
+        assert_eq!( &okm[..], &hex( "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf" )[..] );
+        let okm = hkdf( &sha256, &[], &ikm, &[] );
+        assert_eq!( &okm[..], &hex( "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d" )[..] );
+    }
+}
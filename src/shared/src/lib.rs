@@ -21,6 +21,10 @@
 
 #![no_std]
 
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
 
 use core::fmt;
 use core::slice;
@@ -30,17 +34,28 @@ use core::u32;
 use core::u16;
 
 use core::cmp::Ordering;
-use core::str::FromStr;
+use core::str::{self,FromStr};
 
+pub mod side;
+pub mod contig;
+pub mod sheet;
+pub mod provision;
+pub mod attestation;
+pub mod kdf;
+pub mod cipher;
+pub mod results;
+pub mod policy;
+pub mod signature;
+pub mod audit;
+pub mod siphash;
 
-pub const KEYS_PER_BLOCK_DEFAULT: u32 = 2080;
-pub const ITER_FACTOR_DEFAULT: u32 = 4;
+use siphash::SipKey;
 
-pub const AES_KEY: [u8; 16] =
-    [0x4c, 0x86, 0xaa, 0xf6, 0xaf, 0xc9, 0x5e, 0x87, 0xa6, 0x85, 0x18, 0xdf, 0x8a, 0xe7, 0x58, 0x29];
 
+pub const KEYS_PER_BLOCK_DEFAULT: u32 = 2080;
+pub const ITER_FACTOR_DEFAULT: u32 = 4;
 
-#[derive(Clone,Copy,Debug)]
+#[derive(Clone,Copy,Debug,PartialEq)]
 #[repr(u8)]
 pub enum Kind {
     Control = 0,
@@ -48,10 +63,83 @@ pub enum Kind {
 }
 
 
-#[derive(Debug)]
+// How the enclave counts the keys of an iteration. The hashmap probes memory
+// at an address given by each key, the oblivious sort and merge has memory
+// accesses that do not depend on the keys, at a higher cost.
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[repr(u8)]
+pub enum Aggregation {
+    Hashmap   = 0,
+    Oblivious = 1,
+}
+
+impl Aggregation {
+    pub fn from_u8( v: u8 ) -> Option<Aggregation> {
+        match v {
+            0 => Some(Aggregation::Hashmap),
+            1 => Some(Aggregation::Oblivious),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Aggregation::Hashmap   => "hashmap",
+            Aggregation::Oblivious => "oblivious",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Aggregation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hashmap"   => Ok(Aggregation::Hashmap),
+            "oblivious" => Ok(Aggregation::Oblivious),
+            _           => Err(Error::UnknownAggregation),
+        }
+    }
+}
+
+
+#[derive(Debug,PartialEq)]
 pub enum Error {
     InvalidBase,
     InvalidType,
+    InvalidSide,
+    UnknownContig,
+    InvalidMagic,
+    UnsupportedVersion,
+    InvalidBlockSize,
+    InvalidBlock,
+    InvalidSample,
+    InvalidSheet,
+    InvalidPhenotype,
+    InvalidFooter,
+    InvalidEnclaveKey,
+    UnsupportedCipher,
+    InvalidResults,
+    InvalidSignedResults,
+    UnknownAggregation,
+    InvalidStudy,
+    InvalidDate,
+    AlleleFreqNotAllowed,
+    SnpCountNotAllowed,
+    UsageExpired,
+    StudyNotAllowed,
+    InvalidAuditRecord,
+    AuditTimestamp,
+    AuditTrustedTime,
+    AuditCounter,
+    AuditTimeSource,
+    InvalidAttestation,
+    QuoteNotTrusted,
+    UntrustedEnclave,
+    DebugEnclave,
 }
 
 impl fmt::Display for Error {
@@ -59,6 +147,36 @@ impl fmt::Display for Error {
         let s = match *self {
             Error::InvalidBase => "Invalid Base",
             Error::InvalidType => "Invalid Type",
+            Error::InvalidSide => "Invalid Side Table",
+            Error::UnknownContig => "Unknown Contig",
+            Error::InvalidMagic => "Not a .ce File",
+            Error::UnsupportedVersion => "Unsupported .ce Format or Key Layout Version",
+            Error::InvalidBlockSize => "Invalid Block Size",
+            Error::InvalidBlock => "Invalid Block Header",
+            Error::InvalidSample => "Invalid Sample Identifier",
+            Error::InvalidSheet => "Invalid Sample Sheet",
+            Error::InvalidPhenotype => "Invalid Phenotype",
+            Error::InvalidFooter => "Invalid File Footer",
+            Error::InvalidEnclaveKey => "Invalid Enclave Key",
+            Error::UnsupportedCipher => "Unsupported Cipher",
+            Error::InvalidResults => "Invalid Results File",
+            Error::InvalidSignedResults => "Invalid Signed Results",
+            Error::UnknownAggregation => "Unknown Aggregation",
+            Error::InvalidStudy => "Invalid Study Identifier",
+            Error::InvalidDate => "Invalid Date",
+            Error::AlleleFreqNotAllowed => "Allele Frequencies Not Allowed by the Usage Policy",
+            Error::SnpCountNotAllowed => "Count of Top SNPs Not Allowed by the Usage Policy",
+            Error::UsageExpired => "Usage Policy Expired",
+            Error::StudyNotAllowed => "Study Not Allowed by the Usage Policy",
+            Error::InvalidAuditRecord => "Invalid Audit Record",
+            Error::AuditTimestamp => "Timestamp Older Than the Last Audit Record",
+            Error::AuditTrustedTime => "Timestamp Out of Step with the Trusted Time",
+            Error::AuditCounter => "Audit Log Out of Step with the Monotonic Counter",
+            Error::AuditTimeSource => "Time Source Changed Without a Reset",
+            Error::InvalidAttestation => "Invalid Attestation Report",
+            Error::QuoteNotTrusted => "Quote Not Trusted by the Attestation Service",
+            Error::UntrustedEnclave => "Attestation Report of Another Enclave or Key",
+            Error::DebugEnclave => "Debug Enclave",
         };
         write!(f, "{}", s)
     }
@@ -124,10 +242,18 @@ impl FromStr for Typ {
 }
 
 #[derive(Clone,Copy,Debug)]
-pub struct Key(u16, u16, u16, u16, u16);
+pub struct Key(u16, u16, u16, u16, u16, u16, u16, u16, u16, u16, u16);
 
-pub const KEY_MIN: Key = Key( u16::MIN, u16::MIN, u16::MIN, u16::MIN, u16::MIN );
-pub const KEY_MAX: Key = Key( u16::MAX, u16::MAX, u16::MAX, u16::MAX, u16::MAX );
+pub const KEY_MIN: Key = Key( u16::MIN, u16::MIN, u16::MIN, u16::MIN, u16::MIN, u16::MIN, u16::MIN, u16::MIN, u16::MIN, u16::MIN, u16::MIN );
+pub const KEY_MAX: Key = Key( u16::MAX, u16::MAX, u16::MAX, u16::MAX, u16::MAX, u16::MAX, u16::MAX, u16::MAX, u16::MAX, u16::MAX, u16::MAX );
+
+// Top bit of the allele field: set when the (refe, alt) pair is a hash whose
+// strings travel in the block side table.
+const NAMED_ALLELE: u32 = 0x8000_0000;
+
+// Top bit of the id field: set when the ID is not a rsID but a hash of the ID
+// column, which travels in the block side table.
+const NAMED_ID: u64 = 0x8000_0000_0000_0000;
 
 impl Key {
 
@@ -148,61 +274,145 @@ impl Key {
         (refe, alt)
     }
 
+    // Encode a (REF, ALT) pair into the allele field of a key.
+    //
+    // Single base pairs are packed on 5 bits, any other pair (indels, MNPs,
+    // symbolic alleles) is replaced by a hash of its text. The text must then
+    // be stored in the block side table, see `side::Tag::Allele`.
+    pub fn encode_allele(refe: &str, alt: &str) -> Result<u32, Error> {
+        match (Base::from_str(refe), Base::from_str(alt)) {
+            (Ok(refe), Ok(alt)) => Ok(Key::encode_base_pair(refe, alt) as u32),
+            _ => {
+                if !is_allele(refe) || !is_allele(alt) {
+                    return Err(Error::InvalidBase);
+                }
+                let h = fnv1a(refe.as_bytes(), FNV1A_INIT);
+                let h = fnv1a(b"\t", h);
+                Ok(fnv1a(alt.as_bytes(), h) | NAMED_ALLELE)
+            },
+        }
+    }
 
-    pub fn new(chrom: u8, pos: u32, id: u64, refe: Base, alt: Base, typ: Typ ) -> Key {
+    // Encode the ID column: 0 for '.', the number of a rsID, or a hash of
+    // the text for any other ID (or list of IDs). The text must then be stored
+    // in the block side table, see `side::Tag::Id`.
+    pub fn encode_id(id: &str) -> u64 {
+        if id == "." {
+            return 0;
+        }
+        if id.len() > 2 && id.starts_with("rs") && id[2..].bytes().all(|c| c.is_ascii_digit() ) {
+            if let Ok(rs) = id[2..].parse::<u64>() {
+                if rs != 0 && rs & NAMED_ID == 0 {
+                    return rs;
+                }
+            }
+        }
+        fnv1a64(id.as_bytes()) | NAMED_ID
+    }
+
+    // `contig` is an index from `contig::index`, `id` from `Key::encode_id`.
+    pub fn new(contig: u32, pos: u32, id: u64, allele: u32, typ: Typ ) -> Key {
         let a = (pos >> 16) as u16;
         let b = pos as u16;
 
-        let refe_alt = Key::encode_base_pair(refe, alt);
-        let id_top = (id >> 32) as u16;
-        let c = (chrom as u16 & 0b11111) | (refe_alt as u16 & 0b11111) << 5 | (typ as u16) << 10 | id_top << 11;
+        let c = typ as u16;
+
+        let d = (id >> 48) as u16;
+        let e = (id >> 32) as u16;
+        let f = (id >> 16) as u16;
+        let g = id as u16;
 
-        let d = (id >> 16) as u16;
-        let e = id as u16;
+        let h = (allele >> 16) as u16;
+        let i = allele as u16;
+
+        let j = (contig >> 16) as u16;
+        let k = contig as u16;
+
+        Key(a,b,c,d,e,f,g,h,i,j,k)
+    }
 
-        Key(a,b,c,d,e)
+    pub fn contig(&self) -> u32 {
+        ((self.9 as u32) << 16) + self.10 as u32
     }
 
-    pub fn chrom(&self) -> u8 {
-        (self.2 & 0b11111) as u8
+    // True if the contig is not a primary chromosome and is named in a side table.
+    pub fn is_named_contig(&self) -> bool {
+        contig::is_named( self.contig() )
     }
 
     pub fn pos(&self) -> u32 {
         ((self.0 as u32) << 16) + self.1 as u32
     }
 
-    pub fn id(&self) -> u32 {
-        ((self.2 >> 11) & 0b11111) as u32 + ((self.3 as u32) << 16) + self.4 as u32
+    pub fn id(&self) -> u64 {
+        (self.3 as u64) << 48 | (self.4 as u64) << 32 | (self.5 as u64) << 16 | self.6 as u64
+    }
+
+    // True if the ID is not a rsID but named in a side table.
+    pub fn is_named_id(&self) -> bool {
+        self.id() & NAMED_ID != 0
+    }
+
+    pub fn allele(&self) -> u32 {
+        ((self.7 as u32) << 16) + self.8 as u32
+    }
+
+    // True if the alleles are not packed in the key but named in a side table.
+    pub fn is_named_allele(&self) -> bool {
+        self.allele() & NAMED_ALLELE != 0
     }
 
-    pub fn refe_alt(&self) -> (Base, Base) {
-        Key::decode_base_pair(((self.2 >> 5) & 0b11111) as u8)
+    // Single base (refe, alt) pair, `None` for named alleles.
+    pub fn refe_alt(&self) -> Option<(Base, Base)> {
+        if self.is_named_allele() {
+            None
+        } else {
+            Some(Key::decode_base_pair(self.allele() as u8))
+        }
     }
 
     pub fn typ(&self) -> Typ {
-        unsafe { mem::transmute( ((self.2 >>10) & 1) as u8 ) }
+        unsafe { mem::transmute( (self.2 & 1) as u8 ) }
+    }
+
+    // Dummy key padding a block, they sort after every real key and the `n`
+    // of a block must increase. See `contig::PADDING`.
+    pub fn padding(n: u32) -> Key {
+        Key::new(contig::PADDING, n, 0, 0, Typ::Heterozygous)
+    }
+
+    pub fn is_padding(&self) -> bool {
+        self.contig() == contig::PADDING
     }
 
-    pub fn chrom_refe_alt(&self) -> u16 {
-        (self.2 & 0b11111_11111)
+    pub fn chrom_allele(&self) -> u64 {
+        (self.contig() as u64) << 32 | self.allele() as u64
     }
 }
 
 impl fmt::Display for Key {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let id = self.id();
-        let (refe, alt )= self.refe_alt();
+        try!( resolve(self, Name::Contig, f) );
         if id == 0 {
-            write!(f, "{:2}\t{:10}\t.\t{}\t{}", self.chrom(), self.pos(), refe, alt )
+            try!( write!(f, "\t{:10}\t.\t", self.pos()) );
+        } else if self.is_named_id() {
+            try!( write!(f, "\t{:10}\t", self.pos()) );
+            try!( resolve(self, Name::Id, f) );
+            try!( write!(f, "\t") );
         } else {
-            write!(f, "{:2}\t{:10}\trs{}\t{}\t{}", self.chrom(), self.pos(), id, refe, alt )
+            try!( write!(f, "\t{:10}\trs{}\t", self.pos(), id) );
+        }
+        match self.refe_alt() {
+            Some((refe, alt)) => write!(f, "{}\t{}", refe, alt ),
+            None => resolve(self, Name::Allele, f),
         }
     }
 }
 
 impl PartialEq for Key {
     fn eq(&self, other: &Key) -> bool {
-        (self.pos() == other.pos()) & (self.chrom_refe_alt() == other.chrom_refe_alt())
+        (self.pos() == other.pos()) & (self.chrom_allele() == other.chrom_allele())
     }
 }
 
@@ -214,30 +424,96 @@ impl PartialOrd for Key {
     }
 }
 
+// Genomic order: contig, position, then allele. Blocks of a .ce file are
+// sorted in this order.
 impl Ord for Key {
     fn cmp(&self, other: &Key) -> Ordering {
-        self.pos().cmp(&other.pos()).then(self.chrom_refe_alt().cmp(&other.chrom_refe_alt()))
+        self.contig().cmp(&other.contig())
+            .then(self.pos().cmp(&other.pos()))
+            .then(self.allele().cmp(&other.allele()))
     }
 }
 
-pub trait Hash {
-    fn hash(&self) -> u64;
+
+// A VCF allele: bases (any case), the '*' deletion marker or a symbolic <ID>.
+fn is_allele(s: &str) -> bool {
+    if s.len() > 2 && s.starts_with('<') && s.ends_with('>') {
+        return !s[1..s.len()-1].contains(&['<', '>', ',', '\t'][..]);
+    }
+    !s.is_empty() && s.bytes().all(|c| b"ACGTNacgtn*".contains(&c) )
+}
+
+const FNV1A_INIT:  u32 = 0x811c9dc5;
+const FNV1A_PRIME: u32 = 0x01000193;
+
+fn fnv1a(bytes: &[u8], init: u32) -> u32 {
+    bytes.iter().fold(init, |h, &b| (h ^ b as u32).wrapping_mul(FNV1A_PRIME))
+}
+
+const FNV1A64_INIT:  u64 = 0xcbf29ce484222325;
+const FNV1A64_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV1A64_INIT, |h, &b| (h ^ b as u64).wrapping_mul(FNV1A64_PRIME))
+}
+
+
+// Names that do not fit in a key.
+#[derive(Clone,Copy,Debug)]
+pub enum Name {
+    // Contig name as written in the input.
+    Contig,
+    // ID column when it is not a rsID.
+    Id,
+    // `REF\tALT` of a named allele.
+    Allele,
+}
+
+// Writes the name of a key field from the dictionary built out of the side
+// tables. Used by `Display for Key`.
+pub type Resolver = fn(key: &Key, name: Name, f: &mut fmt::Formatter) -> fmt::Result;
+
+static mut RESOLVER: Option<Resolver> = None;
+
+// Install the resolver used to print named fields.
+pub fn set_resolver(resolver: Resolver) {
+    unsafe { RESOLVER = Some(resolver) };
+}
+
+fn resolve(key: &Key, name: Name, f: &mut fmt::Formatter) -> fmt::Result {
+    match unsafe { RESOLVER } {
+        Some(resolver) => resolver(key, name, f),
+        None => match name {
+            Name::Contig => contig::write_name(key.contig(), f),
+            Name::Id     => write!(f, "<{:016x}>", key.id()),
+            Name::Allele => write!(f, "<{:08x}>\t.", key.allele()),
+        },
+    }
 }
 
-const GOLDEN_RATIO_64:       u64 = 0x61c8864680b583eb;
+// Keyed hash of a key, see `siphash`. Equal keys hash equally under the same
+// key.
+pub trait Hash {
+    fn hash(&self, key: &SipKey) -> u64;
+}
 
 impl Hash for Key {
-    fn hash(&self) -> u64 {
-        (self.pos() as u64 | ((self.chrom_refe_alt() as u64) << 32)) * GOLDEN_RATIO_64
+    fn hash(&self, key: &SipKey) -> u64 {
+        key.hash( &[self.pos() as u64, self.chrom_allele()] )
     }
 }
 
 
 
 // Compressed file block header.
+//
+// The encrypted payload holds `nb_keys` keys followed by the side table of the
+// block (see `side`), `size` is the length of the whole payload. The last
+// block of a file has no keys, its payload is the footer (see `footer`).
 pub struct Header {
-    size: u32,
-    iv:   [u32;3],
+    size:    u32,
+    nb_keys: u32,
+    iv:      [u32;3],
     mac:  [u8;16],
 }
 
@@ -251,16 +527,20 @@ pub fn size( nb_keys: u32 ) -> usize {
 
 
 impl Header {
-    pub fn new( size: u32, iv: [u32;3]) -> Header {
-        Header { size: size, iv: iv, mac: [0u8;16] }
+    pub fn new( size: u32, nb_keys: u32, iv: [u32;3]) -> Header {
+        Header { size, nb_keys, iv, mac: [0u8;16] }
     }
 
     pub fn as_slice(&self) -> &[u8] {
         as_u8_slice( self )
     }
 
-    pub fn blk_size(&self) -> u32 {
-        self.size + mem::size_of::<Header>() as u32
+    // Size of the block with its header, the size comes from the file too.
+    pub fn blk_size(&self) -> Result<u32, Error> {
+        match self.size.checked_add( mem::size_of::<Header>() as u32 ) {
+            Some(blk_size) => Ok(blk_size),
+            None           => Err(Error::InvalidBlock),
+        }
     }
 
     pub fn size(&self) -> u32 {
@@ -274,10 +554,276 @@ impl Header {
     pub fn mac(&self) -> &[u8;16] {
         &self.mac
     }
+
+    pub fn mac_mut(&mut self) -> &mut [u8;16] {
+        &mut self.mac
+    }
     
-    pub fn nb_keys(&self) -> u32 {
-        assert_eq!( self.size % mem::size_of::<Key>() as u32, 0);
-        self.size / mem::size_of::<Key>() as u32
+    // The header comes from the file as it is, its keys must fit in the
+    // payload.
+    pub fn nb_keys(&self) -> Result<u32, Error> {
+        if size( self.nb_keys ) > self.size as usize {
+            return Err(Error::InvalidBlock);
+        }
+        Ok(self.nb_keys)
+    }
+
+    // Size of the side table following the keys.
+    pub fn side_size(&self) -> Result<u32, Error> {
+        let nb_keys = try!( self.nb_keys() );
+        Ok(self.size - size( nb_keys ) as u32)
+    }
+}
+
+
+// Additional data of a block: the ID of its file, its number (from 1) and
+// its number of keys, so that blocks cannot be moved, dropped or reinterpreted.
+pub const BLOCK_AAD_SIZE: usize = 24;
+
+pub fn block_aad( file_id: &[u8;16], blk_nb: u32, nb_keys: u32 ) -> [u8;BLOCK_AAD_SIZE] {
+    let mut aad = [0u8;BLOCK_AAD_SIZE];
+    aad[..16].copy_from_slice( file_id );
+    for i in 0..4 {
+        aad[16+i] = (blk_nb >> (8*i)) as u8;
+        aad[20+i] = (nb_keys >> (8*i)) as u8;
+    }
+    aad
+}
+
+// Footer of a file: its total number of keys and of blocks before the footer.
+pub const FOOTER_SIZE: usize = 12;
+
+pub fn footer( nb_keys: u64, nb_blocks: u32 ) -> [u8;FOOTER_SIZE] {
+    let mut buf = [0u8;FOOTER_SIZE];
+    for (i, b) in buf[..8].iter_mut().enumerate() {
+        *b = (nb_keys >> (8*i)) as u8;
+    }
+    for (i, b) in buf[8..].iter_mut().enumerate() {
+        *b = (nb_blocks >> (8*i)) as u8;
+    }
+    buf
+}
+
+pub fn read_footer( buf: &[u8] ) -> Result<(u64,u32), Error> {
+    if buf.len() != FOOTER_SIZE {
+        return Err(Error::InvalidFooter);
+    }
+    let nb_keys = buf[..8].iter().rev().fold( 0u64, |n, &b| n << 8 | b as u64 );
+    let nb_blocks = buf[8..].iter().rev().fold( 0u32, |n, &b| n << 8 | b as u32 );
+    Ok( (nb_keys, nb_blocks) )
+}
+
+
+// .ce file header, followed by the blocks.
+//
+// The header is authenticated by the tag of `cipher` over no data, with all
+// its fields but `mac` as additional data, and the blocks take `file_id` as
+// additional data, so the sample, its phenotype and its blocks cannot be
+// changed or mixed with another file without the key (see `block_aad`). The
+// key is derived from the data key of the dataset, the file ID and the cipher
+// (see `kdf`), the data key is named by `key_id` and wrapped in the header to
+// the enclave (see `provision`). The usage policy of the data owner is
+// authenticated with the rest (see `policy::Usage`).
+pub const MAGIC: [u8;8] = *b"SGNTXCE\0";
+// Version of the file and block layout.
+//   1: keys sorted by position first.
+//   2: keys sorted by contig, then position.
+//   3: authenticated header with the phenotype, blocks bound to the file ID.
+//   4: blocks bound to their number, footer block.
+//   5: data key wrapped to the enclave in the header.
+//   6: key ID in the header, file keys derived from the data key.
+//   7: cipher in the header, 256-bit data keys.
+//   8: padding keys and side table records.
+//   9: usage policy in the header.
+pub const FORMAT_VERSION: u16 = 9;
+// Version of the `Key` layout.
+pub const KEY_LAYOUT_VERSION: u16 = 1;
+
+pub const SAMPLE_SIZE: usize = 64;
+
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct FileHeader {
+    magic:          [u8;8],
+    version:        u16,
+    key_layout:     u16,
+    keys_per_block: u32,
+    build:          u8,
+    phenotype:      u8,
+    // `cipher::Cipher` of the header MAC and the blocks.
+    cipher:         u8,
+    reserved:       u8,
+    // Sample identifier, NUL padded.
+    sample:         [u8;SAMPLE_SIZE],
+    usage:          policy::Usage,
+    // SHA-256 of the source VCF.
+    checksum:       [u8;32],
+    // Random, unique to each file.
+    file_id:        [u8;16],
+    key_id:         [u8;kdf::KEY_ID_SIZE],
+    wrapped_key:    provision::WrappedKey,
+    iv:             [u8;12],
+    mac:            [u8;16],
+}
+
+impl FileHeader {
+    pub fn new( keys_per_block: u32,
+                build:          contig::Build,
+                sample:         &str,
+                phenotype:      sheet::Phenotype,
+                cipher:         cipher::Cipher,
+                usage:          &policy::Usage ) -> Result<FileHeader, Error> {
+        let mut hdr = FileHeader::zeroed();
+        hdr.magic = MAGIC;
+        hdr.version = FORMAT_VERSION;
+        hdr.key_layout = KEY_LAYOUT_VERSION;
+        hdr.keys_per_block = keys_per_block;
+        hdr.build = build as u8;
+        hdr.phenotype = phenotype as u8;
+        hdr.cipher = cipher as u8;
+        hdr.usage = *usage;
+        if sample.len() > SAMPLE_SIZE || sample.contains('\0') {
+            return Err(Error::InvalidSample);
+        }
+        hdr.sample[..sample.len()].copy_from_slice( sample.as_bytes() );
+        Ok(hdr)
+    }
+
+    // Empty header, to be filled from a file.
+    pub fn zeroed() -> FileHeader {
+        FileHeader { magic: [0u8;8], version: 0, key_layout: 0, keys_per_block: 0, build: 0,
+                     phenotype: 0, cipher: 0, reserved: 0, sample: [0u8;SAMPLE_SIZE],
+                     usage: policy::Usage::zeroed(), checksum: [0u8;32],
+                     file_id: [0u8;16], key_id: [0u8;kdf::KEY_ID_SIZE],
+                     wrapped_key: provision::WrappedKey::zeroed(), iv: [0u8;12], mac: [0u8;16] }
+    }
+
+    // Check that the file can be read by this version of the tools, the MAC
+    // is checked by whoever holds the key.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.magic != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        if self.version != FORMAT_VERSION || self.key_layout != KEY_LAYOUT_VERSION {
+            return Err(Error::UnsupportedVersion);
+        }
+        if self.keys_per_block == 0 || self.keys_per_block > u16::MAX as u32 {
+            return Err(Error::InvalidBlockSize);
+        }
+        if str::from_utf8( self.sample_bytes() ).is_err() {
+            return Err(Error::InvalidSample);
+        }
+        if sheet::Phenotype::from_u8( self.phenotype ).is_none() {
+            return Err(Error::InvalidPhenotype);
+        }
+        if cipher::Cipher::from_u8( self.cipher ).is_none() {
+            return Err(Error::UnsupportedCipher);
+        }
+        Ok(())
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        as_u8_slice( self )
+    }
+
+    // The bytes covered by the MAC.
+    pub fn authenticated(&self) -> &[u8] {
+        let len = mem::size_of::<FileHeader>() - self.mac.len();
+        &self.as_slice()[..len]
+    }
+
+    pub fn keys_per_block(&self) -> u32 {
+        self.keys_per_block
+    }
+
+    pub fn build(&self) -> Option<contig::Build> {
+        contig::Build::from_u8( self.build )
+    }
+
+    // This is safe once the header is checked.
+    pub fn phenotype(&self) -> sheet::Phenotype {
+        sheet::Phenotype::from_u8( self.phenotype ).unwrap()
+    }
+
+    pub fn set_phenotype(&mut self, phenotype: sheet::Phenotype) {
+        self.phenotype = phenotype as u8;
+    }
+
+    // This is safe once the header is checked.
+    pub fn cipher(&self) -> cipher::Cipher {
+        cipher::Cipher::from_u8( self.cipher ).unwrap()
+    }
+
+    pub fn set_cipher(&mut self, cipher: cipher::Cipher) {
+        self.cipher = cipher as u8;
+    }
+
+    pub fn usage(&self) -> &policy::Usage {
+        &self.usage
+    }
+
+    fn sample_bytes(&self) -> &[u8] {
+        let len = self.sample.iter().position(|&c| c == 0).unwrap_or( SAMPLE_SIZE );
+        &self.sample[..len]
+    }
+
+    pub fn sample(&self) -> &str {
+        str::from_utf8( self.sample_bytes() ).unwrap_or("?")
+    }
+
+    pub fn checksum(&self) -> &[u8;32] {
+        &self.checksum
+    }
+
+    pub fn set_checksum(&mut self, checksum: [u8;32]) {
+        self.checksum = checksum;
+    }
+
+    pub fn file_id(&self) -> &[u8;16] {
+        &self.file_id
+    }
+
+    pub fn set_file_id(&mut self, file_id: [u8;16]) {
+        self.file_id = file_id;
+    }
+
+    pub fn key_id(&self) -> &[u8;kdf::KEY_ID_SIZE] {
+        &self.key_id
+    }
+
+    pub fn set_key_id(&mut self, key_id: [u8;kdf::KEY_ID_SIZE]) {
+        self.key_id = key_id;
+    }
+
+    pub fn wrapped_key(&self) -> &provision::WrappedKey {
+        &self.wrapped_key
+    }
+
+    pub fn set_wrapped_key(&mut self, wrapped_key: provision::WrappedKey) {
+        self.wrapped_key = wrapped_key;
+    }
+
+    pub fn iv(&self) -> &[u8;12] {
+        &self.iv
+    }
+
+    pub fn iv_mut(&mut self) -> &mut [u8;12] {
+        &mut self.iv
+    }
+
+    pub fn mac(&self) -> &[u8;16] {
+        &self.mac
+    }
+
+    pub fn mac_mut(&mut self) -> &mut [u8;16] {
+        &mut self.mac
+    }
+}
+
+impl fmt::Debug for FileHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FileHeader {{ version: {}, key_layout: {}, keys_per_block: {}, sample: {:?}, phenotype: {} }}",
+               self.version, self.key_layout, self.keys_per_block, self.sample(), self.phenotype )
     }
 }
 
@@ -298,3 +844,61 @@ pub fn as_u8_slice_mut<T:?Sized>(p: &mut T) -> &mut [u8] {
 }
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use core::mem;
+
+    use super::{Base,Error,Header,Key,NAMED_ALLELE,NAMED_ID};
+
+    #[test]
+    fn blk_size() {
+        let size = mem::size_of::<Header>() as u32;
+        assert_eq!( Header::new( 100, 1, [0u32;3] ).blk_size(), Ok(100 + size) );
+        assert_eq!( Header::new( u32::MAX - size, 1, [0u32;3] ).blk_size(), Ok(u32::MAX) );
+        assert_eq!( Header::new( u32::MAX - size + 1, 1, [0u32;3] ).blk_size(), Err(Error::InvalidBlock) );
+    }
+
+    #[test]
+    fn encode_id() {
+        assert_eq!( Key::encode_id( "." ), 0 );
+        assert_eq!( Key::encode_id( "rs1" ), 1 );
+        assert_eq!( Key::encode_id( "rs123456789" ), 123456789 );
+        // Anything else is hashed.
+        for id in ["rs0", "rs", "rs12a", "rs+1", "RS1", "rs1;rs2", "esv3587295", "rs9223372036854775808"].iter() {
+            let h = Key::encode_id( id );
+            assert!( h & NAMED_ID != 0, "{}", id );
+            assert!( Key::new( 0, 0, h, 0, ::Typ::Heterozygous ).is_named_id() );
+        }
+        assert!( Key::encode_id( "rs1;rs2" ) != Key::encode_id( "rs1;rs3" ) );
+    }
+
+    #[test]
+    fn encode_allele() {
+        let bases = [Base::A, Base::C, Base::G, Base::T, Base::N];
+        let mut seen = Vec::new();
+        for &refe in bases.iter() {
+            for &alt in bases.iter().filter(|&&b| b as u8 != refe as u8 ) {
+                let allele = Key::encode_allele( &format!("{}", refe), &format!("{}", alt) ).unwrap();
+                assert!( allele < 32 && !seen.contains( &allele ) );
+                seen.push( allele );
+                let key = Key::new( 0, 0, 0, allele, ::Typ::Heterozygous );
+                let (r, a) = key.refe_alt().unwrap();
+                assert_eq!( (r as u8, a as u8), (refe as u8, alt as u8) );
+            }
+        }
+        for &(refe, alt) in [("AC", "A"), ("A", "AC"), ("a", "c"), ("A", "*"), ("N", "<DEL>"), ("G", "<INS:ME>")].iter() {
+            let allele = Key::encode_allele( refe, alt ).unwrap();
+            assert!( allele & NAMED_ALLELE != 0, "{} {}", refe, alt );
+            assert!( Key::new( 0, 0, 0, allele, ::Typ::Heterozygous ).refe_alt().is_none() );
+        }
+        // The text is hashed with its tab.
+        assert!( Key::encode_allele( "AC", "A" ).unwrap() != Key::encode_allele( "A", "CA" ).unwrap() );
+        for &(refe, alt) in [("A", "X"), ("", "A"), ("A", "C,G"), ("A", "<>"), ("A", "<D<L>"), ("A C", "A")].iter() {
+            assert_eq!( Key::encode_allele( refe, alt ).err(), Some(Error::InvalidBase), "{} {}", refe, alt );
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Policies enforced by the enclave.
+//
+// `Policy` is the disclosure policy of an analysis. The enclave refuses an
+// analysis of fewer cases or controls than the policy requires, or one asking
+// for more than `max_k` top SNPs. A SNP whose minor allele count over both
+// groups is below `min_mac` is suppressed: it is never one of the top SNPs and
+// its allele frequency is not written. The results tell how many SNPs were
+// suppressed, and the policy is part of the statement signed by the enclave
+// (see `signature`).
+//
+// `Usage` is the policy of the data owner, in the header of each .ce file
+// where the MAC under the data key authenticates it (see `FileHeader`). The
+// enclave refuses to register a file whose usage does not allow the
+// `Analysis` asked by the host. The date of the analysis is the one of its
+// audit record (see `audit`), signed with the study in the statement. Its
+// timestamp comes from the host and is only bound to the trusted time from
+// one record to the next with PSE, the expiry is advisory otherwise: on the
+// first record, the first after a time reset, or without PSE the host may
+// antedate an analysis.
+
+use core::fmt;
+use core::str;
+
+use Error;
+
+
+#[repr(C)]
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Policy {
+    pub min_cases:    u32,
+    pub min_controls: u32,
+    // Minor allele count, the alleles of the SNP or the other ones.
+    pub min_mac:      u32,
+    pub max_k:        u32,
+}
+
+impl Policy {
+    pub fn zeroed() -> Policy {
+        Policy { min_cases: 0, min_controls: 0, min_mac: 0, max_k: 0 }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at least {} cases and {} controls, minor allele count at least {}, top {} SNPs at most",
+               self.min_cases, self.min_controls, self.min_mac, self.max_k )
+    }
+}
+
+
+// Study identifiers are NUL padded.
+pub const STUDY_SIZE: usize = 16;
+pub const MAX_STUDIES: usize = 4;
+
+// The allele frequencies may be released.
+pub const USAGE_ALLELE_FREQ: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Usage {
+    // `USAGE_*` bits.
+    flags:   u32,
+    // Top SNPs at most, 0 for any.
+    max_k:   u32,
+    // Last day allowed, in days since 1970-01-01, 0 for none.
+    expiry:  u32,
+    reserved: u32,
+    // Studies allowed, any if all are empty.
+    studies: [[u8;STUDY_SIZE];MAX_STUDIES],
+}
+
+impl Usage {
+    pub fn new( allele_freq: bool, max_k: u32, expiry: Option<u32>, studies: &[&str] ) -> Result<Usage, Error> {
+        let mut usage = Usage::zeroed();
+        if allele_freq {
+            usage.flags |= USAGE_ALLELE_FREQ;
+        }
+        usage.max_k = max_k;
+        usage.expiry = expiry.unwrap_or( 0 );
+        if studies.len() > MAX_STUDIES {
+            return Err(Error::InvalidStudy);
+        }
+        for (id, study) in usage.studies.iter_mut().zip( studies.iter() ) {
+            *id = try!( study_id( study ) );
+        }
+        Ok(usage)
+    }
+
+    pub fn zeroed() -> Usage {
+        Usage { flags: 0, max_k: 0, expiry: 0, reserved: 0, studies: [[0u8;STUDY_SIZE];MAX_STUDIES] }
+    }
+
+    pub fn allele_freq(&self) -> bool {
+        self.flags & USAGE_ALLELE_FREQ != 0
+    }
+
+    pub fn max_k(&self) -> Option<u32> {
+        if self.max_k != 0 { Some(self.max_k) } else { None }
+    }
+
+    pub fn expiry(&self) -> Option<u32> {
+        if self.expiry != 0 { Some(self.expiry) } else { None }
+    }
+
+    // Whether the data owner allows `analysis` on the file.
+    pub fn allows(&self, analysis: &Analysis) -> Result<(), Error> {
+        if analysis.output_allele_freq && !self.allele_freq() {
+            return Err(Error::AlleleFreqNotAllowed);
+        }
+        if self.max_k != 0 && analysis.snp_cnt > self.max_k as u64 {
+            return Err(Error::SnpCountNotAllowed);
+        }
+        if self.expiry != 0 && analysis.date > self.expiry {
+            return Err(Error::UsageExpired);
+        }
+        let mut studies = self.studies.iter().filter(|id| id[0] != 0 ).peekable();
+        if studies.peek().is_some() && !studies.any(|id| *id == analysis.study ) {
+            return Err(Error::StudyNotAllowed);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!( write!(f, "allele frequencies {}", if self.allele_freq() { "allowed" } else { "not allowed" }) );
+        if let Some(max_k) = self.max_k() {
+            try!( write!(f, ", top {} SNPs at most", max_k) );
+        }
+        if let Some(expiry) = self.expiry() {
+            try!( write!(f, ", until {}", Date(expiry)) );
+        }
+        for (i, id) in self.studies.iter().filter(|id| id[0] != 0 ).enumerate() {
+            try!( write!(f, "{}{}", if i == 0 { ", studies " } else { " " }, Study(id)) );
+        }
+        Ok(())
+    }
+}
+
+
+// What the host asks of the files, checked against their `Usage`.
+pub struct Analysis {
+    pub output_allele_freq: bool,
+    pub snp_cnt:            u64,
+    pub study:              [u8;STUDY_SIZE],
+    // Days since 1970-01-01.
+    pub date:               u32,
+}
+
+
+pub fn study_id( study: &str ) -> Result<[u8;STUDY_SIZE], Error> {
+    if study.is_empty() || study.len() > STUDY_SIZE || study.contains('\0') {
+        return Err(Error::InvalidStudy);
+    }
+    let mut id = [0u8;STUDY_SIZE];
+    id[..study.len()].copy_from_slice( study.as_bytes() );
+    Ok(id)
+}
+
+// Prints a study identifier, `-` for none.
+pub struct Study<'a>(pub &'a [u8;STUDY_SIZE]);
+
+impl<'a> fmt::Display for Study<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.0.iter().position(|&c| c == 0).unwrap_or( STUDY_SIZE );
+        match str::from_utf8( &self.0[..len] ) {
+            Ok("") => write!(f, "-"),
+            Ok(s)  => write!(f, "{}", s),
+            Err(_) => write!(f, "?"),
+        }
+    }
+}
+
+
+// Days since 1970-01-01 of a YYYY-MM-DD date, in the proleptic Gregorian
+// calendar.
+pub fn parse_date( s: &str ) -> Result<u32, Error> {
+    let mut it = s.split('-').map(|f| f.parse::<u32>() );
+    let (y, m, d) = match (it.next(), it.next(), it.next(), it.next()) {
+        (Some(Ok(y @ 1970..=9999)), Some(Ok(m @ 1..=12)), Some(Ok(d @ 1..=31)), None) => (y, m, d),
+        _ => return Err(Error::InvalidDate),
+    };
+    let days = days_from_civil( y, m, d );
+    // Days past the end of the month.
+    if civil_from_days( days ) != (y, m, d) {
+        return Err(Error::InvalidDate);
+    }
+    Ok(days)
+}
+
+// Prints days since 1970-01-01 as YYYY-MM-DD.
+pub struct Date(pub u32);
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (y, m, d) = civil_from_days( self.0 );
+        write!(f, "{:04}-{:02}-{:02}", y, m, d)
+    }
+}
+
+// Years start in March so that February comes last, eras are 400 years.
+fn days_from_civil( y: u32, m: u32, d: u32 ) -> u32 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days( days: u32 ) -> (u32, u32, u32) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use Error;
+    use super::{parse_date,Date};
+
+    #[test]
+    fn date() {
+        for &(s, days) in [("1970-01-01", 0), ("1970-03-01", 59), ("2000-02-29", 11016), ("2017-12-31", 17531),
+                           ("2100-03-01", 47541), ("9999-12-31", 2932896)].iter() {
+            assert_eq!( parse_date( s ), Ok(days), "{}", s );
+            assert_eq!( format!("{}", Date( days )), s );
+        }
+        // Single digits are read too.
+        assert_eq!( parse_date( "2017-1-2" ), Ok(17168) );
+        for s in ["1969-12-31", "2017-00-10", "2017-13-01", "2017-01-00", "2017-02-29", "2100-02-29", "2017-04-31",
+                  "10000-01-01", "2017-01", "2017-01-01-01", "2017/01/01", "", "2017-01-x", "-2017-01-01"].iter() {
+            assert_eq!( parse_date( s ), Err(Error::InvalidDate), "{}", s );
+        }
+    }
+}
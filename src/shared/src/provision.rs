@@ -0,0 +1,203 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Provisioning of the data keys to the enclave.
+//
+// The enclave generates a P-256 keypair, seals it to its MRENCLAVE and
+// publishes the public key in an `EnclaveKey`: the key and a quote of the
+// enclave whose report data starts with the SHA-256 of the key. The quote is
+// verified by the attestation service (see `attestation`), `ce` checks the
+// signed verification report and the MRENCLAVE it gives against the one
+// expected by the data owner, then wraps the data key of the dataset to the
+// enclave: an ephemeral ECDH on P-256 gives a shared secret, the key
+// encryption key is `SHA-256( KEK_LABEL || secret || ephemeral || enclave )`
+// and the data key is encrypted with it in AES-256-GCM. The `WrappedKey` is
+// stored in the header of each .ce file, only the enclave can unwrap it.
+//
+// A second keypair, also sealed, signs the results (see `signature`). Its
+// public key is in the `EnclaveKey` too, the report data ends with its
+// SHA-256.
+//
+// Public keys are the x and y coordinates in big endian, as OpenSSL writes
+// them, the SGX SDK takes them in little endian.
+
+use Error;
+use as_u8_slice;
+use kdf;
+use attestation::{self,QuoteBody,QUOTE_BODY_SIZE};
+
+
+pub const PUBLIC_KEY_SIZE: usize = 64;
+pub const SECRET_SIZE: usize = 32;
+
+// An EPID quote without signature revocation list is about 1.1 kB.
+pub const QUOTE_SIZE_MAX: usize = 2048;
+
+// "SGNTXPB2" had a report in place of the quote.
+pub const ENCLAVE_KEY_MAGIC: [u8;8] = *b"SGNTXPB3";
+
+// Public key of an enclave, written by `app --keygen`.
+#[repr(C)]
+pub struct EnclaveKey {
+    magic:     [u8;8],
+    public:    [u8;PUBLIC_KEY_SIZE],
+    signing:   [u8;PUBLIC_KEY_SIZE],
+    quote_len: u32,
+    quote:     [u8;QUOTE_SIZE_MAX],
+}
+
+impl EnclaveKey {
+    // The quote is added by the host, see `set_quote`.
+    pub fn new( public: &[u8;PUBLIC_KEY_SIZE], signing: &[u8;PUBLIC_KEY_SIZE] ) -> EnclaveKey {
+        let mut key = EnclaveKey::zeroed();
+        key.magic = ENCLAVE_KEY_MAGIC;
+        key.public = *public;
+        key.signing = *signing;
+        key
+    }
+
+    pub fn zeroed() -> EnclaveKey {
+        EnclaveKey { magic: [0u8;8], public: [0u8;PUBLIC_KEY_SIZE], signing: [0u8;PUBLIC_KEY_SIZE],
+                     quote_len: 0, quote: [0u8;QUOTE_SIZE_MAX] }
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        if self.magic != ENCLAVE_KEY_MAGIC {
+            return Err(Error::InvalidEnclaveKey);
+        }
+        if (self.quote_len as usize) < QUOTE_BODY_SIZE || self.quote_len as usize > QUOTE_SIZE_MAX {
+            return Err(Error::InvalidEnclaveKey);
+        }
+        Ok(())
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        as_u8_slice( self )
+    }
+
+    pub fn public(&self) -> &[u8;PUBLIC_KEY_SIZE] {
+        &self.public
+    }
+
+    pub fn signing(&self) -> &[u8;PUBLIC_KEY_SIZE] {
+        &self.signing
+    }
+
+    pub fn set_quote(&mut self, quote: &[u8]) -> Result<(), Error> {
+        if quote.len() < QUOTE_BODY_SIZE || quote.len() > QUOTE_SIZE_MAX {
+            return Err(Error::InvalidEnclaveKey);
+        }
+        self.quote[..quote.len()].copy_from_slice( quote );
+        self.quote_len = quote.len() as u32;
+        Ok(())
+    }
+
+    // The quote for the attestation service, valid once checked.
+    pub fn quote(&self) -> &[u8] {
+        &self.quote[..self.quote_len as usize]
+    }
+
+    // The body of the quote, as the host wrote it. Only the body of a verified
+    // attestation report may be trusted, see `verify`.
+    pub fn quote_body<'a>(&'a self) -> QuoteBody<'a> {
+        QuoteBody( &self.quote[..QUOTE_BODY_SIZE] )
+    }
+
+    // Check the attestation report of the quote, once its signature by the
+    // attestation service is checked: it must be trusted, the quote of this
+    // key, of the expected enclave and bind both public keys. `hashes` are
+    // the SHA-256 of the public key and of the signing key. A test report, see
+    // `attestation`, is only trusted if `test`, its enclave may be a debug one.
+    pub fn verify( &self,
+                   report:     &attestation::Report,
+                   mr_enclave: &[u8;32],
+                   hashes:     &[u8;64],
+                   debug:      bool,
+                   test:       bool ) -> Result<(), Error> {
+        let body = try!( report.trusted_body( test ) );
+        if body.0 != &self.quote[..QUOTE_BODY_SIZE] || body.mr_enclave() != &mr_enclave[..]
+            || body.report_data() != &hashes[..] {
+            return Err(Error::UntrustedEnclave);
+        }
+        if body.is_debug() && !debug && !test {
+            return Err(Error::DebugEnclave);
+        }
+        Ok(())
+    }
+}
+
+
+// Data key wrapped to an enclave.
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub struct WrappedKey {
+    // Ephemeral public key of `ce`.
+    public: [u8;PUBLIC_KEY_SIZE],
+    iv:     [u8;12],
+    key:    [u8;kdf::DATA_KEY_SIZE],
+    mac:    [u8;16],
+}
+
+impl WrappedKey {
+    pub fn new( public: &[u8;PUBLIC_KEY_SIZE], iv: [u8;12], key: [u8;kdf::DATA_KEY_SIZE], mac: [u8;16] ) -> WrappedKey {
+        WrappedKey { public: *public, iv, key, mac }
+    }
+
+    pub fn zeroed() -> WrappedKey {
+        WrappedKey { public: [0u8;PUBLIC_KEY_SIZE], iv: [0u8;12], key: [0u8;kdf::DATA_KEY_SIZE], mac: [0u8;16] }
+    }
+
+    pub fn public(&self) -> &[u8;PUBLIC_KEY_SIZE] {
+        &self.public
+    }
+
+    pub fn iv(&self) -> &[u8;12] {
+        &self.iv
+    }
+
+    // The encrypted data key.
+    pub fn key(&self) -> &[u8;kdf::DATA_KEY_SIZE] {
+        &self.key
+    }
+
+    pub fn mac(&self) -> &[u8;16] {
+        &self.mac
+    }
+}
+
+
+// "v1" took the first 16 bytes of the hash for AES-128-GCM.
+pub const KEK_LABEL: [u8;22] = *b"SGNTX data key wrap v2";
+pub const KEK_INPUT_SIZE: usize = 22 + SECRET_SIZE + 2*PUBLIC_KEY_SIZE;
+
+// Hashed into the key encryption key, see above.
+pub fn kek_input( secret:    &[u8;SECRET_SIZE],
+                  ephemeral: &[u8;PUBLIC_KEY_SIZE],
+                  enclave:   &[u8;PUBLIC_KEY_SIZE] ) -> [u8;KEK_INPUT_SIZE] {
+    let mut buf = [0u8;KEK_INPUT_SIZE];
+    let mut pos = 0;
+    for part in [&KEK_LABEL[..], &secret[..], &ephemeral[..], &enclave[..]].iter() {
+        buf[pos..pos+part.len()].copy_from_slice( part );
+        pos += part.len();
+    }
+    buf
+}
@@ -0,0 +1,225 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Results of the analysis, encrypted by the enclave for the researcher.
+//
+// The researcher gives the public key of a P-256 keypair (see `ce
+// --result_keygen`) to `app`, which passes it to the enclave. For each results
+// file the enclave makes an ephemeral ECDH keypair, the key of the file is the
+// first 16 bytes of `SHA-256( RESULT_LABEL || secret || ephemeral || recipient )`
+// and the file is a `ResultHeader` with the ephemeral public key, then records
+// encrypted in AES-128-GCM. Public keys are as in `provision`.
+//
+// Records all hold `RECORD_SIZE` bytes: the length of their data on 4 bytes,
+// the data and zeros. The host only learns the number of records, that is
+// the size of the results rounded up to `RECORD_DATA_SIZE`. A record is
+// authenticated with the header, its number (from 0) and whether it is the
+// last one, so records cannot be moved or dropped.
+
+use core::mem;
+
+use Error;
+use provision::{PUBLIC_KEY_SIZE,SECRET_SIZE};
+
+
+pub const RESULT_MAGIC: [u8;8] = *b"SGNTXRES";
+pub const RESULT_VERSION: u16 = 1;
+
+pub const RECORD_SIZE: usize = 4096;
+pub const RECORD_DATA_SIZE: usize = RECORD_SIZE - 4;
+// The tag then the encrypted record.
+pub const RECORD_DISK_SIZE: usize = 16 + RECORD_SIZE;
+
+
+// Results files of `app`.
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[repr(u8)]
+pub enum Output {
+    Chisq      = 0,
+    AlleleFreq = 1,
+}
+
+impl Output {
+    pub fn from_u8( v: u8 ) -> Option<Output> {
+        match v {
+            0 => Some(Output::Chisq),
+            1 => Some(Output::AlleleFreq),
+            _ => None,
+        }
+    }
+}
+
+
+#[repr(C)]
+pub struct ResultHeader {
+    magic:       [u8;8],
+    version:     u16,
+    output:      u8,
+    reserved:    u8,
+    record_size: u32,
+    // Ephemeral public key of the enclave.
+    ephemeral:   [u8;PUBLIC_KEY_SIZE],
+    // Public key of the researcher.
+    recipient:   [u8;PUBLIC_KEY_SIZE],
+}
+
+pub const RESULT_HEADER_SIZE: usize = mem::size_of::<ResultHeader>();
+
+impl ResultHeader {
+    pub fn new( output: Output, ephemeral: &[u8;PUBLIC_KEY_SIZE], recipient: &[u8;PUBLIC_KEY_SIZE] ) -> ResultHeader {
+        ResultHeader { magic: RESULT_MAGIC, version: RESULT_VERSION, output: output as u8, reserved: 0,
+                       record_size: RECORD_SIZE as u32, ephemeral: *ephemeral, recipient: *recipient }
+    }
+
+    pub fn zeroed() -> ResultHeader {
+        ResultHeader { magic: [0u8;8], version: 0, output: 0, reserved: 0, record_size: 0,
+                       ephemeral: [0u8;PUBLIC_KEY_SIZE], recipient: [0u8;PUBLIC_KEY_SIZE] }
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        if self.magic != RESULT_MAGIC || self.version != RESULT_VERSION
+            || self.record_size as usize != RECORD_SIZE || Output::from_u8( self.output ).is_none() {
+            return Err(Error::InvalidResults);
+        }
+        Ok(())
+    }
+
+    // This is safe once checked.
+    pub fn output(&self) -> Output {
+        Output::from_u8( self.output ).unwrap()
+    }
+
+    pub fn ephemeral(&self) -> &[u8;PUBLIC_KEY_SIZE] {
+        &self.ephemeral
+    }
+
+    pub fn recipient(&self) -> &[u8;PUBLIC_KEY_SIZE] {
+        &self.recipient
+    }
+}
+
+
+pub const RESULT_LABEL: [u8;16] = *b"SGNTX results v1";
+pub const KEY_INPUT_SIZE: usize = 16 + SECRET_SIZE + 2*PUBLIC_KEY_SIZE;
+
+// Hashed into the key of a results file, see above.
+pub fn key_input( secret:    &[u8;SECRET_SIZE],
+                  ephemeral: &[u8;PUBLIC_KEY_SIZE],
+                  recipient: &[u8;PUBLIC_KEY_SIZE] ) -> [u8;KEY_INPUT_SIZE] {
+    let mut buf = [0u8;KEY_INPUT_SIZE];
+    let mut pos = 0;
+    for part in [&RESULT_LABEL[..], &secret[..], &ephemeral[..], &recipient[..]].iter() {
+        buf[pos..pos+part.len()].copy_from_slice( part );
+        pos += part.len();
+    }
+    buf
+}
+
+
+// Each file has its own key, the record number is enough for the IV.
+pub fn record_iv( nb: u64 ) -> [u8;12] {
+    let mut iv = [0u8;12];
+    for (i, b) in iv[..8].iter_mut().enumerate() {
+        *b = (nb >> (8*i)) as u8;
+    }
+    iv
+}
+
+pub const RECORD_AAD_SIZE: usize = RESULT_HEADER_SIZE + 9;
+
+pub fn record_aad( header: &ResultHeader, nb: u64, last: bool ) -> [u8;RECORD_AAD_SIZE] {
+    let mut aad = [0u8;RECORD_AAD_SIZE];
+    aad[..RESULT_HEADER_SIZE].copy_from_slice( ::as_u8_slice( header ) );
+    for i in 0..8 {
+        aad[RESULT_HEADER_SIZE+i] = (nb >> (8*i)) as u8;
+    }
+    aad[RESULT_HEADER_SIZE+8] = last as u8;
+    aad
+}
+
+
+// Plaintext of a record holding `data`.
+pub fn record( data: &[u8] ) -> [u8;RECORD_SIZE] {
+    assert!( data.len() <= RECORD_DATA_SIZE );
+    let mut rec = [0u8;RECORD_SIZE];
+    for (i, b) in rec[..4].iter_mut().enumerate() {
+        *b = (data.len() >> (8*i)) as u8;
+    }
+    rec[4..4+data.len()].copy_from_slice( data );
+    rec
+}
+
+// Data of a decrypted record.
+pub fn record_data( rec: &[u8;RECORD_SIZE] ) -> Result<&[u8], Error> {
+    let len = rec[..4].iter().rev().fold( 0usize, |n, &b| n << 8 | b as usize );
+    if len > RECORD_DATA_SIZE || rec[4+len..].iter().any(|&b| b != 0) {
+        return Err(Error::InvalidResults);
+    }
+    Ok( &rec[4..4+len] )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use Error;
+    use as_u8_slice;
+    use as_u8_slice_mut;
+    use provision::PUBLIC_KEY_SIZE;
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = ResultHeader::new( Output::AlleleFreq, &[1u8;PUBLIC_KEY_SIZE], &[2u8;PUBLIC_KEY_SIZE] );
+        assert_eq!( as_u8_slice( &header ).len(), RESULT_HEADER_SIZE );
+        let mut read = ResultHeader::zeroed();
+        assert_eq!( read.check().err(), Some(Error::InvalidResults) );
+        as_u8_slice_mut( &mut read ).copy_from_slice( as_u8_slice( &header ) );
+        assert!( read.check().is_ok() );
+        assert_eq!( read.output(), Output::AlleleFreq );
+        assert_eq!( &read.ephemeral()[..], &[1u8;PUBLIC_KEY_SIZE][..] );
+        assert_eq!( &read.recipient()[..], &[2u8;PUBLIC_KEY_SIZE][..] );
+
+        let aad = record_aad( &header, 0x0102, true );
+        assert_eq!( &aad[..RESULT_HEADER_SIZE], as_u8_slice( &header ) );
+        assert_eq!( &aad[RESULT_HEADER_SIZE..], &[2, 1, 0, 0, 0, 0, 0, 0, 1] );
+        assert_eq!( record_iv( 0x0102 ), [2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] );
+    }
+
+    #[test]
+    fn record_round_trip() {
+        for &len in [0, 1, 100, RECORD_DATA_SIZE].iter() {
+            let data: std::vec::Vec<u8> = (0..len).map(|i| (i % 251 + 1) as u8 ).collect();
+            let rec = record( &data );
+            assert_eq!( record_data( &rec ).unwrap(), &data[..] );
+        }
+        // Bytes after the data, or a length past the record.
+        let mut rec = record( b"abc" );
+        rec[0] = 2;
+        assert_eq!( record_data( &rec ).err(), Some(Error::InvalidResults) );
+        let mut rec = record( b"abc" );
+        rec[RECORD_SIZE-1] = 1;
+        assert_eq!( record_data( &rec ).err(), Some(Error::InvalidResults) );
+        let mut rec = record( b"" );
+        rec[..4].copy_from_slice( &[0xff, 0xff, 0, 0] );
+        assert_eq!( record_data( &rec ).err(), Some(Error::InvalidResults) );
+    }
+}
@@ -0,0 +1,170 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Phenotype sample sheet.
+//
+// A TSV or CSV file mapping sample IDs to input files and phenotypes, its
+// first line names the columns:
+//
+//     sample   file            phenotype
+//     NA12878  cohort.vcf.gz   case
+//     NA12891  cohort.vcf.gz   control
+//     NA12892  cohort.vcf.gz   excluded
+//
+// Columns may come in any order and others are ignored, `file` is only read
+// by `ce`. Phenotypes are `case`, `control` or `excluded`, PLINK codes (2, 1,
+// 0 and -9) are accepted as well. Empty lines and lines starting with `#` are
+// skipped. The .ce file of a sample is named after it, sample IDs that are not
+// a plain file name are refused, see `is_file_name`.
+
+use core::str::{self,FromStr};
+
+use Error;
+use Kind;
+
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[repr(u8)]
+pub enum Phenotype {
+    Control  = 0,
+    Case     = 1,
+    // Listed but left out of the analysis.
+    Excluded = 2,
+}
+
+impl Phenotype {
+    pub fn from_u8(phenotype: u8) -> Option<Phenotype> {
+        match phenotype {
+            0 => Some(Phenotype::Control),
+            1 => Some(Phenotype::Case),
+            2 => Some(Phenotype::Excluded),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> Option<Kind> {
+        match *self {
+            Phenotype::Control  => Some(Kind::Control),
+            Phenotype::Case     => Some(Kind::Case),
+            Phenotype::Excluded => None,
+        }
+    }
+}
+
+impl FromStr for Phenotype {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "control" | "1"         => Ok(Phenotype::Control),
+            "case"    | "2"         => Ok(Phenotype::Case),
+            "excluded" | "0" | "-9" => Ok(Phenotype::Excluded),
+            _                       => Err(Error::InvalidPhenotype),
+        }
+    }
+}
+
+
+pub struct Entry<'a> {
+    pub sample:    &'a str,
+    pub file:      Option<&'a str>,
+    pub phenotype: Phenotype,
+}
+
+pub struct Entries<'a> {
+    lines:     str::Lines<'a>,
+    sep:       char,
+    sample:    usize,
+    file:      Option<usize>,
+    phenotype: usize,
+}
+
+fn is_skipped(line: &str) -> bool {
+    line.is_empty() || line.starts_with('#')
+}
+
+// True if `sample.ce` names a file of the output directory: no path separator
+// and no `..`.
+pub fn is_file_name(sample: &str) -> bool {
+    !sample.is_empty() && sample != "." && !sample.contains("..")
+        && !sample.contains(&['/', '\\', '\0'][..])
+}
+
+// Read the column names, tabs are looked for first.
+pub fn entries<'a>(text: &'a str) -> Result<Entries<'a>, Error> {
+    let mut lines = text.lines();
+    let header = match lines.by_ref().map(|l| l.trim() ).find(|l| !is_skipped(l) ) {
+        Some(header) => header,
+        None => return Err(Error::InvalidSheet),
+    };
+    let sep = if header.contains('\t') { '\t' } else { ',' };
+    let column = |name: &str| header.split(sep).position(|c| c.trim() == name );
+
+    match (column("sample"), column("phenotype")) {
+        (Some(sample), Some(phenotype)) =>
+            Ok(Entries { lines, sep, sample, file: column("file"), phenotype }),
+        _ => Err(Error::InvalidSheet),
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<Entry<'a>, Error>> {
+        let line = self.lines.by_ref().map(|l| l.trim() ).find(|l| !is_skipped(l) )?;
+        let field = |i: usize| line.split(self.sep).nth(i).map(|f| f.trim() ).filter(|f| !f.is_empty() );
+
+        let sample = match field(self.sample) {
+            Some(sample) if is_file_name(sample) => sample,
+            Some(_) => return Some(Err(Error::InvalidSample)),
+            None => return Some(Err(Error::InvalidSheet)),
+        };
+        let phenotype = match field(self.phenotype).map(Phenotype::from_str) {
+            Some(Ok(phenotype)) => phenotype,
+            Some(Err(why)) => return Some(Err(why)),
+            None => return Some(Err(Error::InvalidSheet)),
+        };
+        Some(Ok(Entry { sample, file: self.file.and_then(&field), phenotype }))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use Error;
+    use super::{entries,is_file_name,Phenotype};
+
+    #[test]
+    fn sample_file_names() {
+        for s in ["NA12878", "HG00096.1", "a.b"].iter() {
+            assert!( is_file_name( s ), "{}", s );
+        }
+        for s in ["", ".", "..", "../x", "a/b", "a\\b", "a..b", "/etc/passwd"].iter() {
+            assert!( !is_file_name( s ), "{}", s );
+        }
+        let mut it = entries( "sample\tphenotype\nNA12878\tcase\n../x\tcontrol\n" ).ok().unwrap();
+        let first = it.next().unwrap().ok().unwrap();
+        assert_eq!( (first.sample, first.phenotype), ("NA12878", Phenotype::Case) );
+        assert_eq!( it.next().unwrap().err(), Some(Error::InvalidSample) );
+        assert!( it.next().is_none() );
+    }
+}
@@ -0,0 +1,165 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Block side table.
+//
+// Values that do not fit in a `Key` (allele strings, ...) are appended to
+// the keys of a block as a list of records:
+//
+//     tag: u8 | idx: u16 | len: u16 | data: [u8; len]
+//
+// `idx` is the index in the block of the key the record refers to. Integers
+// are little-endian.
+
+use core::str;
+
+use Error;
+
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+#[repr(u8)]
+pub enum Tag {
+    // `REF\tALT` of a key with a named allele.
+    Allele = 1,
+    // Name of the contig of the key, as written in the input.
+    Contig = 2,
+    // ID column of a key with a named ID.
+    Id = 3,
+    // Filler of the side tables of padded files, its data is ignored.
+    Padding = 4,
+}
+
+impl Tag {
+    fn from_u8(tag: u8) -> Result<Tag, Error> {
+        match tag {
+            1 => Ok(Tag::Allele),
+            2 => Ok(Tag::Contig),
+            3 => Ok(Tag::Id),
+            4 => Ok(Tag::Padding),
+            _ => Err(Error::InvalidSide),
+        }
+    }
+}
+
+pub const RECORD_HEADER_SIZE: usize = 5;
+// Longest data of a record, its length is a u16.
+pub const RECORD_DATA_MAX: usize = 0xffff;
+
+
+pub fn record_header(tag: Tag, idx: u16, len: u16) -> [u8; RECORD_HEADER_SIZE] {
+    [tag as u8, idx as u8, (idx >> 8) as u8, len as u8, (len >> 8) as u8]
+}
+
+
+pub struct Record<'a> {
+    pub tag:  Tag,
+    pub idx:  usize,
+    pub data: &'a str,
+}
+
+pub struct Records<'a> {
+    buf: &'a [u8],
+}
+
+pub fn records<'a>(buf: &'a [u8]) -> Records<'a> {
+    Records { buf }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<Record<'a>, Error>> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        if self.buf.len() < RECORD_HEADER_SIZE {
+            self.buf = &[];
+            return Some(Err(Error::InvalidSide));
+        }
+
+        let (hdr, rest) = self.buf.split_at(RECORD_HEADER_SIZE);
+        let idx = hdr[1] as usize | (hdr[2] as usize) << 8;
+        let len = hdr[3] as usize | (hdr[4] as usize) << 8;
+        if rest.len() < len {
+            self.buf = &[];
+            return Some(Err(Error::InvalidSide));
+        }
+        let (data, rest) = rest.split_at(len);
+        self.buf = rest;
+
+        let tag = match Tag::from_u8(hdr[0]) {
+            Ok(tag) => tag,
+            Err(why) => return Some(Err(why)),
+        };
+        match str::from_utf8(data) {
+            Ok(data) => Some(Ok(Record { tag, idx, data })),
+            Err(_)   => Some(Err(Error::InvalidSide)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use Error;
+    use super::{record_header,records,Tag};
+
+    fn push( buf: &mut Vec<u8>, tag: Tag, idx: u16, data: &[u8] ) {
+        buf.extend_from_slice( &record_header( tag, idx, data.len() as u16 ) );
+        buf.extend_from_slice( data );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = Vec::new();
+        push( &mut buf, Tag::Contig, 0, b"chr1" );
+        push( &mut buf, Tag::Allele, 300, b"AC\tA" );
+        push( &mut buf, Tag::Id, 0x1234, b"rs1;esv2" );
+        push( &mut buf, Tag::Padding, 0, b"" );
+        let recs: Vec<_> = records( &buf ).map(|r| r.unwrap() ).collect();
+        assert_eq!( recs.len(), 4 );
+        for (rec, &(tag, idx, data)) in recs.iter().zip( [(Tag::Contig, 0, "chr1"), (Tag::Allele, 300, "AC\tA"),
+                                                          (Tag::Id, 0x1234, "rs1;esv2"), (Tag::Padding, 0, "")].iter() ) {
+            assert_eq!( (rec.tag, rec.idx, rec.data), (tag, idx, data) );
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let mut buf = Vec::new();
+        push( &mut buf, Tag::Allele, 1, b"AC\tA" );
+        // Truncated data, then header.
+        for len in [buf.len() - 1, 3].iter() {
+            let recs: Vec<_> = records( &buf[..*len] ).collect();
+            assert_eq!( recs.len(), 1 );
+            assert_eq!( recs[0].as_ref().err(), Some(&Error::InvalidSide) );
+        }
+        let mut bad = buf.clone();
+        bad[0] = 5;
+        assert_eq!( records( &bad ).next().unwrap().err(), Some(Error::InvalidSide) );
+        let mut bad = buf.clone();
+        bad[super::RECORD_HEADER_SIZE] = 0xff;
+        assert_eq!( records( &bad ).next().unwrap().err(), Some(Error::InvalidSide) );
+    }
+}
@@ -0,0 +1,198 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// Results signed by the enclave.
+//
+// At the end of an analysis the enclave signs a `SignedResults` with its
+// signing key, whose SHA-256 is in the report of its `EnclaveKey` (see
+// `provision`): the parameters of `encl_init` with its `Policy` and the
+// study and date the usage of the files was checked against, a commitment to the .ce files
+// it read and the SHA-256 of the results files as written (see `results`).
+//
+// The enclave hashes each .ce file as it consumes it, the header then each
+// block the first time it comes, up to the footer: that is the SHA-256 of the
+// whole file. The commitment is the SHA-256 of `INPUTS_LABEL` followed by the
+// hashes of the files in increasing order, so an auditor only needs the list
+// of .ce files, in any order.
+
+use core::mem;
+
+use Error;
+use Aggregation;
+use policy::{Policy,Analysis,STUDY_SIZE};
+use as_u8_slice;
+use provision::PUBLIC_KEY_SIZE;
+
+
+pub const SIGNED_MAGIC: [u8;8] = *b"SGNTXSIG";
+// Version 2 added the policy, 3 the study and date.
+pub const SIGNED_VERSION: u16 = 3;
+
+pub const INPUTS_LABEL: [u8;16] = *b"SGNTX inputs v1\0";
+
+// ECDSA on P-256, r then s in big endian.
+pub const SIGNATURE_SIZE: usize = 64;
+
+
+#[repr(C)]
+pub struct SignedResults {
+    magic:              [u8;8],
+    version:            u16,
+    output_allele_freq: u8,
+    // `Aggregation` of the enclave, was zero (the hashmap) before.
+    aggregation:        u8,
+    // Files registered.
+    nb_files:           u32,
+    keys_per_block:     u32,
+    nb_keys:            u32,
+    snp_cnt:            u64,
+    policy:             Policy,
+    // Days since 1970-01-01, of the host.
+    date:               u32,
+    reserved:           u32,
+    study:              [u8;STUDY_SIZE],
+    // Public key of the researcher the results are encrypted to.
+    recipient:          [u8;PUBLIC_KEY_SIZE],
+    inputs:             [u8;32],
+    // Zeros without an allele frequency file.
+    chisq:              [u8;32],
+    allele_freq:        [u8;32],
+    // Of the SHA-256 of all the fields above.
+    signature:          [u8;SIGNATURE_SIZE],
+}
+
+pub const SIGNED_SIZE: usize = mem::size_of::<SignedResults>();
+
+impl SignedResults {
+    pub fn new( keys_per_block: u32, nb_keys: u32, analysis: &Analysis, aggregation: Aggregation,
+                policy: &Policy, recipient: &[u8;PUBLIC_KEY_SIZE] ) -> SignedResults {
+        let mut signed = SignedResults::zeroed();
+        signed.magic = SIGNED_MAGIC;
+        signed.version = SIGNED_VERSION;
+        signed.output_allele_freq = analysis.output_allele_freq as u8;
+        signed.aggregation = aggregation as u8;
+        signed.keys_per_block = keys_per_block;
+        signed.nb_keys = nb_keys;
+        signed.snp_cnt = analysis.snp_cnt;
+        signed.policy = *policy;
+        signed.date = analysis.date;
+        signed.study = analysis.study;
+        signed.recipient = *recipient;
+        signed
+    }
+
+    pub fn zeroed() -> SignedResults {
+        SignedResults { magic: [0u8;8], version: 0, output_allele_freq: 0, aggregation: 0, nb_files: 0,
+                        keys_per_block: 0, nb_keys: 0, snp_cnt: 0, policy: Policy::zeroed(),
+                        date: 0, reserved: 0, study: [0u8;STUDY_SIZE], recipient: [0u8;PUBLIC_KEY_SIZE],
+                        inputs: [0u8;32], chisq: [0u8;32], allele_freq: [0u8;32], signature: [0u8;SIGNATURE_SIZE] }
+    }
+
+    pub fn check(&self) -> Result<(), Error> {
+        if self.magic != SIGNED_MAGIC || self.version != SIGNED_VERSION || self.output_allele_freq > 1
+            || Aggregation::from_u8( self.aggregation ).is_none() {
+            return Err(Error::InvalidSignedResults);
+        }
+        Ok(())
+    }
+
+    pub fn set_inputs(&mut self, nb_files: u32, inputs: &[u8;32]) {
+        self.nb_files = nb_files;
+        self.inputs = *inputs;
+    }
+
+    pub fn set_results(&mut self, chisq: &[u8;32], allele_freq: Option<&[u8;32]>) {
+        self.chisq = *chisq;
+        self.allele_freq = allele_freq.map_or( [0u8;32], |h| *h );
+    }
+
+    pub fn set_signature(&mut self, signature: &[u8;SIGNATURE_SIZE]) {
+        self.signature = *signature;
+    }
+
+    // The bytes signed, all but the signature.
+    pub fn signed(&self) -> &[u8] {
+        &as_u8_slice( self )[..SIGNED_SIZE-SIGNATURE_SIZE]
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        as_u8_slice( self )
+    }
+
+    pub fn output_allele_freq(&self) -> bool {
+        self.output_allele_freq != 0
+    }
+
+    // This is safe once checked.
+    pub fn aggregation(&self) -> Aggregation {
+        Aggregation::from_u8( self.aggregation ).unwrap()
+    }
+
+    pub fn nb_files(&self) -> u32 {
+        self.nb_files
+    }
+
+    pub fn keys_per_block(&self) -> u32 {
+        self.keys_per_block
+    }
+
+    pub fn nb_keys(&self) -> u32 {
+        self.nb_keys
+    }
+
+    pub fn snp_cnt(&self) -> u64 {
+        self.snp_cnt
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    pub fn date(&self) -> u32 {
+        self.date
+    }
+
+    pub fn study(&self) -> &[u8;STUDY_SIZE] {
+        &self.study
+    }
+
+    pub fn recipient(&self) -> &[u8;PUBLIC_KEY_SIZE] {
+        &self.recipient
+    }
+
+    pub fn inputs(&self) -> &[u8;32] {
+        &self.inputs
+    }
+
+    pub fn chisq(&self) -> &[u8;32] {
+        &self.chisq
+    }
+
+    // None without an allele frequency file.
+    pub fn allele_freq(&self) -> Option<&[u8;32]> {
+        if self.output_allele_freq() { Some(&self.allele_freq) } else { None }
+    }
+
+    pub fn signature(&self) -> &[u8;SIGNATURE_SIZE] {
+        &self.signature
+    }
+}
@@ -0,0 +1,107 @@
+//
+//   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+//   Contributor(s): Thibaud Tortech & Sergiu Carpov
+//
+//   This software is governed by the CeCILL-C license under French law and
+//   abiding by the rules of distribution of free software.  You can  use,
+//   modify and/ or redistribute the software under the terms of the CeCILL-C
+//   license as circulated by CEA, CNRS and INRIA at the following URL
+//   "http://www.cecill.info".
+//
+//   As a counterpart to the access to the source code and  rights to copy,
+//   modify and redistribute granted by the license, users are provided only
+//   with a limited warranty  and the software's author,  the holder of the
+//   economic rights,  and the successive licensors  have only  limited
+//   liability.
+//
+//   The fact that you are presently reading this means that you have had
+//   knowledge of the CeCILL-C license and that you accept its terms.
+//
+
+
+
+// SipHash-2-4, the keyed hash of the enclave hash map.
+//
+// With a secret key the index of a key in the table cannot be predicted, so
+// a data contributor cannot craft keys that fill one probe chain. The message
+// is a sequence of 64-bit words, hashed as their little-endian bytes: the
+// result is the SipHash-2-4 of those bytes.
+
+
+pub const SIP_KEY_SIZE: usize = 16;
+
+#[derive(Clone,Copy)]
+pub struct SipKey {
+    k0: u64,
+    k1: u64,
+}
+
+impl SipKey {
+    // The key is the 16 bytes `k`, as in the reference implementation.
+    pub fn new(k: &[u8;SIP_KEY_SIZE]) -> SipKey {
+        SipKey { k0: read_u64(&k[..8]), k1: read_u64(&k[8..]) }
+    }
+
+    pub fn hash(&self, words: &[u64]) -> u64 {
+        let mut v = [ self.k0 ^ 0x736f6d6570736575,
+                      self.k1 ^ 0x646f72616e646f6d,
+                      self.k0 ^ 0x6c7967656e657261,
+                      self.k1 ^ 0x7465646279746573 ];
+
+        for &m in words {
+            v[3] ^= m;
+            round( &mut v );
+            round( &mut v );
+            v[0] ^= m;
+        }
+
+        // Last block: the length in bytes, no tail as words are whole.
+        let b = ((words.len() * 8) as u64) << 56;
+        v[3] ^= b;
+        round( &mut v );
+        round( &mut v );
+        v[0] ^= b;
+
+        v[2] ^= 0xff;
+        for _ in 0..4 {
+            round( &mut v );
+        }
+        v[0] ^ v[1] ^ v[2] ^ v[3]
+    }
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    b.iter().rev().fold(0, |w, &x| w << 8 | x as u64)
+}
+
+fn round(v: &mut [u64;4]) {
+    v[0] = v[0].wrapping_add(v[1]); v[1] = v[1].rotate_left(13); v[1] ^= v[0]; v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]); v[3] = v[3].rotate_left(16); v[3] ^= v[2];
+    v[0] = v[0].wrapping_add(v[3]); v[3] = v[3].rotate_left(21); v[3] ^= v[0];
+    v[2] = v[2].wrapping_add(v[1]); v[1] = v[1].rotate_left(17); v[1] ^= v[2]; v[2] = v[2].rotate_left(32);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{SipKey,SIP_KEY_SIZE};
+
+    // The key and messages of the paper, 00 01 02 ..., from the vectors of
+    // the reference implementation: messages of whole words only.
+    #[test]
+    fn vectors() {
+        let mut k = [0u8;SIP_KEY_SIZE];
+        for (i, b) in k.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let key = SipKey::new( &k );
+        let mut words = [0u64;7];
+        for (i, w) in words.iter_mut().enumerate() {
+            *w = (0..8).fold( 0, |w, j| w | ((8*i + j) as u64) << (8*j) );
+        }
+        for &(len, hash) in [(0, 0x726fdb47dd0e0e31), (1, 0x93f5f5799a932462), (2, 0x3f2acc7f57c29bdb),
+                             (3, 0xb8ad50c6f649af94), (7, 0xb78dbfaf3a8d83bd)].iter() {
+            assert_eq!( key.hash( &words[..len] ), hash, "{} bytes", 8*len );
+        }
+    }
+}
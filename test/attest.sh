@@ -0,0 +1,60 @@
+#!/bin/sh
+#
+#   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+#   Contributor(s): Thibaud Tortech & Sergiu Carpov
+#
+#   This software is governed by the CeCILL-C license under French law and
+#   abiding by the rules of distribution of free software.  You can  use,
+#   modify and/ or redistribute the software under the terms of the CeCILL-C
+#   license as circulated by CEA, CNRS and INRIA at the following URL
+#   "http://www.cecill.info".
+#
+#   As a counterpart to the access to the source code and  rights to copy,
+#   modify and redistribute granted by the license, users are provided only
+#   with a limited warranty  and the software's author,  the holder of the
+#   economic rights,  and the successive licensors  have only  limited
+#   liability.
+#
+#   The fact that you are presently reading this means that you have had
+#   knowledge of the CeCILL-C license and that you accept its terms.
+#
+
+# Sign the test attestation report of `app --keygen --test_attestation` as the
+# attestation service would, into a response as `curl -i` saves it, for
+# `ce --test_attestation --attestation RESPONSE --ias_root ROOT.pem`. The
+# self-signed test root ROOT.key/ROOT.pem is created if missing. It only
+# stands for whoever holds ROOT.key, never for the platform.
+#
+#   test/attest.sh enclave.pub.report.json attestation.txt [ROOT]
+
+set -e
+
+if [ $# -lt 2 ]; then
+    echo "usage: $0 REPORT RESPONSE [ROOT]" >&2
+    exit 1
+fi
+report=$1
+response=$2
+root=${3:-test-root}
+
+if [ ! -f "$root.key" ]; then
+    openssl req -x509 -newkey rsa:2048 -sha256 -nodes -days 365 \
+            -subj "/CN=SGNTX test attestation root" \
+            -keyout "$root.key" -out "$root.pem" 2> /dev/null
+fi
+
+signature=$(openssl dgst -sha256 -sign "$root.key" "$report" | base64 | tr -d '\n')
+# The signing certificate is the root itself, URL encoded on one line.
+certificate=$(sed -e 's/%/%25/g' -e 's/ /%20/g' -e 's/+/%2B/g' -e 's/\//%2F/g' -e 's/=/%3D/g' "$root.pem" \
+              | awk '{ printf "%s%%0A", $0 }')
+
+{
+    printf 'HTTP/1.1 200 OK\r\n'
+    printf 'Content-Type: application/json\r\n'
+    printf 'X-IASReport-Signature: %s\r\n' "$signature"
+    printf 'X-IASReport-Signing-Certificate: %s\r\n' "$certificate"
+    printf '\r\n'
+    cat "$report"
+} > "$response"
+
+echo "Test attestation response written to $response, test root $root.pem"
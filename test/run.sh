@@ -0,0 +1,69 @@
+#!/bin/sh
+#
+#   (C) Copyright 2017 CEA LIST. All Rights Reserved.
+#   Contributor(s): Thibaud Tortech & Sergiu Carpov
+#
+#   This software is governed by the CeCILL-C license under French law and
+#   abiding by the rules of distribution of free software.  You can  use,
+#   modify and/ or redistribute the software under the terms of the CeCILL-C
+#   license as circulated by CEA, CNRS and INRIA at the following URL
+#   "http://www.cecill.info".
+#
+#   As a counterpart to the access to the source code and  rights to copy,
+#   modify and redistribute granted by the license, users are provided only
+#   with a limited warranty  and the software's author,  the holder of the
+#   economic rights,  and the successive licensors  have only  limited
+#   liability.
+#
+#   The fact that you are presently reading this means that you have had
+#   knowledge of the CeCILL-C license and that you accept its terms.
+#
+
+# End to end run of the binaries of bin/, built with `make SGX_MODE=SIM` (or on
+# SGX hardware): test attestation of the enclave key, encryption of a sample
+# sheet whose VCF has a named contig, an analysis with allele frequencies
+# (`-a`, which resolves the names of the side tables), decryption of the
+# results and check of the audit log. `make run_test` runs it.
+
+set -e
+
+top=$(cd "$(dirname "$0")/.." && pwd)
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+cp "$top/bin/app" "$top/bin/ce" "$top/bin/enclave.signed.so" "$work"
+cd "$work"
+
+printf '##fileformat=VCFv4.2\n' > cohort.vcf
+printf '##contig=<ID=1,length=249250621>\n' >> cohort.vcf
+printf '##contig=<ID=GL000192.1,length=547496>\n' >> cohort.vcf
+printf '#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tS1\tS2\tS3\tS4\n' >> cohort.vcf
+printf '1\t1000\trs1\tA\tG\t.\tPASS\t.\tGT\t0/1\t1/1\t0/0\t0/1\n' >> cohort.vcf
+printf '1\t2000\t.\tC\tT\t.\tPASS\t.\tGT\t0/0\t0/1\t0/1\t1/1\n' >> cohort.vcf
+printf 'GL000192.1\t500\t.\tG\tGTTA\t.\tPASS\t.\tGT\t1/1\t0/1\t0/0\t0/0\n' >> cohort.vcf
+
+printf 'sample\tfile\tphenotype\n' > sheet.tsv
+for s in S1:case S2:case S3:control S4:control; do
+    printf '%s\tcohort.vcf\t%s\n' "${s%:*}" "${s#*:}" >> sheet.tsv
+done
+
+mrenclave=$(./app --keygen --test_attestation | sed -n 's/^MRENCLAVE: //p')
+"$top/test/attest.sh" enclave.pub.report.json enclave.avr > /dev/null
+trust="-e enclave.pub -m $mrenclave -q enclave.avr --ias_root test-root.pem --test_attestation"
+
+./ce -G researcher.key > /dev/null
+./ce -s sheet.tsv -o out $trust > /dev/null
+if ! timeout 300 ./app -s sheet.tsv -d out -r researcher.key.pub -a -k 3 -f run- \
+     --min_cases 1 --min_controls 1 --min_mac 1 > app.log; then
+    cat app.log
+    echo "FAIL: app did not complete" >&2
+    exit 1
+fi
+./ce -D run-Chisq.vcf.enc run-AF.vcf.enc -K researcher.key > /dev/null
+
+if ! grep -q '^GL000192.1' run-AF.vcf; then
+    cat run-AF.vcf
+    echo "FAIL: no allele frequency on the named contig" >&2
+    exit 1
+fi
+./ce -a enclave.audit $trust | tail -1
+echo "OK"